@@ -0,0 +1,241 @@
+use crate::crypto::CryptoError;
+use crate::identity::IdentityKeyPair;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Longest username the server accepts
+const MAX_USERNAME_LEN: usize = 32;
+
+/// Length of a login challenge
+pub const CHALLENGE_LEN: usize = 32;
+
+/// A username bound to the identity key that created it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub username: String,
+    pub identity_key: Vec<u8>,
+}
+
+/// Reasons a login can be refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginError {
+    /// Usernames are 1-32 letters, digits, '-' or '_'
+    InvalidUsername,
+    /// The username belongs to a different identity key
+    UsernameTaken,
+    /// The challenge response didn't verify
+    InvalidSignature,
+    /// The user is already logged in on another connection
+    AlreadyConnected,
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            LoginError::InvalidUsername => "invalid username",
+            LoginError::UsernameTaken => "username is registered to another identity key",
+            LoginError::InvalidSignature => "invalid challenge signature",
+            LoginError::AlreadyConnected => "user is already connected",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+/// Registry of accounts on the relay, keyed by username
+///
+/// The username doubles as the user's stable ID for message routing.
+#[derive(Default, Serialize, Deserialize)]
+pub struct UserRegistry {
+    accounts: HashMap<String, Account>,
+}
+
+impl UserRegistry {
+    /// Load a registry previously written with `save`
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Load the registry stored at `path`, starting empty if the file is missing
+    pub fn load_or_default(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Write the registry to disk
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let contents = serde_json::to_vec(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+
+    /// Look up an account by username
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts.get(username)
+    }
+
+    /// Check a signed login challenge, creating the account on first login
+    ///
+    /// Returns `true` if a new account was created and the registry should be saved.
+    pub fn login(
+        &mut self,
+        username: &str,
+        identity_key: &[u8],
+        challenge: &[u8],
+        response: &[u8],
+    ) -> Result<bool, LoginError> {
+        if !is_valid_username(username) {
+            return Err(LoginError::InvalidUsername);
+        }
+        if let Some(account) = self.accounts.get(username) {
+            if account.identity_key != identity_key {
+                return Err(LoginError::UsernameTaken);
+            }
+        }
+        if !IdentityKeyPair::verify(identity_key, &challenge_message(username, challenge), response) {
+            return Err(LoginError::InvalidSignature);
+        }
+
+        if self.accounts.contains_key(username) {
+            return Ok(false);
+        }
+        self.accounts.insert(
+            username.to_string(),
+            Account { username: username.to_string(), identity_key: identity_key.to_vec() },
+        );
+        Ok(true)
+    }
+}
+
+/// Check that a username is 1-32 letters, digits, '-' or '_'
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LEN
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Generate a fresh random login challenge
+pub fn new_challenge() -> Result<[u8; CHALLENGE_LEN], CryptoError> {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    SystemRandom::new().fill(&mut challenge).map_err(|_| CryptoError::RandomFailed)?;
+    Ok(challenge)
+}
+
+/// The login challenge sent on a connection, which can be answered only once
+#[derive(Default)]
+pub struct LoginChallenge {
+    pending: Option<(String, [u8; CHALLENGE_LEN])>,
+}
+
+impl LoginChallenge {
+    /// Make a fresh challenge for `username`, replacing any unanswered one
+    pub fn issue(&mut self, username: &str) -> Result<[u8; CHALLENGE_LEN], CryptoError> {
+        let challenge = new_challenge()?;
+        self.pending = Some((username.to_string(), challenge));
+        Ok(challenge)
+    }
+
+    /// Take the challenge issued for `username`
+    ///
+    /// Any attempt uses the challenge up, so a response can't be tried twice.
+    pub fn take(&mut self, username: &str) -> Option<[u8; CHALLENGE_LEN]> {
+        self.pending.take().filter(|(pending, _)| pending == username).map(|(_, challenge)| challenge)
+    }
+}
+
+/// Build the message a client signs to answer a login challenge
+///
+/// Binding the username stops a response from being replayed for another account.
+pub fn challenge_message(username: &str, challenge: &[u8]) -> Vec<u8> {
+    [b"p2p_sparse_messaging login".as_slice(), username.as_bytes(), challenge].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(identity: &IdentityKeyPair, username: &str, challenge: &[u8]) -> Vec<u8> {
+        identity.sign(&challenge_message(username, challenge))
+    }
+
+    #[test]
+    fn first_login_binds_the_username() {
+        let mut registry = UserRegistry::default();
+        let alice = IdentityKeyPair::generate();
+        let challenge = new_challenge().unwrap();
+
+        let response = answer(&alice, "alice", &challenge);
+        assert_eq!(registry.login("alice", alice.public_key(), &challenge, &response), Ok(true));
+        assert_eq!(registry.get("alice").unwrap().identity_key, alice.public_key());
+
+        let challenge = new_challenge().unwrap();
+        let response = answer(&alice, "alice", &challenge);
+        assert_eq!(registry.login("alice", alice.public_key(), &challenge, &response), Ok(false));
+    }
+
+    #[test]
+    fn another_key_cannot_take_a_bound_username() {
+        let mut registry = UserRegistry::default();
+        let alice = IdentityKeyPair::generate();
+        let mallory = IdentityKeyPair::generate();
+        let challenge = new_challenge().unwrap();
+        registry.login("alice", alice.public_key(), &challenge, &answer(&alice, "alice", &challenge)).unwrap();
+
+        let challenge = new_challenge().unwrap();
+        let response = answer(&mallory, "alice", &challenge);
+        assert_eq!(registry.login("alice", mallory.public_key(), &challenge, &response), Err(LoginError::UsernameTaken));
+        assert_eq!(registry.get("alice").unwrap().identity_key, alice.public_key());
+    }
+
+    #[test]
+    fn bad_challenge_responses_are_rejected() {
+        let mut registry = UserRegistry::default();
+        let alice = IdentityKeyPair::generate();
+        let challenge = new_challenge().unwrap();
+        let other = new_challenge().unwrap();
+
+        // Signed over the wrong challenge, for the wrong username, or by the wrong key
+        let responses = [
+            answer(&alice, "alice", &other),
+            answer(&alice, "bob", &challenge),
+            answer(&IdentityKeyPair::generate(), "alice", &challenge),
+        ];
+        for response in responses {
+            assert_eq!(registry.login("alice", alice.public_key(), &challenge, &response), Err(LoginError::InvalidSignature));
+        }
+        assert!(registry.get("alice").is_none());
+
+        let response = answer(&alice, "not alice", &challenge);
+        assert_eq!(registry.login("not alice", alice.public_key(), &challenge, &response), Err(LoginError::InvalidUsername));
+    }
+
+    #[test]
+    fn challenges_are_used_once() {
+        let mut challenge = LoginChallenge::default();
+        assert_eq!(challenge.take("alice"), None);
+
+        let issued = challenge.issue("alice").unwrap();
+        assert_eq!(challenge.take("alice"), Some(issued));
+        assert_eq!(challenge.take("alice"), None);
+
+        // Answering for another username uses the challenge up too
+        challenge.issue("alice").unwrap();
+        assert_eq!(challenge.take("bob"), None);
+        assert_eq!(challenge.take("alice"), None);
+
+        let first = challenge.issue("alice").unwrap();
+        let second = challenge.issue("alice").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(challenge.take("alice"), Some(second));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::crypto::{Crypto, CryptoError};
+use ring::aead::LessSafeKey;
+use ring::digest::SHA256;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Plaintext bytes in every chunk of a file but the last
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Length of the random key each file is encrypted with
+const FILE_KEY_LEN: usize = 32;
+
+/// A file uploaded in encrypted chunks, and everything needed to fetch and check it
+///
+/// Sent to the recipient inside an encrypted message, so the relay storing the
+/// chunks only ever learns the blob ID and the chunk sizes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub blob_id: String,
+    pub file_name: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    /// Random key the chunks are encrypted with, used for this file only
+    pub key: Vec<u8>,
+    /// SHA-256 of the whole plaintext file
+    pub digest: Vec<u8>,
+}
+
+impl Attachment {
+    /// Describe a new file of `size` bytes with a fresh key and blob ID
+    ///
+    /// The digest is filled in once the file has been read.
+    pub fn new(file_name: &str, size: u64) -> Result<Self, CryptoError> {
+        let mut key = vec![0u8; FILE_KEY_LEN];
+        SystemRandom::new().fill(&mut key).map_err(|_| CryptoError::RandomFailed)?;
+        Ok(Attachment {
+            blob_id: uuid::Uuid::new_v4().to_string(),
+            file_name: file_name.to_string(),
+            size,
+            chunk_count: size.div_ceil(CHUNK_SIZE as u64).max(1) as u32,
+            key,
+            digest: Vec::new(),
+        })
+    }
+
+    /// Check the fields a peer sent fit together, before any of them are used
+    pub fn is_well_formed(&self) -> bool {
+        uuid::Uuid::parse_str(&self.blob_id).is_ok()
+            && self.key.len() == FILE_KEY_LEN
+            && self.digest.len() == SHA256.output_len
+            && self.chunk_count as u64 == self.size.div_ceil(CHUNK_SIZE as u64).max(1)
+    }
+
+    /// Number of plaintext bytes in chunk `index`
+    pub fn chunk_len(&self, index: u32) -> usize {
+        let start = index as u64 * CHUNK_SIZE as u64;
+        self.size.saturating_sub(start).min(CHUNK_SIZE as u64) as usize
+    }
+
+    /// Encrypt chunk `index` of the file
+    pub fn encrypt_chunk(&self, index: u32, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Crypto::encrypt_with_aad(&self.chunk_key()?, plaintext, &self.chunk_aad(index))
+    }
+
+    /// Decrypt chunk `index`, failing if it was altered, moved or is the wrong length
+    pub fn decrypt_chunk(&self, index: u32, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let plaintext = Crypto::decrypt_with_aad(&self.chunk_key()?, ciphertext, &self.chunk_aad(index))?;
+        if index >= self.chunk_count || plaintext.len() != self.chunk_len(index) {
+            return Err(CryptoError::DecryptionFailed);
+        }
+        Ok(plaintext)
+    }
+
+    /// The file name without any directories, or `None` if there's nothing safe to save it as
+    pub fn safe_file_name(&self) -> Option<&str> {
+        Path::new(&self.file_name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .filter(|name| !name.starts_with('.'))
+    }
+
+    fn chunk_key(&self) -> Result<LessSafeKey, CryptoError> {
+        let key: &[u8; FILE_KEY_LEN] = self.key.as_slice().try_into().map_err(|_| CryptoError::DecryptionFailed)?;
+        Crypto::create_symmetric_key(key, self.blob_id.as_bytes(), "attachment chunk")
+    }
+
+    /// Bind each chunk to its file and position, so chunks can't be reordered or swapped between files
+    fn chunk_aad(&self, index: u32) -> Vec<u8> {
+        [
+            (self.blob_id.len() as u32).to_be_bytes().as_slice(),
+            self.blob_id.as_bytes(),
+            &self.size.to_be_bytes(),
+            &self.chunk_count.to_be_bytes(),
+            &index.to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(size: u64) -> Attachment {
+        let mut attachment = Attachment::new("notes.txt", size).unwrap();
+        attachment.digest = vec![0; SHA256.output_len];
+        attachment
+    }
+
+    fn file(size: usize) -> Vec<u8> {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        for size in [0, 10, CHUNK_SIZE, 2 * CHUNK_SIZE + 10] {
+            let attachment = attachment(size as u64);
+            let plaintext = file(size);
+            let mut decrypted = Vec::new();
+            for (index, chunk) in (0..attachment.chunk_count).zip(plaintext.chunks(CHUNK_SIZE).chain([&[][..]])) {
+                assert_eq!(chunk.len(), attachment.chunk_len(index));
+                let ciphertext = attachment.encrypt_chunk(index, chunk).unwrap();
+                decrypted.extend(attachment.decrypt_chunk(index, &ciphertext).unwrap());
+            }
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn chunks_cannot_be_moved_within_or_between_files() {
+        let attachment = attachment(2 * CHUNK_SIZE as u64);
+        let chunk = file(CHUNK_SIZE);
+        let ciphertext = attachment.encrypt_chunk(0, &chunk).unwrap();
+
+        assert_eq!(attachment.decrypt_chunk(1, &ciphertext), Err(CryptoError::DecryptionFailed));
+
+        let mut other_file = attachment.clone();
+        other_file.blob_id = uuid::Uuid::new_v4().to_string();
+        assert_eq!(other_file.decrypt_chunk(0, &ciphertext), Err(CryptoError::DecryptionFailed));
+
+        let mut truncated = attachment.clone();
+        truncated.size = CHUNK_SIZE as u64;
+        truncated.chunk_count = 1;
+        assert_eq!(truncated.decrypt_chunk(0, &ciphertext), Err(CryptoError::DecryptionFailed));
+    }
+
+    #[test]
+    fn tampered_or_short_chunks_are_refused() {
+        let attachment = attachment(CHUNK_SIZE as u64 + 10);
+        let ciphertext = attachment.encrypt_chunk(0, &file(CHUNK_SIZE)).unwrap();
+        for index in [0, 12, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[index] ^= 1;
+            assert_eq!(attachment.decrypt_chunk(0, &tampered), Err(CryptoError::DecryptionFailed));
+        }
+
+        let short = attachment.encrypt_chunk(1, &file(9)).unwrap();
+        assert_eq!(attachment.decrypt_chunk(1, &short), Err(CryptoError::DecryptionFailed));
+        let past_the_end = attachment.encrypt_chunk(2, &[]).unwrap();
+        assert_eq!(attachment.decrypt_chunk(2, &past_the_end), Err(CryptoError::DecryptionFailed));
+    }
+
+    #[test]
+    fn malformed_attachments_are_refused() {
+        let attachment = attachment(CHUNK_SIZE as u64 + 1);
+        assert!(attachment.is_well_formed());
+
+        let mut bad_blob_id = attachment.clone();
+        bad_blob_id.blob_id = "../blobs".to_string();
+        let mut bad_key = attachment.clone();
+        bad_key.key.pop();
+        let mut bad_digest = attachment.clone();
+        bad_digest.digest.clear();
+        let mut bad_count = attachment;
+        bad_count.chunk_count = 1;
+        for attachment in [bad_blob_id, bad_key, bad_digest, bad_count] {
+            assert!(!attachment.is_well_formed());
+        }
+    }
+
+    #[test]
+    fn file_names_lose_their_directories() {
+        let mut attachment = attachment(0);
+        for (name, safe) in [("notes.txt", Some("notes.txt")), ("../../etc/passwd", Some("passwd")), (".bashrc", None), ("..", None)] {
+            attachment.file_name = name.to_string();
+            assert_eq!(attachment.safe_file_name(), safe);
+        }
+    }
+}
@@ -1,118 +1,349 @@
 use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
 use futures_util::{StreamExt, SinkExt};
-use tokio::io::{self, AsyncBufReadExt, BufReader};
-use tokio::sync::Mutex;
-use serde_json::json;
-use p2p_sparse_messaging::crypto::{Crypto};
+use tokio::io::{self, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader, Lines, Stdin};
+use p2p_sparse_messaging::accounts::{challenge_message, is_valid_username};
+use p2p_sparse_messaging::attachments::{Attachment, CHUNK_SIZE};
+use p2p_sparse_messaging::contacts::{ContactStore, KeyChange};
+use p2p_sparse_messaging::content::{Content, ReceiptKind};
+use p2p_sparse_messaging::crypto::{AssociatedData, CryptoError, PROTOCOL_VERSION};
+use p2p_sparse_messaging::fallback::sign_request;
+use p2p_sparse_messaging::fingerprint::safety_number;
+use p2p_sparse_messaging::groups::{Group, GroupMessage, GroupRoster};
+use p2p_sparse_messaging::history::{HistoryEntry, MessageHistory};
+use p2p_sparse_messaging::identity::IdentityKeyPair;
+use p2p_sparse_messaging::keystore::{Keystore, KeystoreError};
+use p2p_sparse_messaging::mls::{KeyPackage, KeyPackageBundle, MlsGroup, MlsMessage, Processed, Proposal, Welcome};
+use p2p_sparse_messaging::p2p::{start_p2p_listener, DirectLinks};
+use p2p_sparse_messaging::prekeys::PreKeyStore;
+use p2p_sparse_messaging::protocol::{ClientMessage, Encoding, Envelope, MlsPayload, ProtocolError, RelayStatus, ServerMessage};
+use p2p_sparse_messaging::session::SessionManager;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use base64;
-use std::collections::HashMap;
-use tokio_tungstenite::tungstenite::{Error, Message};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Mutex};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use ring::digest::{Context, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Number of one-time prekeys generated for a new prekey store, and kept on the server
+const ONE_TIME_PREKEY_COUNT: usize = 20;
+
+/// Number of one-time prekeys left on the server below which more are uploaded
+const PREKEY_LOW_WATER: usize = 5;
+
+/// Address the direct P2P listener binds to unless `P2P_LISTEN_ADDR` is set
+const DEFAULT_P2P_LISTEN_ADDR: &str = "127.0.0.1:0";
+
+/// Frame encoding requested from the server unless `WIRE_ENCODING` is set
+const DEFAULT_WIRE_ENCODING: Encoding = Encoding::Cbor;
+
+/// Number of sent messages whose status is remembered for '/sent'
+const SENT_HISTORY_LEN: usize = 100;
+
+/// How long messages wait for a peer's keys before they're given up on
+const KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of MLS key packages kept published for others to add us to groups with
+const KEY_PACKAGE_COUNT: usize = 5;
+
+/// Base URL of the relay's HTTP endpoints
+const RELAY_HTTP_URL: &str = "http://127.0.0.1:3030";
+
+/// How many times a chunk upload or download is tried before giving up
+const TRANSFER_ATTEMPTS: u32 = 3;
+
+/// How long to wait before retrying a chunk after a network error
+const TRANSFER_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Messages '/history' shows when the user doesn't say how many
+const DEFAULT_HISTORY_LEN: usize = 20;
+
+/// How many wrong passphrases the keystore prompt accepts before giving up
+const PASSPHRASE_ATTEMPTS: usize = 3;
+
+/// Most envelopes remembered to spot one that arrives both directly and through the relay
+const RECENT_MESSAGES: usize = 1000;
+
+/// Names of the secrets sealed in the keystore
+const IDENTITY_ENTRY: &str = "identity";
+const PREKEYS_ENTRY: &str = "prekeys";
+const SESSIONS_ENTRY: &str = "sessions";
+const CONTACTS_ENTRY: &str = "contacts";
+const HISTORY_KEY_ENTRY: &str = "history_key";
 
 #[tokio::main]
 async fn main() {
-    let url = "ws://127.0.0.1:3030/ws";
+    // JSON frames are easier to read when debugging, CBOR frames are smaller
+    let encoding = match std::env::var("WIRE_ENCODING") {
+        Ok(name) => Encoding::from_name(&name).unwrap_or_else(|| {
+            println!("Unknown wire encoding '{}', using {}", name, DEFAULT_WIRE_ENCODING.name());
+            DEFAULT_WIRE_ENCODING
+        }),
+        Err(_) => DEFAULT_WIRE_ENCODING,
+    };
+    let url = format!("ws://127.0.0.1:3030/ws?encoding={}", encoding.name());
 
-    // Prompt user for their display name
-    println!("Enter your display name:");
+    // Prompt user for their username
+    println!("Enter your username:");
     let stdin = BufReader::new(io::stdin());
     let mut lines = stdin.lines();
 
     let display_name = if let Ok(Some(line)) = lines.next_line().await {
         line.trim().to_string()
     } else {
-        println!("Failed to read display name.");
+        println!("Failed to read username.");
         return;
     };
+    if !is_valid_username(&display_name) {
+        println!("Usernames are 1-32 letters, digits, '-' or '_'.");
+        return;
+    }
 
-    // Initialize crypto and generate key pair
-    let mut crypto = Crypto::new();
-    let public_key = crypto.public_key().to_vec();
+    // Our secrets are sealed with a passphrase, chosen the first time this username is used
+    let keystore_path = format!("{}.keystore", display_name);
+    let keystore = if Path::new(&keystore_path).exists() {
+        unlock_keystore(&keystore_path, &mut lines).await
+    } else {
+        match choose_passphrase(&mut lines).await.map(|passphrase| Keystore::create(&keystore_path, &passphrase)) {
+            Some(Ok(keystore)) => Some(keystore),
+            Some(Err(e)) => {
+                println!("Failed to create keystore {}: {}", keystore_path, e);
+                None
+            }
+            None => None,
+        }
+    };
+    let Some(mut keystore) = keystore else {
+        return;
+    };
+
+    // Load (or create) the long-term identity for this username
+    let identity_path = format!("{}.identity", display_name);
+    let identity = load_secret(&mut keystore, IDENTITY_ENTRY, &identity_path, |path| std::fs::read(path), || {
+        IdentityKeyPair::generate().secret_bytes()
+    });
+    let identity = match identity.and_then(|secret| IdentityKeyPair::from_secret_bytes(&secret).map_err(KeystoreError::Io)) {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("Failed to load identity key: {}", e);
+            return;
+        }
+    };
 
-    let (socket, _) = connect_async(url).await.expect("Failed to connect");
+    // Load (or create) the prekeys others use to reach us while we're offline
+    let prekeys_path = format!("{}.prekeys", display_name);
+    let prekeys = load_secret(&mut keystore, PREKEYS_ENTRY, &prekeys_path, |path| PreKeyStore::load(path), || {
+        PreKeyStore::generate(&identity, ONE_TIME_PREKEY_COUNT)
+    });
+    let mut prekeys = match prekeys {
+        Ok(prekeys) => prekeys,
+        Err(e) => {
+            println!("Failed to load prekeys: {}", e);
+            return;
+        }
+    };
+
+    // Load the identity keys we've seen for our contacts
+    let contacts_path = format!("{}.contacts", display_name);
+    let contacts = match load_secret(&mut keystore, CONTACTS_ENTRY, &contacts_path, |path| ContactStore::load(path), ContactStore::default) {
+        Ok(contacts) => Arc::new(Mutex::new(contacts)),
+        Err(e) => {
+            println!("Failed to load contacts: {}", e);
+            return;
+        }
+    };
+
+    // Resume the ratchet sessions we had open when the client last stopped
+    let sessions = match keystore.get::<SessionManager>(SESSIONS_ENTRY) {
+        Ok(sessions) => Arc::new(Mutex::new(sessions.unwrap_or_default())),
+        Err(e) => {
+            println!("Failed to load sessions: {}", e);
+            return;
+        }
+    };
+
+    // Open the encrypted history of our conversations, with its own key from the keystore
+    let history_path = format!("{}.history", display_name);
+    let history = match open_history(&mut keystore, &history_path, &identity) {
+        Ok(history) => Arc::new(history),
+        Err(e) => {
+            println!("Failed to open message history at {}: {}", history_path, e);
+            return;
+        }
+    };
+    let keystore = Arc::new(Mutex::new(keystore));
+    let keystore_clone = keystore.clone();
+    let history_clone = history.clone();
+
+    let (socket, _) = connect_async(url.as_str()).await.expect("Failed to connect");
     println!("Connected to the server!");
 
     // Split the WebSocket into writer and reader
     let (mut writer, mut reader) = socket.split();
 
-    // Send the display name and public key to the server
+    // Log in: the server sends a challenge we sign with our identity key
     writer
-        .send(json!({ "type": "Register", "name": display_name, "public_key": public_key }).to_string().into())
+        .send(to_frame(ClientMessage::Login { username: display_name.clone() }, encoding))
         .await
-        .expect("Failed to send display name and public key");
+        .expect("Failed to send login");
+    let Some(ServerMessage::Challenge { challenge, .. }) = next_message(&mut reader).await else {
+        println!("Login failed: no challenge from the server");
+        return;
+    };
+    let challenge_response = identity.sign(&challenge_message(&display_name, &challenge));
+
+    // Send the username, identity key and challenge response to the server
+    writer
+        .send(to_frame(ClientMessage::Register {
+            name: display_name.clone(),
+            identity_key: identity.public_key().to_vec(),
+            challenge_response,
+        }, encoding))
+        .await
+        .expect("Failed to send login response");
+
+    // Our user ID, which the server uses to route messages to us
+    let own_id = match next_message(&mut reader).await {
+        Some(ServerMessage::Registered { client_id }) => client_id,
+        Some(ServerMessage::LoginFailed { reason, .. }) => {
+            println!("Login failed: {}", reason);
+            return;
+        }
+        _ => {
+            println!("Login failed: unexpected response from the server");
+            return;
+        }
+    };
+    println!("Logged in as {}", own_id);
+
+    // Publish our signed prekey, replaced once it's a week old, and the one-time prekeys the server doesn't have
+    prekeys.rotate_signed_prekey_if_stale(&identity);
+    let upload = match prekey_upload(&mut prekeys) {
+        Ok(upload) => upload,
+        Err(e) => {
+            println!("Failed to load prekeys: {}", e);
+            return;
+        }
+    };
+    save_secret(&keystore, PREKEYS_ENTRY, &prekeys).await;
+    writer.send(to_frame(upload, encoding)).await.expect("Failed to upload prekeys");
+
+    // Listen for direct connections and advertise the address through the relay
+    let (direct_tx, mut direct_rx) = mpsc::unbounded_channel::<Envelope>();
+    let identity = Arc::new(identity);
+    let direct_links = DirectLinks::new(identity.clone(), &own_id, direct_tx);
+    let listen_addr = std::env::var("P2P_LISTEN_ADDR").unwrap_or_else(|_| DEFAULT_P2P_LISTEN_ADDR.to_string());
+    match start_p2p_listener(&listen_addr, direct_links.clone()).await {
+        Ok(local_addr) => {
+            writer
+                .send(to_frame(ClientMessage::AdvertiseAddresses { addresses: vec![local_addr.to_string()] }, encoding))
+                .await
+                .expect("Failed to advertise addresses");
+        }
+        Err(e) => println!("Failed to start P2P listener on {}, using the relay only: {}", listen_addr, e),
+    }
+
+    // Frames for the server go through one writer task so any task can send them
+    let (server_tx, mut server_rx) = mpsc::unbounded_channel::<ClientMessage>();
+    tokio::spawn(async move {
+        while let Some(message) = server_rx.recv().await {
+            if let Err(e) = writer.send(to_frame(message, encoding)).await {
+                println!("Failed to send to the server: {}", e);
+                break;
+            }
+        }
+    });
 
-    // Store connected clients and their shared secrets
+    // Store connected clients and their ratchet sessions
     let connected_clients: Arc<tokio::sync::Mutex<Vec<(String, String)>>> =
         Arc::new(tokio::sync::Mutex::new(Vec::new()));
-    let shared_secrets = Arc::new(tokio::sync::Mutex::new(HashMap::<String, [u8; 32]>::new()));
 
     // Spawn a task to handle incoming messages
     let clients_clone = connected_clients.clone();
-    let crypto_clone = Arc::new(tokio::sync::Mutex::new(crypto));
-    let shared_secrets_clone = shared_secrets.clone();
+    let identity_clone = identity.clone();
+    let prekeys_clone = Arc::new(tokio::sync::Mutex::new(prekeys));
+    let sessions_clone = sessions.clone();
+    let own_id_clone = own_id.clone();
+    let contacts_clone = contacts.clone();
+    // Changed identity keys waiting for the user to '/approve' them, keyed by user ID
+    let pending_approvals = Arc::new(Mutex::new(HashMap::<String, Vec<u8>>::new()));
+    let pending_approvals_clone = pending_approvals.clone();
+    let direct_links_clone = direct_links.clone();
+    // Direct addresses peers advertised through the relay, keyed by user ID
+    let peer_addresses = Arc::new(Mutex::new(HashMap::<String, Vec<String>>::new()));
+    let peer_addresses_clone = peer_addresses.clone();
+    // Messages we've sent and how far each has got
+    let sent = Arc::new(Mutex::new(SentMessages::default()));
+    let sent_clone = sent.clone();
+    let outbox = Outbox {
+        own_id: own_id.clone(),
+        sessions: sessions.clone(),
+        direct_links: direct_links.clone(),
+        peer_addresses: peer_addresses.clone(),
+        server_tx: server_tx.clone(),
+        pending_requests: Arc::new(Mutex::new(HashMap::new())),
+        awaiting_keys: Arc::new(Mutex::new(HashMap::new())),
+        sent: sent.clone(),
+        keystore: keystore.clone(),
+    };
+    let outbox_clone = outbox.clone();
+    // Groups we're in, keyed by group ID
+    let groups = Arc::new(Mutex::new(HashMap::<String, Group>::new()));
+    let groups_clone = groups.clone();
+    // Experimental MLS groups, with fresh key packages published for others to add us with
+    let mls = MlsState {
+        own_id: own_id.clone(),
+        identity: identity.clone(),
+        server_tx: server_tx.clone(),
+        groups: Arc::new(Mutex::new(HashMap::new())),
+        key_packages: Arc::new(Mutex::new(Vec::new())),
+        key_package_requests: Arc::new(Mutex::new(HashMap::new())),
+        pending_commits: Arc::new(Mutex::new(HashMap::new())),
+        history: history.clone(),
+    };
+    mls.publish_key_packages().await;
+    let mls_clone = mls.clone();
+    // Encrypted files going to and from the relay's blob store
+    let transfers = Transfers {
+        own_id: own_id.clone(),
+        identity: identity.clone(),
+        http: Client::new(),
+        download_dir: PathBuf::from(format!("{}_downloads", display_name)),
+        received: Arc::new(Mutex::new(Vec::new())),
+        downloading: Arc::new(Mutex::new(HashSet::new())),
+    };
+    let transfers_clone = transfers.clone();
+    // Messages shown to the user but not yet confirmed read, keyed by sender
+    let unread = Arc::new(Mutex::new(HashMap::<String, Vec<String>>::new()));
+    let unread_clone = unread.clone();
 
+    // Relay frames and direct envelopes from peers are handled the same way
+    let (inbound_tx, mut inbound_rx) = mpsc::unbounded_channel::<ServerMessage>();
+    let relay_tx = inbound_tx.clone();
+    // Group rosters set aside until their signer's key is pinned
+    let rosters = PendingRosters { pending: Arc::new(Mutex::new(HashMap::new())), inbound_tx: inbound_tx.clone() };
     tokio::spawn(async move {
         println!("WebSocket reader task started!");
         while let Some(msg) = reader.next().await {
             println!("Received message");
             match msg {
-                Ok(msg) if msg.is_text() => {
-                    let message = msg.to_text().unwrap();
-                    println!("Received message: {}", message);
-
-                    // Try to parse the message as a client list update
-                    if let Ok(client_list) = serde_json::from_str::<Vec<(String, String)>>(message) {
-                        // Update the connected client list
-                        let mut clients = clients_clone.lock().await;
-                        *clients = client_list;
-                        println!("Updated connected clients: {:?}", *clients);
-                        continue; // Skip further processing since this is a client list
-                    }
-
-                    // Try to parse the message as a structured JSON object
-                    if let Ok(parsed_message) = serde_json::from_str::<serde_json::Value>(message) {
-                        if parsed_message["type"] == "PublicKeyResponse" {
-                            // Handle public key response
-                            let peer_id = parsed_message["client_id"].as_str().unwrap();
-                            let peer_public_key = parsed_message["public_key"].as_array().unwrap();
-
-                            let peer_public_key_bytes: Vec<u8> =
-                                peer_public_key.iter().map(|v| v.as_u64().unwrap() as u8).collect();
-
-                            let mut crypto = crypto_clone.lock().await;
-                            crypto.derive_session_key(&peer_public_key_bytes);
-
-                            let mut shared_secrets = shared_secrets_clone.lock().await;
-                            shared_secrets.insert(peer_id.to_string(), crypto.get_shared_secret());
-
-                            println!("Shared secret established with client: {}", peer_id);
-                            continue; // Changed from return to continue
-                        } else if let Some(from) = parsed_message["from"].as_str() {
-                            // Handle encrypted messages
-                            if let Some(encrypted_message) = parsed_message["message"].as_str() {
-                                println!("Received encrypted message from {}: {}", from, encrypted_message);
-                                let mut shared_secrets = shared_secrets_clone.lock().await;
-                                if let Some(secret) = shared_secrets.get(from) {
-                                    let key = Crypto::create_symmetric_key(secret);
-                                    if let Ok(decoded_message) = base64::decode(encrypted_message) {
-                                        let decrypted_message = Crypto::decrypt_with_key(&key, &decoded_message);
-                                        println!(
-                                            "Decrypted message from {}: {:?}",
-                                            from,
-                                            String::from_utf8_lossy(&decrypted_message)
-                                        );
-                                    } else {
-                                        println!("Failed to decode encrypted message from {}", from);
-                                    }
-                                } else {
-                                    println!("No shared secret available for sender: {}", from);
-                                }
-                            }
-                        } else {
-                            println!("Unknown message type received: {}", message);
+                Ok(msg) if msg.is_text() || msg.is_binary() => {
+                    if let Message::Text(text) = &msg {
+                        println!("Received message: {}", text);
+                    }
+                    match from_frame(msg) {
+                        Ok(message) => {
+                            let _ = relay_tx.send(message);
                         }
-                    } else {
-                        println!("Malformed message received: {}", message);
+                        Err(e) => println!("Malformed message received: {}", e),
                     }
                 }
                 Err(e) => {
@@ -126,46 +357,1548 @@ async fn main() {
         }
         println!("WebSocket reader task ended!");
     });
+    tokio::spawn(async move {
+        while let Some(envelope) = direct_rx.recv().await {
+            println!("Received direct message from {}", envelope.from);
+            if inbound_tx.send(ServerMessage::Deliver { envelope }).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut handled = RecentMessages::default();
+    tokio::spawn(async move {
+        while let Some(message) = inbound_rx.recv().await {
+            match message {
+                ServerMessage::ClientList { clients: client_list } => {
+                    // Update the connected client list
+                    let mut clients = clients_clone.lock().await;
+                    *clients = client_list;
+                    println!("Updated connected clients: {:?}", *clients);
+                }
+                ServerMessage::Addresses { client_id: peer_id, addresses } => {
+                    println!("Direct addresses for {}: {:?}", peer_id, addresses);
+                    peer_addresses_clone.lock().await.insert(peer_id, addresses);
+                }
+                ServerMessage::PreKeyCount { remaining } if remaining < PREKEY_LOW_WATER => {
+                    // Others have used up most of our one-time prekeys, so top the server back up
+                    let mut prekeys = prekeys_clone.lock().await;
+                    prekeys.replenish(ONE_TIME_PREKEY_COUNT - remaining);
+                    match prekey_upload(&mut prekeys) {
+                        Ok(upload) => {
+                            save_secret(&keystore_clone, PREKEYS_ENTRY, &*prekeys).await;
+                            println!("Uploading {} more one-time prekeys", ONE_TIME_PREKEY_COUNT - remaining);
+                            let _ = outbox_clone.server_tx.send(upload);
+                        }
+                        Err(e) => println!("Failed to upload prekeys: {}", e),
+                    }
+                }
+                ServerMessage::PreKeyCount { .. } => {}
+                ServerMessage::PreKeyBundle { client_id: peer_id, request_id, bundle } => {
+                    outbox_clone.pending_requests.lock().await.remove(&request_id);
+
+                    // Handle prekey bundle response by running X3DH as the initiator
+                    // Refuse identity keys that don't match the one pinned for this contact
+                    let name = display_name_of(&clients_clone, &peer_id).await;
+                    let change = check_identity(&contacts_clone, &pending_approvals_clone, &name, &peer_id, &bundle.identity_key).await;
+                    if change.is_none() {
+                        outbox_clone.fail(&peer_id, "their identity key changed").await;
+                        continue;
+                    }
+
+                    // Refuse bundles whose signed prekey isn't signed by the peer's identity
+                    let mut sessions = sessions_clone.lock().await;
+                    let result = sessions.initiate(&peer_id, &identity_clone, &bundle);
+                    if result.is_ok() {
+                        save_secret(&keystore_clone, SESSIONS_ENTRY, &*sessions).await;
+                    }
+                    drop(sessions);
+                    match result {
+                        Ok(()) => {
+                            println!("Session established with client: {}", peer_id);
+                            direct_links_clone.trust(&peer_id, &bundle.identity_key).await;
+                            if change == Some(KeyChange::New) {
+                                pin_identity(&contacts_clone, &keystore_clone, &peer_id, &bundle.identity_key).await;
+                            }
+
+                            // Send what was waiting for the keys
+                            outbox_clone.flush(&peer_id).await;
+                            rosters.release(&peer_id).await;
+                        }
+                        Err(e) => {
+                            println!("Invalid prekey bundle for client {}: {}", peer_id, e);
+                            outbox_clone.fail(&peer_id, "their prekey bundle was invalid").await;
+                        }
+                    }
+                }
+                ServerMessage::KeyPackage { client_id: peer_id, request_id, key_package } => {
+                    let Some(group_id) = mls_clone.key_package_requests.lock().await.remove(&request_id) else {
+                        println!("Unexpected key package for {}", peer_id);
+                        continue;
+                    };
+                    if !key_package.verify() || key_package.member() != peer_id {
+                        println!("Invalid key package for {}", peer_id);
+                        continue;
+                    }
+
+                    // Key packages are signed with the identity key, which we pin like a session's
+                    let name = display_name_of(&clients_clone, &peer_id).await;
+                    let change = check_identity(&contacts_clone, &pending_approvals_clone, &name, &peer_id, key_package.identity_key()).await;
+                    if change.is_none() {
+                        println!("Not adding {} until their new key is approved", peer_id);
+                        continue;
+                    }
+                    if change == Some(KeyChange::New) {
+                        pin_identity(&contacts_clone, &keystore_clone, &peer_id, key_package.identity_key()).await;
+                    }
+
+                    let mut groups = mls_clone.groups.lock().await;
+                    if let Some(group) = groups.get_mut(&group_id) {
+                        mls_clone.commit(group, vec![key_package], Vec::new()).await;
+                    }
+                }
+                ServerMessage::Deliver { envelope } => {
+                    // Handle encrypted messages
+                    let from = envelope.from.as_str();
+                    println!("Received encrypted message from {} ({} bytes)", from, envelope.message.len());
+
+                    // Reject envelopes that weren't addressed to us in this protocol version
+                    if envelope.to != own_id_clone || envelope.version != PROTOCOL_VERSION || envelope.message_id.is_empty() {
+                        println!("Rejected message from {}: envelope metadata mismatch", from);
+                        continue;
+                    }
+
+                    // MLS messages carry their own signatures and keys
+                    if let Some(payload) = envelope.mls {
+                        mls_clone.receive(from, &envelope.message, payload).await;
+                        continue;
+                    }
+
+                    // Group messages are encrypted with the sender's key for the group, not our session
+                    if let Some(group_id) = &envelope.group {
+                        let associated_data = AssociatedData {
+                            version: PROTOCOL_VERSION,
+                            sender: from,
+                            recipient: group_id,
+                            message_id: &envelope.message_id,
+                        };
+                        let mut groups = groups_clone.lock().await;
+                        let Some(group) = groups.get_mut(group_id) else {
+                            println!("Message from {} for unknown group {}", from, group_id);
+                            continue;
+                        };
+                        let decrypted_message = GroupMessage::from_bytes(&envelope.message)
+                            .and_then(|message| group.decrypt(from, &message, &associated_data));
+                        match decrypted_message.map(|message| Content::from_bytes(&message)) {
+                            Ok(Ok(Content::Text { body })) => {
+                                println!("[{}] {}: {:?}", group.name, from, body);
+                                record(&history_clone, &format!("#{}", group.name), from, &body);
+                            }
+                            Ok(_) => println!("Unreadable group message from {}", from),
+                            Err(e) => println!("Failed to decrypt group message from {}: {}", from, e),
+                        }
+                        continue;
+                    }
+                    let associated_data = AssociatedData {
+                        version: PROTOCOL_VERSION,
+                        sender: from,
+                        recipient: &own_id_clone,
+                        message_id: &envelope.message_id,
+                    };
+
+                    // A direct send that wasn't acknowledged in time is sent through the relay as well
+                    if handled.contains(from, &envelope.message_id) {
+                        println!("Dropped duplicate message {} from {}", envelope.message_id, from);
+                        continue;
+                    }
+
+                    // The first message of a session carries an X3DH header, and the session
+                    // only replaces ours once that message decrypts with it
+                    let mut sessions = sessions_clone.lock().await;
+                    let decrypted_message = if let Some(initial) = &envelope.initial {
+                        let name = display_name_of(&clients_clone, from).await;
+                        let change = check_identity(&contacts_clone, &pending_approvals_clone, &name, from, &initial.identity_key).await;
+                        if change.is_none() {
+                            println!("Dropped message from {} until their new key is approved", from);
+                            continue;
+                        }
+
+                        let mut prekeys = prekeys_clone.lock().await;
+                        let accepted = sessions.respond(from, &identity_clone, &prekeys, initial).and_then(|session| {
+                            sessions.accept(from, session, &mut prekeys, initial, &envelope.message, &associated_data)
+                        });
+                        match accepted {
+                            Ok(decrypted_message) => {
+                                save_secret(&keystore_clone, PREKEYS_ENTRY, &*prekeys).await;
+                                println!("Session established with client: {}", from);
+                                direct_links_clone.trust(from, &initial.identity_key).await;
+                                if change == Some(KeyChange::New) {
+                                    pin_identity(&contacts_clone, &keystore_clone, from, &initial.identity_key).await;
+                                }
+                                rosters.release(from).await;
+                                decrypted_message
+                            }
+                            Err(e) => {
+                                println!("Failed X3DH handshake with client {}: {}", from, e);
+                                continue;
+                            }
+                        }
+                    } else {
+                        if !sessions.has_session(from) {
+                            println!("No session available for sender: {}", from);
+                            continue;
+                        }
+                        match sessions.decrypt(from, &envelope.message, &associated_data) {
+                            Ok(decrypted_message) => decrypted_message,
+                            Err(e) => {
+                                println!("Failed to decrypt message from {}: {}", from, e);
+                                continue;
+                            }
+                        }
+                    };
+                    save_secret(&keystore_clone, SESSIONS_ENTRY, &*sessions).await;
+                    drop(sessions);
+                    handled.insert(from, &envelope.message_id);
+
+                    match Content::from_bytes(&decrypted_message) {
+                        Ok(Content::Text { body }) => {
+                            println!("Decrypted message from {}: {:?}", from, body);
+                            record(&history_clone, from, from, &body);
+
+                            // Let the sender know it arrived; it's read once the user is back at the prompt
+                            let receipt = Content::Receipt { kind: ReceiptKind::Delivered, message_ids: vec![envelope.message_id.clone()] };
+                            if let Err(e) = outbox_clone.send(from, &uuid::Uuid::new_v4().to_string(), &receipt).await {
+                                println!("Failed to send delivery receipt to {}: {}", from, e);
+                            }
+                            unread_clone.lock().await.entry(from.to_string()).or_default().push(envelope.message_id);
+                        }
+                        Ok(Content::File { attachment }) => {
+                            if !attachment.is_well_formed() {
+                                println!("Malformed file from {}", from);
+                                continue;
+                            }
+                            let mut received = transfers_clone.received.lock().await;
+                            received.push((from.to_string(), attachment.clone()));
+                            println!(
+                                "{} sent you {} ({} bytes). Type '/download {}' to save it.",
+                                from,
+                                attachment.file_name,
+                                attachment.size,
+                                received.len()
+                            );
+                            drop(received);
+                            record(&history_clone, from, from, &format!("[file] {}", attachment.file_name));
+
+                            let receipt = Content::Receipt { kind: ReceiptKind::Delivered, message_ids: vec![envelope.message_id.clone()] };
+                            if let Err(e) = outbox_clone.send(from, &uuid::Uuid::new_v4().to_string(), &receipt).await {
+                                println!("Failed to send delivery receipt to {}: {}", from, e);
+                            }
+                            unread_clone.lock().await.entry(from.to_string()).or_default().push(envelope.message_id);
+                        }
+                        Ok(Content::Receipt { kind, message_ids }) => {
+                            let status = match kind {
+                                ReceiptKind::Delivered => MessageStatus::Delivered,
+                                ReceiptKind::Read => MessageStatus::Read,
+                            };
+                            // Only the recipient of a message can confirm it
+                            let mut sent = sent_clone.lock().await;
+                            for message_id in message_ids {
+                                if let Some(message) = sent.get_mut(&message_id).filter(|message| message.recipient == from) {
+                                    message.advance(status);
+                                }
+                            }
+                        }
+                        Ok(Content::SenderKey { distribution, request_reply }) => {
+                            let reply = {
+                                let mut groups = groups_clone.lock().await;
+                                match groups.get_mut(&distribution.group_id) {
+                                    Some(group) => match group.add_sender_key(from, &distribution) {
+                                        Ok(()) => {
+                                            println!("Received {}'s key for group {}", from, group.name);
+                                            request_reply.then(|| group.distribution())
+                                        }
+                                        Err(e) => {
+                                            println!("Rejected {}'s key for group {}: {}", from, group.name, e);
+                                            None
+                                        }
+                                    },
+                                    None => {
+                                        println!("Received a key from {} for a group we're not in", from);
+                                        None
+                                    }
+                                }
+                            };
+                            if let Some(distribution) = reply {
+                                let content = Content::SenderKey { distribution, request_reply: false };
+                                outbox_clone.send_or_queue(from, &uuid::Uuid::new_v4().to_string(), content).await;
+                            }
+                        }
+                        Err(e) => println!("Unreadable message from {}: {}", from, e),
+                    }
+                }
+                ServerMessage::GroupUpdate { roster } => {
+                    // Rosters apply in order, so one waiting for its signer's key holds up those after it
+                    if rosters.hold_if_waiting(&roster).await {
+                        continue;
+                    }
+
+                    // Only a roster signed with a pinned identity key decides who gets our sender key
+                    let signer_key = if roster.signer == own_id_clone {
+                        Some(identity_clone.public_key().to_vec())
+                    } else {
+                        contacts_clone.lock().await.get(&roster.signer).map(|contact| contact.identity_key.clone())
+                    };
+                    let Some(signer_key) = signer_key else {
+                        println!("Checking {}'s key before accepting their change to group {}", roster.signer, roster.name);
+                        let signer = roster.signer.clone();
+                        rosters.hold(roster).await;
+                        outbox_clone.request_session(&signer).await;
+                        continue;
+                    };
+                    let mut groups = groups_clone.lock().await;
+                    let follows = match groups.get(&roster.group_id) {
+                        Some(group) => roster.follows(group.roster()),
+                        None => roster.is_well_formed(),
+                    };
+                    if !follows || !roster.verify(&signer_key) {
+                        println!("Ignored a change to group {} that {} couldn't have made", roster.name, roster.signer);
+                        continue;
+                    }
+
+                    let (group_id, name, members) = (roster.group_id.clone(), roster.name.clone(), roster.members.clone());
+                    if !members.contains(&own_id_clone) {
+                        if groups.remove(&group_id).is_some() {
+                            println!("You are no longer in group {}", name);
+                        }
+                        continue;
+                    }
+
+                    // A group new to us asks the others for their keys; a membership change rotates ours
+                    let request_reply = match groups.get_mut(&group_id) {
+                        Some(group) => {
+                            if !group.set_roster(roster) {
+                                continue;
+                            }
+                            false
+                        }
+                        None => {
+                            groups.insert(group_id.clone(), Group::new(roster));
+                            true
+                        }
+                    };
+                    println!("Group {} ({}) members: {:?}", name, group_id, members);
+
+                    // Hand our key to the other members over our pairwise sessions
+                    let distribution = groups[&group_id].distribution();
+                    drop(groups);
+                    for member in members.iter().filter(|member| **member != own_id_clone) {
+                        let content = Content::SenderKey { distribution: distribution.clone(), request_reply };
+                        outbox_clone.send_or_queue(member, &uuid::Uuid::new_v4().to_string(), content).await;
+                    }
+                }
+                ServerMessage::Ack { message_id, status } => {
+                    // Our MLS commit won its epoch, so the group moves on with it
+                    let commit_group = mls_clone.pending_commits.lock().await.remove(&message_id);
+                    if let Some(group_id) = commit_group {
+                        let mut groups = mls_clone.groups.lock().await;
+                        if let Some(group) = groups.get_mut(&group_id) {
+                            if group.merge_pending_commit() {
+                                println!("MLS group {} is at epoch {}: {:?}", group.name(), group.epoch(), group.members());
+                            }
+                        }
+                    }
+
+                    let status = match status {
+                        RelayStatus::Accepted => MessageStatus::Accepted,
+                        RelayStatus::Queued => MessageStatus::Queued,
+                        RelayStatus::Delivered => MessageStatus::Relayed,
+                    };
+                    if let Some(message) = sent_clone.lock().await.get_mut(&message_id) {
+                        message.advance(status);
+                    }
+                }
+                ServerMessage::Error { code, request_id, reason } => {
+                    // Errors name the request they answer, so tell the user which one failed
+                    let Some(request_id) = request_id else {
+                        println!("Server error: {} ({})", reason, code);
+                        continue;
+                    };
+                    let commit_group = mls_clone.pending_commits.lock().await.remove(&request_id);
+                    if let Some(group_id) = commit_group {
+                        if let Some(group) = mls_clone.groups.lock().await.get_mut(&group_id) {
+                            group.clear_pending_commit();
+                        }
+                        println!("Server rejected the change to the MLS group: {} ({}). Try again.", reason, code);
+                        continue;
+                    }
+                    if mls_clone.key_package_requests.lock().await.remove(&request_id).is_some() {
+                        println!("Couldn't add the member to the MLS group: {} ({})", reason, code);
+                        continue;
+                    }
+                    if let Some(peer_id) = outbox_clone.pending_requests.lock().await.remove(&request_id) {
+                        println!("Server rejected key request for {}: {} ({})", peer_id, reason, code);
+                        outbox_clone.fail(&peer_id, &reason).await;
+                    } else if let Some(message) = sent_clone.lock().await.get_mut(&request_id) {
+                        message.advance(MessageStatus::Failed);
+                        println!("Server rejected the message: {} ({})", reason, code);
+                    } else {
+                        println!("Server error: {} ({})", reason, code);
+                    }
+                }
+                _ => {
+                    println!("Unexpected message from the server");
+                }
+            }
+        }
+        println!("Message handler task ended!");
+    });
 
 
     // Main loop for user input
-    println!("Type '/list' to see connected clients, or '/quit' to exit.");
+    println!("Type '/list' to see connected clients, '/sent' to see your messages' status,");
+    println!("'/passphrase' to change your keystore's passphrase, '/verify <id>' to check a contact's safety number, '/approve <id>' to accept a contact's changed key,");
+    println!("'/history <id or #group> [count] [page]' and '/search <text>' to look back through your messages,");
+    println!("'/send-file <id> <path>' to send a file, '/files' and '/download <n>' for files sent to you,");
+    println!("'/groups' to list your groups, '/group create <name> [members...]' to start one and");
+    println!("'#<group>:message' to write to it, '/mls create|add|remove|leave|send|groups' for experimental");
+    println!("MLS groups, or '/quit' to exit.");
 
     while let Ok(Some(line)) = lines.next_line().await {
         if line == "/quit" {
             break;
-        } else if line == "/list" {
+        }
+
+        // The user is back at the prompt, so everything shown so far has been read
+        let read: Vec<(String, Vec<String>)> = unread.lock().await.drain().collect();
+        for (peer_id, message_ids) in read {
+            let receipt = Content::Receipt { kind: ReceiptKind::Read, message_ids };
+            if let Err(e) = outbox.send(&peer_id, &uuid::Uuid::new_v4().to_string(), &receipt).await {
+                println!("Failed to send read receipt to {}: {}", peer_id, e);
+            }
+        }
+
+        if line == "/list" {
             let clients = connected_clients.lock().await;
             println!("Connected clients: {:?}", *clients);
-        } else if let Some((recipient, message)) = line.split_once(':') {
-            // Request the recipient's public key if not already known
-            {
-                let shared_secrets = shared_secrets.lock().await;
-                if !shared_secrets.contains_key(recipient) {
-                    writer
-                        .send(json!({ "type": "RequestPublicKey", "for_client": recipient }).to_string().into())
-                        .await
-                        .expect("Failed to request public key");
+        } else if line == "/sent" {
+            let sent = sent.lock().await;
+            for message in sent.iter() {
+                println!("{}", message);
+            }
+        } else if let Some(peer_id) = line.strip_prefix("/approve ") {
+            // Accept a changed identity key the user has been warned about
+            let peer_id = peer_id.trim();
+            let name = display_name_of(&connected_clients, peer_id).await;
+            match pending_approvals.lock().await.remove(peer_id) {
+                Some(identity_key) => {
+                    pin_identity(&contacts, &keystore, peer_id, &identity_key).await;
+                    println!("Approved the new identity key of {}. Check it with '/verify {}'.", name, peer_id);
+                }
+                None => println!("No changed key waiting for approval from {}.", name),
+            }
+        } else if let Some(args) = line.strip_prefix("/verify ") {
+            // Show the safety number, or mark the contact verified once the user has compared it
+            let (peer_id, confirm) = match args.trim().split_once(' ') {
+                Some((peer_id, "confirm")) => (peer_id, true),
+                _ => (args.trim(), false),
+            };
+            let name = display_name_of(&connected_clients, peer_id).await;
+            let mut contacts = contacts.lock().await;
+            let Some(contact) = contacts.get(peer_id).cloned() else {
+                println!("No identity key known for {}. Exchange a message first.", name);
+                continue;
+            };
+
+            if confirm {
+                contacts.set_verified(peer_id, true);
+                save_secret(&keystore, CONTACTS_ENTRY, &*contacts).await;
+                println!("Marked {} as verified.", name);
+            } else {
+                println!("Safety number with {}{}:", name, if contact.verified { " (verified)" } else { "" });
+                println!("{}", safety_number(identity.public_key(), &contact.identity_key));
+                println!("Compare it with {} over a trusted channel, then type '/verify {} confirm'.", name, peer_id);
+            }
+        } else if let Some((recipient, path)) = line.strip_prefix("/send-file ").and_then(|args| args.trim().split_once(' ')) {
+            // Upload in the background, then send the key and digest like any other message
+            let (recipient, path) = (recipient.to_string(), PathBuf::from(path.trim()));
+            let (transfers, outbox, sent, history) = (transfers.clone(), outbox.clone(), sent.clone(), history.clone());
+            println!("Uploading {}...", path.display());
+            tokio::spawn(async move {
+                let attachment = match transfers.upload(&path).await {
+                    Ok(attachment) => attachment,
+                    Err(e) => {
+                        println!("Failed to upload {}: {}", path.display(), e);
+                        return;
+                    }
+                };
+                let message_id = uuid::Uuid::new_v4().to_string();
+                let text = format!("[file] {}", attachment.file_name);
+                sent.lock().await.push(&message_id, &recipient, &text, MessageStatus::AwaitingKeys);
+                record(&history, &recipient, &transfers.own_id, &text);
+                outbox.send_or_queue(&recipient, &message_id, Content::File { attachment }).await;
+            });
+        } else if line == "/files" {
+            for (number, (from, attachment)) in transfers.received.lock().await.iter().enumerate() {
+                println!("{}. {} ({} bytes) from {}", number + 1, attachment.file_name, attachment.size, from);
+            }
+        } else if let Some(number) = line.strip_prefix("/download ") {
+            let attachment = match number.trim().parse::<usize>() {
+                Ok(number) if number > 0 => transfers.received.lock().await.get(number - 1).map(|(_, attachment)| attachment.clone()),
+                _ => None,
+            };
+            let Some(attachment) = attachment else {
+                println!("No file numbered {}. Type '/files' to list them.", number.trim());
+                continue;
+            };
+            if !transfers.downloading.lock().await.insert(attachment.blob_id.clone()) {
+                println!("{} is already downloading.", attachment.file_name);
+                continue;
+            }
+
+            let (transfers, number) = (transfers.clone(), number.trim().to_string());
+            println!("Downloading {}...", attachment.file_name);
+            tokio::spawn(async move {
+                match transfers.download(&attachment).await {
+                    Ok(path) => println!("Saved {} to {}", attachment.file_name, path.display()),
+                    Err(e) => println!(
+                        "Download of {} stopped: {}. Type '/download {}' to resume.",
+                        attachment.file_name, e, number
+                    ),
+                }
+                transfers.downloading.lock().await.remove(&attachment.blob_id);
+            });
+        } else if let Some(args) = line.strip_prefix("/history ") {
+            // Pages go back from the most recent messages, '/history <id> 20 2' shows the 20 before the last 20
+            let (conversation, count, page) = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [conversation] => (*conversation, Some(DEFAULT_HISTORY_LEN), Some(1)),
+                [conversation, count] => (*conversation, count.parse().ok(), Some(1)),
+                [conversation, count, page] => (*conversation, count.parse().ok(), page.parse().ok()),
+                _ => ("", None, None),
+            };
+            let (Some(count), Some(page)) = (count, page.filter(|page: &usize| *page > 0)) else {
+                println!("Use '/history <id or #group> [count] [page]'.");
+                continue;
+            };
+            match history.recent(conversation, count, count * (page - 1)) {
+                Ok(entries) if entries.is_empty() => println!("No messages with {} in your history.", conversation),
+                Ok(entries) => {
+                    for entry in entries {
+                        println!("[{}] {}: {:?}", format_timestamp(entry.timestamp), entry.sender, entry.body);
+                    }
+                }
+                Err(e) => println!("Failed to read history: {}", e),
+            }
+        } else if let Some(query) = line.strip_prefix("/search ") {
+            match history.search(query.trim()) {
+                Ok(entries) if entries.is_empty() => println!("No messages contain {:?}.", query.trim()),
+                Ok(entries) => {
+                    for entry in entries {
+                        let time = format_timestamp(entry.timestamp);
+                        println!("[{}] {} {}: {:?}", time, entry.conversation, entry.sender, entry.body);
+                    }
+                }
+                Err(e) => println!("Failed to search history: {}", e),
+            }
+        } else if line == "/passphrase" {
+            println!("Enter your current passphrase:");
+            let Ok(Some(current)) = lines.next_line().await else {
+                break;
+            };
+            let Some(new) = choose_passphrase(&mut lines).await else {
+                break;
+            };
+            match keystore.lock().await.change_passphrase(&current, &new) {
+                Ok(()) => println!("Passphrase changed."),
+                Err(e) => println!("Failed to change passphrase: {}", e),
+            }
+        } else if line == "/groups" {
+            for group in groups.lock().await.values() {
+                println!("{} ({}), owned by {}: {:?}", group.name, group.id, group.owner, group.members());
+            }
+        } else if let Some(args) = line.strip_prefix("/group ") {
+            // Membership changes are signed here and checked by every member when the server passes them on
+            let mut groups = groups.lock().await;
+            let request_id = uuid::Uuid::new_v4().to_string();
+            let request = match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["create", name, members @ ..] => {
+                    let mut group_members = vec![own_id.clone()];
+                    for member in members {
+                        if !group_members.iter().any(|existing| existing == member) {
+                            group_members.push(member.to_string());
+                        }
+                    }
+                    let group_id = uuid::Uuid::new_v4().to_string();
+                    let roster = GroupRoster::new(&group_id, name, &own_id, group_members, &identity);
+                    Some(Some(ClientMessage::CreateGroup { roster, request_id }))
+                }
+                ["add", group, member] => find_group(&mut groups, group).map(|group| {
+                    let mut members = group.members().to_vec();
+                    if !members.iter().any(|existing| existing == member) {
+                        members.push(member.to_string());
+                    }
+                    (group.owner == own_id).then(|| ClientMessage::UpdateGroup {
+                        roster: group.roster().next(&own_id, members, &own_id, &identity),
+                        request_id: request_id.clone(),
+                    })
+                }),
+                ["remove", group, member] if *member != own_id => find_group(&mut groups, group).map(|group| {
+                    let members = group.members().iter().filter(|existing| *existing != member).cloned().collect();
+                    (group.owner == own_id).then(|| ClientMessage::UpdateGroup {
+                        roster: group.roster().next(&own_id, members, &own_id, &identity),
+                        request_id: request_id.clone(),
+                    })
+                }),
+                ["leave", group] | ["remove", group, _] => find_group(&mut groups, group).map(|group| {
+                    // An owner leaving hands the group to the next member
+                    let members: Vec<String> = group.members().iter().filter(|existing| **existing != own_id).cloned().collect();
+                    let owner = if group.owner == own_id { members.first().cloned().unwrap_or_default() } else { group.owner.clone() };
+                    Some(ClientMessage::UpdateGroup {
+                        roster: group.roster().next(&owner, members, &own_id, &identity),
+                        request_id: request_id.clone(),
+                    })
+                }),
+                _ => {
+                    println!("Use '/group create <name> [members...]', '/group add <group> <member>',");
+                    println!("'/group remove <group> <member>' or '/group leave <group>'.");
                     continue;
                 }
+            };
+            match request {
+                Some(Some(request)) => {
+                    let _ = server_tx.send(request);
+                }
+                Some(None) => println!("Only the group's owner can change its members."),
+                None => println!("You're not in a group called that. Type '/groups' to list them."),
+            }
+        } else if let Some(args) = line.strip_prefix("/mls ") {
+            // MLS membership changes are commits; they take effect once the server accepts them
+            let mut groups = mls.groups.lock().await;
+            match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["groups"] => {
+                    for group in groups.values() {
+                        println!("{} ({}), epoch {}: {:?}", group.name(), group.id(), group.epoch(), group.members());
+                    }
+                }
+                ["create", name] => {
+                    if !is_valid_username(name) {
+                        println!("Group names are 1-32 letters, digits, '-' or '_'.");
+                        continue;
+                    }
+                    let group_id = uuid::Uuid::new_v4().to_string();
+                    groups.insert(group_id.clone(), MlsGroup::create(&group_id, name, &own_id, &identity));
+                    println!("Created MLS group {}. Add members with '/mls add {} <member>'.", name, name);
+                }
+                ["add", group, member] => {
+                    let Some(group) = find_mls_group(&mut groups, group) else {
+                        println!("You're not in an MLS group called {}. Type '/mls groups' to list them.", group);
+                        continue;
+                    };
+                    // The member is added with one of the key packages they published
+                    let request_id = uuid::Uuid::new_v4().to_string();
+                    mls.key_package_requests.lock().await.insert(request_id.clone(), group.id().to_string());
+                    let _ = server_tx.send(ClientMessage::RequestKeyPackage { for_client: member.to_string(), request_id });
+                }
+                ["remove", group, member] => match find_mls_group(&mut groups, group) {
+                    Some(group) => mls.commit(group, Vec::new(), vec![member.to_string()]).await,
+                    None => println!("You're not in an MLS group called {}. Type '/mls groups' to list them.", group),
+                },
+                ["leave", group] => {
+                    let Some(group) = find_mls_group(&mut groups, group) else {
+                        println!("You're not in an MLS group called {}. Type '/mls groups' to list them.", group);
+                        continue;
+                    };
+                    // We can't commit our own removal, so another member commits our proposal
+                    if group.members().len() == 1 {
+                        let group_id = group.id().to_string();
+                        groups.remove(&group_id);
+                        continue;
+                    }
+                    match group.propose_remove(&own_id, &identity) {
+                        Ok(message) => mls.send(message),
+                        Err(e) => println!("Failed to leave MLS group {}: {}", group.name(), e),
+                    }
+                }
+                ["send", group, words @ ..] if !words.is_empty() => {
+                    let Some(group) = find_mls_group(&mut groups, group) else {
+                        println!("You're not in an MLS group called {}. Type '/mls groups' to list them.", group);
+                        continue;
+                    };
+                    let body = words.join(" ");
+                    match group.encrypt(&Content::Text { body: body.clone() }.to_bytes(), &identity) {
+                        Ok(message) => {
+                            mls.send(message);
+                            record(&history, &format!("#{}", group.name()), &own_id, &body);
+                        }
+                        Err(e) => println!("Failed to encrypt message for MLS group {}: {}", group.name(), e),
+                    }
+                }
+                _ => {
+                    println!("Use '/mls create <name>', '/mls add <group> <member>', '/mls remove <group> <member>',");
+                    println!("'/mls leave <group>', '/mls send <group> <message>' or '/mls groups'.");
+                }
+            }
+        } else if let Some((group, message)) = line.strip_prefix('#').and_then(|line| line.split_once(':')) {
+            // Group messages are encrypted once with our sender key and fanned out by the server
+            let mut groups = groups.lock().await;
+            let Some(group) = find_group(&mut groups, group) else {
+                println!("You're not in a group called {}. Type '/groups' to list them.", group);
+                continue;
+            };
+            let message_id = uuid::Uuid::new_v4().to_string();
+            let group_id = group.id.clone();
+            let associated_data = AssociatedData {
+                version: PROTOCOL_VERSION,
+                sender: &own_id,
+                recipient: &group_id,
+                message_id: &message_id,
+            };
+            match group.encrypt(&Content::Text { body: message.to_string() }.to_bytes(), &associated_data) {
+                Ok(encrypted_message) => {
+                    sent.lock().await.push(&message_id, &format!("#{}", group.name), message, MessageStatus::Sent);
+                    record(&history, &format!("#{}", group.name), &own_id, message);
+                    let _ = server_tx.send(ClientMessage::SendGroup {
+                        group_id,
+                        message: encrypted_message.to_bytes(),
+                        message_id,
+                        version: PROTOCOL_VERSION,
+                    });
+                }
+                Err(e) => println!("Failed to encrypt message for group {}: {}", group.name, e),
             }
+        } else if let Some((recipient, message)) = line.split_once(':') {
+            // Track the message before sending so no receipt can arrive ahead of it
+            let message_id = uuid::Uuid::new_v4().to_string();
+            sent.lock().await.push(&message_id, recipient, message, MessageStatus::AwaitingKeys);
+            outbox.send_or_queue(recipient, &message_id, Content::Text { body: message.to_string() }).await;
+            record(&history, recipient, &own_id, message);
+        } else {
+            println!("Invalid format. Use username:message or '/list'.");
+        }
+    }
+}
+
+/// Save a message to the history, telling the user if that fails
+fn record(history: &MessageHistory, conversation: &str, sender: &str, body: &str) {
+    let entry = HistoryEntry {
+        conversation: conversation.to_string(),
+        sender: sender.to_string(),
+        body: body.to_string(),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()),
+    };
+    if let Err(e) = history.append(&entry) {
+        println!("Failed to save message to history: {}", e);
+    }
+}
 
-            // Encrypt the message using the shared secret
-            let shared_secrets = shared_secrets.lock().await;
-            if let Some(secret) = shared_secrets.get(recipient) {
-                let key = Crypto::create_symmetric_key(secret);
-                let encrypted_message = Crypto::encrypt_with_key(&key, message.as_bytes());
-                let encoded_message = base64::encode(encrypted_message);
+/// Format seconds since the Unix epoch as a UTC date and time
+fn format_timestamp(timestamp: u64) -> String {
+    // Days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`
+    let (days, seconds) = (timestamp / 86400 + 719_468, timestamp % 86400);
+    let (era, day_of_era) = (days / 146_097, days % 146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, seconds / 3600, seconds % 3600 / 60)
+}
 
-                writer
-                    .send(json!({ "type": "Send", "to": recipient, "message": encoded_message }).to_string().into())
-                    .await
-                    .expect("Failed to send message");
+/// Wait for the next frame from the server
+async fn next_message<S>(reader: &mut S) -> Option<ServerMessage>
+where
+    S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    while let Some(Ok(msg)) = reader.next().await {
+        if msg.is_text() || msg.is_binary() {
+            return from_frame(msg).ok();
+        }
+    }
+    None
+}
+
+/// Encode a frame for the server in the encoding negotiated at connect time
+fn to_frame(message: ClientMessage, encoding: Encoding) -> Message {
+    match encoding {
+        Encoding::Json => Message::Text(message.to_json()),
+        Encoding::Cbor => Message::Binary(message.encode(encoding)),
+    }
+}
+
+/// Decode a frame from the server; text frames are JSON and binary frames CBOR
+fn from_frame(msg: Message) -> Result<ServerMessage, ProtocolError> {
+    let encoding = if msg.is_text() { Encoding::Json } else { Encoding::Cbor };
+    ServerMessage::decode(&msg.into_data(), encoding)
+}
+
+/// Look up the display name of a connected client, falling back to its ID
+async fn display_name_of(clients: &Mutex<Vec<(String, String)>>, client_id: &str) -> String {
+    clients
+        .lock()
+        .await
+        .iter()
+        .find(|(id, _)| id == client_id)
+        .map(|(_, name)| name.clone())
+        .unwrap_or_else(|| client_id.to_string())
+}
+
+/// Check a contact's identity key against the one pinned for their user ID
+///
+/// Returns `None` if the key changed: the handshake must be refused and the new key
+/// waits in `pending_approvals` until the user approves it. `name` is only shown to the user.
+async fn check_identity(
+    contacts: &Mutex<ContactStore>,
+    pending_approvals: &Mutex<HashMap<String, Vec<u8>>>,
+    name: &str,
+    peer_id: &str,
+    identity_key: &[u8],
+) -> Option<KeyChange> {
+    let change = contacts.lock().await.check_identity(peer_id, identity_key);
+    match change {
+        KeyChange::New => println!("New contact {}. Use '/verify' to check their safety number.", name),
+        KeyChange::Unchanged => {}
+        KeyChange::Changed { was_verified } => {
+            if was_verified {
+                println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+                println!("WARNING: the identity key of VERIFIED contact {} has CHANGED!", name);
+                println!("Someone may be intercepting your conversation. Verify again before trusting it.");
+                println!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
             } else {
-                println!("No shared secret available for recipient.");
+                println!("Warning: the identity key of {} has changed.", name);
             }
-        } else {
-            println!("Invalid format. Use recipient_id:message or '/list'.");
+            println!("Refusing the new key. Type '/approve {}' to accept it.", peer_id);
+            pending_approvals.lock().await.insert(peer_id.to_string(), identity_key.to_vec());
+            return None;
+        }
+    }
+    Some(change)
+}
+
+/// Pin a contact's identity key and save the contact store
+async fn pin_identity(contacts: &Mutex<ContactStore>, keystore: &Mutex<Keystore>, peer_id: &str, identity_key: &[u8]) {
+    let mut contacts = contacts.lock().await;
+    contacts.pin_identity(peer_id, identity_key);
+    save_secret(keystore, CONTACTS_ENTRY, &*contacts).await;
+}
+
+/// Ask for the keystore's passphrase until it's right, or the user runs out of attempts
+async fn unlock_keystore(path: &str, lines: &mut Lines<BufReader<Stdin>>) -> Option<Keystore> {
+    for _ in 0..PASSPHRASE_ATTEMPTS {
+        println!("Enter the passphrase for {}:", path);
+        let passphrase = lines.next_line().await.ok().flatten()?;
+        match Keystore::unlock(path, &passphrase) {
+            Ok(keystore) => return Some(keystore),
+            Err(KeystoreError::WrongPassphrase) => println!("Wrong passphrase."),
+            Err(e) => {
+                println!("Failed to open keystore {}: {}", path, e);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Ask for a new passphrase, twice so a typo can't lock the user out
+async fn choose_passphrase(lines: &mut Lines<BufReader<Stdin>>) -> Option<String> {
+    loop {
+        println!("Choose a passphrase to protect your keys:");
+        let passphrase = lines.next_line().await.ok().flatten()?;
+        if passphrase.is_empty() {
+            println!("The passphrase can't be empty.");
+            continue;
+        }
+        println!("Enter it again:");
+        if lines.next_line().await.ok().flatten()? == passphrase {
+            return Some(passphrase);
+        }
+        println!("The passphrases don't match.");
+    }
+}
+
+/// Get a secret from the keystore, moving it in from a plaintext file an older client left
+///
+/// The plaintext file is deleted once the secret is sealed. If neither exists the
+/// secret is made with `create`.
+fn load_secret<T: Serialize + DeserializeOwned>(
+    keystore: &mut Keystore,
+    name: &str,
+    legacy_path: &str,
+    load_legacy: impl FnOnce(&str) -> io::Result<T>,
+    create: impl FnOnce() -> T,
+) -> Result<T, KeystoreError> {
+    if let Some(secret) = keystore.get(name)? {
+        return Ok(secret);
+    }
+    let legacy = Path::new(legacy_path).exists();
+    let secret = if legacy { load_legacy(legacy_path)? } else { create() };
+    keystore.put(name, &secret)?;
+    if legacy {
+        std::fs::remove_file(legacy_path)?;
+        println!("Moved {} into the keystore", legacy_path);
+    }
+    Ok(secret)
+}
+
+/// Open the message history with the random key kept in the keystore
+///
+/// Older clients keyed the history from the identity key; such a history is
+/// re-encrypted under a new key, and only replaced once that key is sealed.
+fn open_history(keystore: &mut Keystore, path: &str, identity: &IdentityKeyPair) -> Result<MessageHistory, Box<dyn Error>> {
+    let key = match keystore.get::<[u8; 32]>(HISTORY_KEY_ENTRY)? {
+        Some(key) => key,
+        None => {
+            let mut key = [0u8; 32];
+            SystemRandom::new().fill(&mut key).map_err(|_| CryptoError::RandomFailed)?;
+            if Path::new(path).exists() {
+                let legacy_key: [u8; 32] = identity.secret_bytes().try_into().map_err(|_| CryptoError::CorruptKey)?;
+                MessageHistory::rekey(path, &legacy_key, &key)?;
+                println!("Moved {} to its own key", path);
+            }
+            keystore.put(HISTORY_KEY_ENTRY, &key)?;
+            key
+        }
+    };
+    MessageHistory::finish_rekey(path)?;
+    Ok(MessageHistory::open(path, &key)?)
+}
+
+/// Our signed prekey and the one-time prekeys the server doesn't have yet, which are marked as uploaded
+fn prekey_upload(prekeys: &mut PreKeyStore) -> Result<ClientMessage, CryptoError> {
+    Ok(ClientMessage::UploadPreKeys {
+        signed_prekey_id: prekeys.signed_prekey_id(),
+        signed_prekey: prekeys.signed_prekey()?,
+        signed_prekey_signature: prekeys.signed_prekey_signature().to_vec(),
+        one_time_prekeys: prekeys.publish_one_time_prekeys(),
+    })
+}
+
+/// Seal a changed secret into the keystore, telling the user if that fails
+async fn save_secret<T: Serialize>(keystore: &Mutex<Keystore>, name: &str, value: &T) {
+    if let Err(e) = keystore.lock().await.put(name, value) {
+        println!("Failed to save {} to the keystore: {}", name, e);
+    }
+}
+
+/// Group rosters set aside until the key of the member who signed them is pinned
+///
+/// A group's rosters have to apply in order, so once one is waiting the rest for
+/// that group queue behind it. Released rosters go back through the inbound
+/// channel to be checked again.
+#[derive(Clone)]
+struct PendingRosters {
+    /// Waiting rosters in the order they arrived, keyed by group ID
+    pending: Arc<Mutex<HashMap<String, Vec<GroupRoster>>>>,
+    inbound_tx: mpsc::UnboundedSender<ServerMessage>,
+}
+
+impl PendingRosters {
+    /// Queue a roster behind others waiting for its group, returning whether there were any
+    async fn hold_if_waiting(&self, roster: &GroupRoster) -> bool {
+        match self.pending.lock().await.get_mut(&roster.group_id) {
+            Some(waiting) => {
+                waiting.push(roster.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set a roster aside until its signer's key is pinned
+    async fn hold(&self, roster: GroupRoster) {
+        self.pending.lock().await.entry(roster.group_id.clone()).or_default().push(roster);
+    }
+
+    /// Check again the rosters of every group that was waiting on `signer`
+    async fn release(&self, signer: &str) {
+        let mut pending = self.pending.lock().await;
+        let group_ids: Vec<String> = pending
+            .iter()
+            .filter(|(_, waiting)| waiting.first().is_some_and(|roster| roster.signer == signer))
+            .map(|(group_id, _)| group_id.clone())
+            .collect();
+        for roster in group_ids.iter().flat_map(|group_id| pending.remove(group_id).unwrap_or_default()) {
+            let _ = self.inbound_tx.send(ServerMessage::GroupUpdate { roster });
         }
     }
 }
+
+/// Sender and message ID of the pairwise envelopes decrypted most recently
+#[derive(Default)]
+struct RecentMessages {
+    order: VecDeque<(String, String)>,
+    seen: HashSet<(String, String)>,
+}
+
+impl RecentMessages {
+    /// Whether an envelope from `from` with `message_id` was already handled
+    fn contains(&self, from: &str, message_id: &str) -> bool {
+        self.seen.contains(&(from.to_string(), message_id.to_string()))
+    }
+
+    /// Remember an envelope once it has decrypted, so a copy arriving by another route is dropped
+    fn insert(&mut self, from: &str, message_id: &str) {
+        let key = (from.to_string(), message_id.to_string());
+        if !self.seen.insert(key.clone()) {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > RECENT_MESSAGES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Content for peers we don't have a session with yet, as (message ID, content) by peer
+type AwaitingKeys = HashMap<String, Vec<(String, Content)>>;
+
+/// Sends encrypted content to peers, over a direct link when possible and through the relay otherwise
+#[derive(Clone)]
+struct Outbox {
+    own_id: String,
+    sessions: Arc<Mutex<SessionManager>>,
+    direct_links: DirectLinks,
+    peer_addresses: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    server_tx: mpsc::UnboundedSender<ClientMessage>,
+    /// Key requests waiting for the server's answer, mapping request ID to the peer
+    pending_requests: Arc<Mutex<HashMap<String, String>>>,
+    awaiting_keys: Arc<Mutex<AwaitingKeys>>,
+    sent: Arc<Mutex<SentMessages>>,
+    keystore: Arc<Mutex<Keystore>>,
+}
+
+impl Outbox {
+    /// Send content to a peer, first fetching their prekey bundle if we have no session
+    ///
+    /// Content waits until the keys arrive, and fails if they don't arrive within
+    /// `KEY_REQUEST_TIMEOUT`.
+    async fn send_or_queue(&self, recipient: &str, message_id: &str, content: Content) {
+        // Queue while holding the sessions lock, so a session set up meanwhile is flushed after the queue has the content
+        let sessions = self.sessions.lock().await;
+        if sessions.has_session(recipient) {
+            drop(sessions);
+            self.send_tracked(recipient, message_id, &content).await;
+            return;
+        }
+
+        // Only the first message for a peer requests its keys
+        let mut awaiting_keys = self.awaiting_keys.lock().await;
+        let requested = awaiting_keys.contains_key(recipient);
+        awaiting_keys.entry(recipient.to_string()).or_default().push((message_id.to_string(), content));
+        drop(awaiting_keys);
+        drop(sessions);
+        if !requested {
+            println!("Requesting keys for {}, messages will be sent once they arrive", recipient);
+            self.request_keys(recipient).await;
+        }
+    }
+
+    /// Start a session with a peer, pinning their identity key, unless there is one or it's on its way
+    async fn request_session(&self, peer_id: &str) {
+        let sessions = self.sessions.lock().await;
+        if sessions.has_session(peer_id) {
+            return;
+        }
+        let mut awaiting_keys = self.awaiting_keys.lock().await;
+        if awaiting_keys.contains_key(peer_id) {
+            return;
+        }
+        awaiting_keys.insert(peer_id.to_string(), Vec::new());
+        drop(awaiting_keys);
+        drop(sessions);
+        self.request_keys(peer_id).await;
+    }
+
+    /// Ask the server for a peer's prekey bundle, failing what waits for it if there's no answer in time
+    async fn request_keys(&self, recipient: &str) {
+        let request_id = uuid::Uuid::new_v4().to_string();
+        self.pending_requests.lock().await.insert(request_id.clone(), recipient.to_string());
+        let _ = self.server_tx.send(ClientMessage::RequestPreKeyBundle { for_client: recipient.to_string(), request_id: request_id.clone() });
+
+        let outbox = self.clone();
+        let peer_id = recipient.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(KEY_REQUEST_TIMEOUT).await;
+            if outbox.pending_requests.lock().await.remove(&request_id).is_some() {
+                outbox.fail(&peer_id, "timed out waiting for their keys").await;
+            }
+        });
+    }
+
+    /// Send everything that was waiting for a peer's keys
+    async fn flush(&self, peer_id: &str) {
+        let waiting = self.awaiting_keys.lock().await.remove(peer_id).unwrap_or_default();
+        for (message_id, content) in waiting {
+            self.send_tracked(peer_id, &message_id, &content).await;
+        }
+    }
+
+    /// Give up on everything that was waiting for a peer's keys
+    async fn fail(&self, peer_id: &str, reason: &str) {
+        let Some(waiting) = self.awaiting_keys.lock().await.remove(peer_id) else {
+            return;
+        };
+        println!("Couldn't start a session with {}: {}", peer_id, reason);
+        let mut sent = self.sent.lock().await;
+        for (message_id, _) in waiting {
+            if let Some(message) = sent.get_mut(&message_id) {
+                message.advance(MessageStatus::Failed);
+            }
+        }
+    }
+
+    /// Send content, updating the status of the message if it's one the user typed
+    async fn send_tracked(&self, recipient: &str, message_id: &str, content: &Content) {
+        if let Some(message) = self.sent.lock().await.get_mut(message_id) {
+            message.advance(MessageStatus::Sent);
+        }
+        if let Err(e) = self.send(recipient, message_id, content).await {
+            println!("Failed to encrypt message for {}: {}", recipient, e);
+            if let Some(message) = self.sent.lock().await.get_mut(message_id) {
+                message.advance(MessageStatus::Failed);
+            }
+        }
+    }
+
+    /// Encrypt content for a peer we have a session with and send it as `message_id`
+    ///
+    /// Messages are encrypted in the order they're sent, but delivered in the
+    /// background, so a peer that can't be reached directly holds nothing up.
+    async fn send(&self, recipient: &str, message_id: &str, content: &Content) -> Result<(), CryptoError> {
+        // Encrypt the message with the next ratchet message key, bound to its envelope
+        let associated_data = AssociatedData {
+            version: PROTOCOL_VERSION,
+            sender: &self.own_id,
+            recipient,
+            message_id,
+        };
+        let mut sessions = self.sessions.lock().await;
+        let (encrypted_message, initial) = sessions.encrypt(recipient, &content.to_bytes(), &associated_data)?;
+        save_secret(&self.keystore, SESSIONS_ENTRY, &*sessions).await;
+        drop(sessions);
+        let envelope = Envelope {
+            from: self.own_id.clone(),
+            to: recipient.to_string(),
+            message: encrypted_message,
+            message_id: message_id.to_string(),
+            version: PROTOCOL_VERSION,
+            initial,
+            group: None,
+            mls: None,
+        };
+        let outbox = self.clone();
+        tokio::spawn(async move { outbox.deliver(envelope).await });
+        Ok(())
+    }
+
+    /// Deliver an envelope over a direct link, falling back to the relay
+    async fn deliver(&self, envelope: Envelope) {
+        // Try an open or new direct link first, asking the relay for the peer's addresses if we don't have them
+        let recipient = envelope.to.as_str();
+        let addresses = self.peer_addresses.lock().await.get(recipient).cloned();
+        match self.direct_links.send(recipient, addresses.as_deref().unwrap_or_default(), &envelope).await {
+            Ok(()) => {
+                println!("Sent directly to {}", recipient);
+                return;
+            }
+            Err(e) if addresses.as_ref().is_some_and(|addresses| !addresses.is_empty()) => {
+                println!("Direct connection to {} failed, using the relay: {}", recipient, e);
+                self.peer_addresses.lock().await.remove(recipient);
+            }
+            Err(_) => {}
+        }
+        if addresses.is_none() {
+            self.peer_addresses.lock().await.insert(recipient.to_string(), Vec::new());
+            let _ = self.server_tx.send(ClientMessage::RequestAddresses { for_client: recipient.to_string() });
+        }
+
+        let _ = self.server_tx.send(ClientMessage::Send {
+            to: envelope.to,
+            message: envelope.message,
+            message_id: envelope.message_id,
+            version: envelope.version,
+            initial: envelope.initial,
+        });
+    }
+}
+
+/// Find one of our groups by name or ID
+fn find_group<'a>(groups: &'a mut HashMap<String, Group>, name_or_id: &str) -> Option<&'a mut Group> {
+    groups.values_mut().find(|group| group.id == name_or_id || group.name == name_or_id)
+}
+
+/// Our experimental MLS groups and the requests they're waiting on
+#[derive(Clone)]
+struct MlsState {
+    own_id: String,
+    identity: Arc<IdentityKeyPair>,
+    server_tx: mpsc::UnboundedSender<ClientMessage>,
+    groups: Arc<Mutex<HashMap<String, MlsGroup>>>,
+    /// Private halves of the key packages we've published
+    key_packages: Arc<Mutex<Vec<KeyPackageBundle>>>,
+    /// Key package requests waiting for the server, mapping request ID to group ID
+    key_package_requests: Arc<Mutex<HashMap<String, String>>>,
+    /// Our commits waiting for the server to accept them, mapping message ID to group ID
+    pending_commits: Arc<Mutex<HashMap<String, String>>>,
+    history: Arc<MessageHistory>,
+}
+
+impl MlsState {
+    /// Top up our key packages and publish them, replacing those on the server
+    async fn publish_key_packages(&self) {
+        let mut key_packages = self.key_packages.lock().await;
+        while key_packages.len() < KEY_PACKAGE_COUNT {
+            key_packages.push(KeyPackageBundle::generate(&self.own_id, &self.identity));
+        }
+        let key_packages = key_packages.iter().map(|bundle| bundle.key_package.clone()).collect();
+        let _ = self.server_tx.send(ClientMessage::PublishKeyPackages { key_packages });
+    }
+
+    /// Send a proposal or application message to the rest of a group
+    fn send(&self, message: MlsMessage) {
+        let message_id = uuid::Uuid::new_v4().to_string();
+        let _ = self.server_tx.send(ClientMessage::SendMls { message, message_id, welcome: None });
+    }
+
+    /// Commit a membership change, along with any proposals the group has received
+    async fn commit(&self, group: &mut MlsGroup, add: Vec<KeyPackage>, remove: Vec<String>) {
+        match group.commit(add, remove, &self.identity) {
+            Ok((message, welcome)) => {
+                // Tracked before sending so the server's answer can't arrive first
+                let message_id = uuid::Uuid::new_v4().to_string();
+                self.pending_commits.lock().await.insert(message_id.clone(), group.id().to_string());
+                let _ = self.server_tx.send(ClientMessage::SendMls { message, message_id, welcome });
+            }
+            Err(e) => println!("Failed to change MLS group {}: {}", group.name(), e),
+        }
+    }
+
+    /// Handle an MLS message or Welcome relayed from `from`
+    async fn receive(&self, from: &str, bytes: &[u8], payload: MlsPayload) {
+        if payload == MlsPayload::Welcome {
+            let welcome = match Welcome::from_bytes(bytes) {
+                Ok(welcome) => welcome,
+                Err(e) => {
+                    println!("Invalid welcome from {}: {}", from, e);
+                    return;
+                }
+            };
+            let mut key_packages = self.key_packages.lock().await;
+            let Some(position) = key_packages.iter().position(|bundle| welcome.is_for(&bundle.key_package)) else {
+                println!("Welcome from {} isn't for any of our key packages", from);
+                return;
+            };
+            let bundle = key_packages.remove(position);
+            drop(key_packages);
+            match MlsGroup::join(&welcome, &bundle) {
+                Ok(group) => {
+                    println!("{} added you to MLS group {}: {:?}", from, group.name(), group.members());
+                    self.groups.lock().await.insert(group.id().to_string(), group);
+                }
+                Err(e) => println!("Failed to join MLS group from {}'s welcome: {}", from, e),
+            }
+
+            // The server handed that key package out, so publish a replacement
+            self.publish_key_packages().await;
+            return;
+        }
+
+        // The relay authenticated the sender, and the message must agree
+        let message = match MlsMessage::from_bytes(bytes) {
+            Ok(message) if message.sender == from => message,
+            Ok(_) => {
+                println!("Rejected MLS message from {} claiming another sender", from);
+                return;
+            }
+            Err(e) => {
+                println!("Invalid MLS message from {}: {}", from, e);
+                return;
+            }
+        };
+        let mut groups = self.groups.lock().await;
+        let Some(group) = groups.get_mut(&message.group_id) else {
+            println!("MLS message from {} for a group we're not in", from);
+            return;
+        };
+        match group.process(&message) {
+            Ok(Processed::Application { sender, plaintext }) => match Content::from_bytes(&plaintext) {
+                Ok(Content::Text { body }) => {
+                    println!("[{}] {}: {:?}", group.name(), sender, body);
+                    record(&self.history, &format!("#{}", group.name()), &sender, &body);
+                }
+                _ => println!("Unreadable MLS message from {}", sender),
+            },
+            Ok(Processed::Proposal { sender, proposal: Proposal::Remove { member } }) => {
+                println!("{} proposed removing {} from MLS group {}", sender, member, group.name());
+
+                // The first member staying in the group commits it, so nobody waits on the leaver
+                let committer = group.members().into_iter().find(|existing| *existing != member);
+                if committer.as_deref() == Some(self.own_id.as_str()) {
+                    self.commit(group, Vec::new(), Vec::new()).await;
+                }
+            }
+            Ok(Processed::Proposal { sender, proposal: Proposal::Add { key_package } }) => {
+                println!("{} proposed adding {} to MLS group {}", sender, key_package.member(), group.name());
+            }
+            Ok(Processed::Commit { sender, added, removed }) => {
+                println!("{} changed MLS group {}, now at epoch {}", sender, group.name(), group.epoch());
+                println!("Added {:?}, removed {:?}, members {:?}", added, removed, group.members());
+            }
+            Ok(Processed::Removed { sender }) => {
+                println!("{} removed you from MLS group {}", sender, group.name());
+                groups.remove(&message.group_id);
+            }
+            Err(e) => println!("Failed to process MLS message from {}: {}", from, e),
+        }
+    }
+}
+
+/// Why a file upload or download stopped
+#[derive(Debug)]
+enum TransferError {
+    Io(io::Error),
+    Http(hyper::Error),
+    Crypto(CryptoError),
+    /// The relay refused the request
+    Rejected(StatusCode, String),
+    /// The downloaded file doesn't match the digest the sender gave
+    DigestMismatch,
+    /// The file has no name it can be sent or saved under
+    InvalidFileName,
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferError::Io(e) => write!(f, "{}", e),
+            TransferError::Http(e) => write!(f, "couldn't reach the relay: {}", e),
+            TransferError::Crypto(e) => write!(f, "{}", e),
+            TransferError::Rejected(status, reason) => write!(f, "relay refused the request: {} ({})", reason, status),
+            TransferError::DigestMismatch => f.write_str("file doesn't match the sender's digest"),
+            TransferError::InvalidFileName => f.write_str("invalid file name"),
+        }
+    }
+}
+
+impl From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::Io(e)
+    }
+}
+
+impl From<hyper::Error> for TransferError {
+    fn from(e: hyper::Error) -> Self {
+        TransferError::Http(e)
+    }
+}
+
+impl From<CryptoError> for TransferError {
+    fn from(e: CryptoError) -> Self {
+        TransferError::Crypto(e)
+    }
+}
+
+/// Moves encrypted files through the relay's blob store, a chunk per signed HTTP request
+#[derive(Clone)]
+struct Transfers {
+    own_id: String,
+    identity: Arc<IdentityKeyPair>,
+    http: Client<HttpConnector>,
+    download_dir: PathBuf,
+    /// Files peers have sent us, numbered from 1 in the order they arrived
+    received: Arc<Mutex<Vec<(String, Attachment)>>>,
+    /// Blob IDs of the downloads in progress
+    downloading: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Transfers {
+    /// Encrypt a file under a fresh key and upload it, returning what the recipient needs to fetch it
+    async fn upload(&self, path: &Path) -> Result<Attachment, TransferError> {
+        let file_name = path.file_name().and_then(|name| name.to_str()).ok_or(TransferError::InvalidFileName)?;
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut attachment = Attachment::new(file_name, file.metadata().await?.len())?;
+
+        let mut digest = Context::new(&SHA256);
+        for index in 0..attachment.chunk_count {
+            let mut chunk = vec![0; attachment.chunk_len(index)];
+            file.read_exact(&mut chunk).await?;
+            digest.update(&chunk);
+            let ciphertext = attachment.encrypt_chunk(index, &chunk)?;
+            self.request(Method::PUT, &format!("/blobs/{}/{}", attachment.blob_id, index), ciphertext).await?;
+        }
+        attachment.digest = digest.finish().as_ref().to_vec();
+        Ok(attachment)
+    }
+
+    /// Download, decrypt and check a file, carrying on from any chunks an earlier attempt saved
+    async fn download(&self, attachment: &Attachment) -> Result<PathBuf, TransferError> {
+        let file_name = attachment.safe_file_name().ok_or(TransferError::InvalidFileName)?;
+        tokio::fs::create_dir_all(&self.download_dir).await?;
+
+        // Only whole chunks are kept, so a chunk cut off part way is fetched again
+        let part_path = self.download_dir.join(format!("{}.part", attachment.blob_id));
+        let mut part = tokio::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&part_path).await?;
+        let saved_chunks = (part.metadata().await?.len() / CHUNK_SIZE as u64).min(attachment.chunk_count as u64);
+        let resume_at = saved_chunks * CHUNK_SIZE as u64;
+        part.set_len(resume_at).await?;
+        part.seek(SeekFrom::Start(resume_at)).await?;
+
+        for index in saved_chunks as u32..attachment.chunk_count {
+            let ciphertext = self.request(Method::GET, &format!("/blobs/{}/{}", attachment.blob_id, index), Vec::new()).await?;
+            part.write_all(&attachment.decrypt_chunk(index, &ciphertext)?).await?;
+        }
+        part.flush().await?;
+        drop(part);
+
+        // Each chunk is authenticated on its own, the digest checks the file as a whole
+        if file_digest(&part_path).await? != attachment.digest {
+            tokio::fs::remove_file(&part_path).await?;
+            return Err(TransferError::DigestMismatch);
+        }
+        let mut path = self.download_dir.join(file_name);
+        if tokio::fs::try_exists(&path).await? {
+            path = self.download_dir.join(format!("{}-{}", attachment.blob_id, file_name));
+        }
+        tokio::fs::rename(&part_path, &path).await?;
+        Ok(path)
+    }
+
+    /// Send a signed request to the relay, retrying if it can't be reached
+    async fn request(&self, method: Method, path: &str, body: Vec<u8>) -> Result<Vec<u8>, TransferError> {
+        let mut attempt = 1;
+        loop {
+            let mut request = Request::builder().method(method.clone()).uri(format!("{}{}", RELAY_HTTP_URL, path));
+            for (name, value) in sign_request(&self.identity, &self.own_id, method.as_str(), path, &body) {
+                request = request.header(name, value);
+            }
+            let request = request.body(Body::from(body.clone())).expect("Invalid blob store request");
+
+            let response = match self.http.request(request).await {
+                Ok(response) => response,
+                Err(e) if attempt < TRANSFER_ATTEMPTS => {
+                    println!("Couldn't reach the relay, retrying: {}", e);
+                    attempt += 1;
+                    tokio::time::sleep(TRANSFER_RETRY_DELAY).await;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            if !status.is_success() {
+                let reason = serde_json::from_slice::<serde_json::Value>(&body)
+                    .ok()
+                    .and_then(|body| body["error"].as_str().map(str::to_string))
+                    .unwrap_or_default();
+                return Err(TransferError::Rejected(status, reason));
+            }
+            return Ok(body.to_vec());
+        }
+    }
+}
+
+/// SHA-256 of a file, read a chunk at a time
+async fn file_digest(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut digest = Context::new(&SHA256);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(digest.finish().as_ref().to_vec());
+        }
+        digest.update(&buffer[..read]);
+    }
+}
+
+/// Find one of our MLS groups by name or ID
+fn find_mls_group<'a>(groups: &'a mut HashMap<String, MlsGroup>, name_or_id: &str) -> Option<&'a mut MlsGroup> {
+    groups.values_mut().find(|group| group.id() == name_or_id || group.name() == name_or_id)
+}
+
+/// How far a sent message has got, in the order it gets there
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MessageStatus {
+    AwaitingKeys,
+    Sent,
+    Accepted,
+    Queued,
+    Relayed,
+    Delivered,
+    Read,
+    Failed,
+}
+
+impl fmt::Display for MessageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MessageStatus::AwaitingKeys => "waiting for keys",
+            MessageStatus::Sent => "sent",
+            MessageStatus::Accepted => "accepted by the server",
+            MessageStatus::Queued => "queued on the server",
+            MessageStatus::Relayed => "relayed",
+            MessageStatus::Delivered => "delivered",
+            MessageStatus::Read => "read",
+            MessageStatus::Failed => "failed",
+        })
+    }
+}
+
+/// A message we sent and how far it has got
+struct SentMessage {
+    message_id: String,
+    recipient: String,
+    text: String,
+    status: MessageStatus,
+}
+
+impl SentMessage {
+    /// Move the message on to `status` and show it; statuses never go backwards
+    ///
+    /// A message can only fail before the server or the peer has accepted it.
+    fn advance(&mut self, status: MessageStatus) {
+        let allowed = match status {
+            MessageStatus::Failed => matches!(self.status, MessageStatus::AwaitingKeys | MessageStatus::Sent),
+            _ => status > self.status,
+        };
+        if allowed {
+            self.status = status;
+            println!("{}", self);
+        }
+    }
+}
+
+impl fmt::Display for SentMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "To {} {:?}: {}", self.recipient, self.text, self.status)
+    }
+}
+
+/// The most recently sent messages, oldest first
+#[derive(Default)]
+struct SentMessages {
+    messages: VecDeque<SentMessage>,
+}
+
+impl SentMessages {
+    fn push(&mut self, message_id: &str, recipient: &str, text: &str, status: MessageStatus) {
+        if self.messages.len() == SENT_HISTORY_LEN {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(SentMessage {
+            message_id: message_id.to_string(),
+            recipient: recipient.to_string(),
+            text: text.to_string(),
+            status,
+        });
+    }
+
+    fn get_mut(&mut self, message_id: &str) -> Option<&mut SentMessage> {
+        self.messages.iter_mut().find(|message| message.message_id == message_id)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &SentMessage> {
+        self.messages.iter()
+    }
+}
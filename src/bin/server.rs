@@ -2,68 +2,156 @@ use warp::Filter;
 use warp::ws::WebSocket;
 use futures::{StreamExt, SinkExt};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tokio::sync::mpsc;
 use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use std::net::SocketAddr;
+use std::time::Duration;
+use p2p_sparse_messaging::accounts::{is_valid_username, LoginChallenge, LoginError, UserRegistry};
+use p2p_sparse_messaging::blobs::{self, BlobStore};
+use p2p_sparse_messaging::identity::IdentityKeyPair;
+use p2p_sparse_messaging::fallback::{self, SeenNonces};
+use p2p_sparse_messaging::groups::GroupRoster;
+use p2p_sparse_messaging::crypto::PROTOCOL_VERSION;
+use p2p_sparse_messaging::mailbox::Mailbox;
+use p2p_sparse_messaging::p2p::MAX_PEER_ADDRESSES;
+use p2p_sparse_messaging::mls::{DeliveryService, KeyPackage, MlsContent, Proposal};
+use p2p_sparse_messaging::prekeys::{OneTimePreKey, PreKeyBundle};
+use p2p_sparse_messaging::protocol::{ClientMessage, Encoding, Envelope, ErrorCode, MlsPayload, RelayStatus, ServerMessage};
+use serde::Deserialize;
+
+/// File the account registry is persisted to
+const USERS_PATH: &str = "users.json";
+
+/// Most envelopes that can wait for a single offline user
+const OFFLINE_QUEUE_QUOTA: usize = 1000;
+
+/// How long an envelope waits for an offline user before it is dropped
+const OFFLINE_QUEUE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Most bytes of encrypted files a single user can have stored
+const BLOB_QUOTA: usize = 256 * 1024 * 1024;
+
+/// How long an uploaded file is kept for its recipients to download
+const BLOB_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Number of one-time prekeys left at which their owner is told to upload more
+const PREKEY_LOW_WATER: usize = 5;
 
 #[derive(Clone)]
 struct ServerState {
-    clients: Arc<tokio::sync::Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>,
-    names: Arc<tokio::sync::Mutex<HashMap<String, String>>>, // Maps client IDs to display names
-    public_keys: Arc<tokio::sync::Mutex<HashMap<String, Vec<u8>>>>, // Maps client IDs to their public keys
+    accounts: Arc<tokio::sync::Mutex<UserRegistry>>, // Usernames bound to identity keys
+    clients: Arc<tokio::sync::Mutex<HashMap<String, mpsc::UnboundedSender<ServerMessage>>>>, // Maps online user IDs to their connections
+    names: Arc<tokio::sync::Mutex<HashMap<String, String>>>, // Maps online user IDs to display names
+    prekeys: Arc<tokio::sync::Mutex<HashMap<String, StoredPreKeys>>>, // Maps user IDs to their published prekeys, kept while offline
+    mailbox: Arc<tokio::sync::Mutex<Mailbox>>, // Envelopes waiting for offline users
+    blobs: Arc<tokio::sync::Mutex<BlobStore>>, // Encrypted file chunks waiting to be downloaded
+    addresses: Arc<tokio::sync::Mutex<HashMap<String, Vec<String>>>>, // Maps online user IDs to their P2P listen addresses
+    groups: Arc<tokio::sync::Mutex<HashMap<String, Vec<GroupRoster>>>>, // Maps group IDs to their last owner-signed roster and the departures since
+    key_packages: Arc<tokio::sync::Mutex<HashMap<String, Vec<KeyPackage>>>>, // Maps user IDs to their unused MLS key packages
+    mls: Arc<tokio::sync::Mutex<DeliveryService>>, // Epochs and members of MLS groups
 }
 
+/// Query parameters of the WebSocket upgrade request
 #[derive(Deserialize)]
-#[serde(tag = "type")]
-enum ClientMessage {
-    Register { name: String, public_key: Vec<u8> },
-    Send { to: String, message: String },
-    RequestPublicKey { for_client: String },
+struct ConnectParams {
+    encoding: Option<String>,
 }
 
-#[derive(Serialize)]
-struct ServerMessage {
-    from: String,
-    message: String,
+/// Signed prekey and remaining one-time prekeys published by a client
+struct StoredPreKeys {
+    signed_prekey_id: u32,
+    signed_prekey: Vec<u8>,
+    signed_prekey_signature: Vec<u8>,
+    one_time_prekeys: Vec<OneTimePreKey>,
 }
 
 #[tokio::main]
 async fn main() {
+    let accounts = UserRegistry::load_or_default(USERS_PATH).expect("Failed to load user registry");
     let state = ServerState {
+        accounts: Arc::new(tokio::sync::Mutex::new(accounts)),
         clients: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
         names: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
-        public_keys: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        prekeys: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        mailbox: Arc::new(tokio::sync::Mutex::new(Mailbox::new(OFFLINE_QUEUE_QUOTA, OFFLINE_QUEUE_TTL))),
+        blobs: Arc::new(tokio::sync::Mutex::new(BlobStore::new(BLOB_QUOTA, BLOB_TTL))),
+        addresses: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        groups: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        key_packages: Arc::new(tokio::sync::Mutex::new(HashMap::new())),
+        mls: Arc::new(tokio::sync::Mutex::new(DeliveryService::new())),
     };
 
+    // Periodically drop queued envelopes and files nobody came back for
+    let mailbox = state.mailbox.clone();
+    let blob_store = state.blobs.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            mailbox.lock().await.expire_all();
+            blob_store.lock().await.expire_all();
+        }
+    });
+
+    // HTTP mailbox for clients that can't keep a WebSocket open, sharing the record of used request nonces
+    let nonces = Arc::new(tokio::sync::Mutex::new(SeenNonces::new()));
+    let mailbox_routes = fallback::routes(fallback::ServerState {
+        accounts: state.accounts.clone(),
+        clients: state.clients.clone(),
+        mailbox: state.mailbox.clone(),
+        nonces: nonces.clone(),
+    });
+
+    // HTTP store for encrypted file chunks
+    let blob_routes = blobs::routes(blobs::ServerState {
+        accounts: state.accounts.clone(),
+        blobs: state.blobs.clone(),
+        nonces,
+    });
+
     let state_filter = warp::any().map(move || state.clone());
 
     let ws_route = warp::path("ws")
         .and(warp::ws())
+        .and(warp::query::<ConnectParams>())
         .and(state_filter)
-        .map(|ws: warp::ws::Ws, state: ServerState| {
-            ws.on_upgrade(move |socket| handle_connection(socket, state))
+        .map(|ws: warp::ws::Ws, params: ConnectParams, state: ServerState| {
+            // Clients pick the frame encoding when they connect, JSON unless they ask otherwise
+            let encoding = match params.encoding.as_deref().map(Encoding::from_name) {
+                None => Encoding::Json,
+                Some(Some(encoding)) => encoding,
+                Some(None) => {
+                    let reply = warp::reply::with_status("unsupported encoding", warp::http::StatusCode::BAD_REQUEST);
+                    return warp::reply::Reply::into_response(reply);
+                }
+            };
+            warp::reply::Reply::into_response(ws.on_upgrade(move |socket| handle_connection(socket, state, encoding)))
         });
 
-    warp::serve(ws_route).run(([127, 0, 0, 1], 3030)).await;
+    warp::serve(ws_route.or(mailbox_routes).or(blob_routes)).run(([127, 0, 0, 1], 3030)).await;
 }
 
-async fn handle_connection(ws: WebSocket, state: ServerState) {
+async fn handle_connection(ws: WebSocket, state: ServerState, encoding: Encoding) {
     let (tx, mut rx) = ws.split();
     let tx = Arc::new(tokio::sync::Mutex::new(tx)); // Wrap tx in Arc<Mutex>
-    let (client_tx, mut client_rx) = mpsc::unbounded_channel();
+    let (client_tx, mut client_rx) = mpsc::unbounded_channel::<ServerMessage>();
 
-    // Assign a unique ID to the client
-    let client_id = uuid::Uuid::new_v4().to_string();
-    println!("Client connected: {}", client_id);
+    // Connections get a throwaway ID until they log in as a user
+    let connection_id = uuid::Uuid::new_v4().to_string();
+    let mut user_id: Option<String> = None;
+    let mut login_challenge = LoginChallenge::default();
+    println!("Client connected: {} ({})", connection_id, encoding.name());
 
     // Spawn a task to forward messages from client_rx to the WebSocket
     let tx_clone = tx.clone();
     tokio::spawn(async move {
         while let Some(message) = client_rx.recv().await {
             let mut tx = tx_clone.lock().await; // Lock tx for sending
-            if tx.send(warp::ws::Message::text(message)).await.is_err() {
+            let frame = match encoding {
+                Encoding::Json => warp::ws::Message::text(message.to_json()),
+                Encoding::Cbor => warp::ws::Message::binary(message.encode(encoding)),
+            };
+            if tx.send(frame).await.is_err() {
                 println!("Failed to send message to client");
                 break; // Exit loop if sending fails
             } else {
@@ -73,84 +161,578 @@ async fn handle_connection(ws: WebSocket, state: ServerState) {
     });
 
     while let Some(Ok(msg)) = rx.next().await {
-        if msg.is_text() {
-            let text = msg.to_str().unwrap();
+        if msg.is_text() || msg.is_binary() {
+            // Parse the message from the client; text frames are JSON and binary frames CBOR
+            let frame_encoding = if msg.is_text() { Encoding::Json } else { Encoding::Cbor };
+            let parsed = ClientMessage::decode(msg.as_bytes(), frame_encoding);
+
+            // Everything but the login handshake needs a logged in user
+            let logging_in = matches!(parsed, Ok(ClientMessage::Login { .. }) | Ok(ClientMessage::Register { .. }));
+            if let (None, Ok(message)) = (&user_id, &parsed) {
+                if !logging_in {
+                    println!("Message before login from connection {}", connection_id);
+                    let _ = client_tx.send(ServerMessage::Error {
+                        code: ErrorCode::NotLoggedIn,
+                        request_id: message.request_id().map(str::to_string),
+                        reason: "not logged in".to_string(),
+                    });
+                    continue;
+                }
+            }
+            let client_id = user_id.clone().unwrap_or_else(|| connection_id.clone());
+
+            match parsed {
+                Ok(ClientMessage::Login { username }) => {
+                    if user_id.is_some() {
+                        println!("Repeated login from user {}", client_id);
+                        continue;
+                    }
+
+                    // The client proves it holds the username's identity key by signing this
+                    let response = match login_challenge.issue(&username) {
+                        Ok(challenge) => ServerMessage::Challenge { username, challenge: challenge.to_vec() },
+                        Err(e) => {
+                            println!("Failed to make a login challenge for connection {}: {}", connection_id, e);
+                            ServerMessage::LoginFailed { username, reason: e.to_string() }
+                        }
+                    };
+                    let _ = client_tx.send(response);
+                }
+                Ok(ClientMessage::Register { name, identity_key, challenge_response }) => {
+                    let Some(challenge) = login_challenge.take(&name) else {
+                        println!("Registration without a login challenge from connection {}", connection_id);
+                        let response = ServerMessage::LoginFailed { username: name, reason: "no login challenge".to_string() };
+                        let _ = client_tx.send(response);
+                        continue;
+                    };
+                    let username = name.clone();
+
+                    // Bind the username to the identity key on first login, check it afterwards
+                    let login = {
+                        let mut accounts = state.accounts.lock().await;
+                        let login = accounts.login(&username, &identity_key, &challenge, &challenge_response);
+                        if login == Ok(true) {
+                            if let Err(e) = accounts.save(USERS_PATH) {
+                                println!("Failed to save user registry: {}", e);
+                            }
+                            println!("Created account for user {}", username);
+                        }
+                        login
+                    };
+
+                    // Register the user under its name
+                    let mut clients = state.clients.lock().await;
+                    let login = login.and_then(|_| {
+                        if clients.contains_key(&username) {
+                            Err(LoginError::AlreadyConnected)
+                        } else {
+                            Ok(())
+                        }
+                    });
+                    if let Err(e) = login {
+                        println!("Login failed for user {}: {}", username, e);
+                        let response = ServerMessage::LoginFailed { username, reason: e.to_string() };
+                        let _ = client_tx.send(response);
+                        continue;
+                    }
 
-            // Parse the message from the client
-            match serde_json::from_str::<ClientMessage>(text) {
-                Ok(ClientMessage::Register { name, public_key }) => {
-                    // Register the client with name and public key
                     {
-                        let mut clients = state.clients.lock().await;
                         let mut names = state.names.lock().await;
-                        let mut public_keys = state.public_keys.lock().await;
 
-                        clients.insert(client_id.clone(), client_tx.clone());
-                        names.insert(client_id.clone(), name.clone());
-                        public_keys.insert(client_id.clone(), public_key);
+                        clients.insert(username.clone(), client_tx.clone());
+                        names.insert(username.clone(), name.clone());
+
+                        // Tell the client its ID so it can authenticate message envelopes
+                        let registered = ServerMessage::Registered { client_id: username.clone() };
+                        let _ = client_tx.send(registered);
+
+                        // Groups come first, so queued group messages find their group
+                        // Members check each roster follows the last, so they get the chain from the owner's
+                        for rosters in state.groups.lock().await.values() {
+                            if rosters.last().is_some_and(|roster| roster.members.contains(&username)) {
+                                for roster in rosters {
+                                    let _ = client_tx.send(ServerMessage::GroupUpdate { roster: roster.clone() });
+                                }
+                            }
+                        }
+
+                        // Deliver anything that was queued while the user was offline, in order
+                        let queued = state.mailbox.lock().await.take_all(&username);
+                        if !queued.is_empty() {
+                            println!("Delivering {} queued messages to user {}", queued.len(), username);
+                        }
+                        for envelope in queued {
+                            // Senders that are still online hear that their queued message got through
+                            if let Some(sender_tx) = clients.get(&envelope.from) {
+                                let ack = ServerMessage::Ack { message_id: envelope.message_id.clone(), status: RelayStatus::Delivered };
+                                let _ = sender_tx.send(ack);
+                            }
+                            let _ = client_tx.send(ServerMessage::Deliver { envelope });
+                        }
 
                         broadcast_client_list(&clients, &names).await;
                     }
-                    println!("Client registered with name: {}", name);
+                    user_id = Some(username);
+                    println!("User logged in: {}", name);
                 }
-                Ok(ClientMessage::Send { to, message }) => {
+                Ok(ClientMessage::Send { to, message, message_id, version, initial }) => {
                     // Relay the encrypted message to the recipient
-                    let mut clients = state.clients.lock().await;
-                    if let Some(recipient_tx) = clients.get(&to) {
-                        let outgoing_msg = json!({
-                            "from": client_id.clone(),
-                            "message": message
-                        });
-                        if let Err(e) = recipient_tx.send(serde_json::to_string(&outgoing_msg).unwrap()) {
+                    let envelope = Envelope {
+                        from: client_id.clone(),
+                        to: to.clone(),
+                        message,
+                        message_id: message_id.clone(),
+                        version,
+                        initial,
+                        group: None,
+                        mls: None,
+                    };
+                    let clients = state.clients.lock().await;
+                    if !clients.contains_key(&to) && state.accounts.lock().await.get(&to).is_none() {
+                        println!("Recipient {} not found", to);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::UnknownUser,
+                            request_id: Some(message_id),
+                            reason: format!("unknown recipient {}", to),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    }
+                    let _ = client_tx.send(ServerMessage::Ack { message_id: message_id.clone(), status: RelayStatus::Accepted });
+
+                    let result = if let Some(recipient_tx) = clients.get(&to) {
+                        if let Err(e) = recipient_tx.send(ServerMessage::Deliver { envelope }) {
                             println!("Failed to send message to {}: {}", to, e);
+                            Err((ErrorCode::RecipientUnavailable, format!("{} went offline", to)))
                         } else {
                             println!("Message successfully sent from {} to {}", client_id, to);
+                            Ok(RelayStatus::Delivered)
                         }
                     } else {
-                        println!("Recipient {} not found", to);
+                        // Hold the envelope until the recipient logs in again
+                        let mut mailbox = state.mailbox.lock().await;
+                        match mailbox.push(&to, envelope) {
+                            Ok(_) => {
+                                println!("Queued message from {} for offline user {} ({} waiting)", client_id, to, mailbox.len(&to));
+                                Ok(RelayStatus::Queued)
+                            }
+                            Err(e) => {
+                                println!("Dropped message from {} to {}: {}", client_id, to, e);
+                                Err((ErrorCode::QueueFull, e.to_string()))
+                            }
+                        }
+                    };
+
+                    // Tell the sender where the message ended up
+                    let response = match result {
+                        Ok(status) => ServerMessage::Ack { message_id, status },
+                        Err((code, reason)) => ServerMessage::Error { code, request_id: Some(message_id), reason },
+                    };
+                    let _ = client_tx.send(response);
+                }
+                Ok(ClientMessage::UploadPreKeys { signed_prekey_id, signed_prekey, signed_prekey_signature, one_time_prekeys }) => {
+                    // The signed prekey must be signed by the identity bound to the account
+                    let accounts = state.accounts.lock().await;
+                    let Some(account) = accounts.get(&client_id) else {
+                        println!("Prekeys uploaded without an account by client {}", client_id);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::UnknownUser,
+                            request_id: None,
+                            reason: "no account to publish prekeys for".to_string(),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    };
+                    if !IdentityKeyPair::verify(&account.identity_key, &signed_prekey, &signed_prekey_signature) {
+                        println!("Invalid signed prekey from client {}", client_id);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::InvalidSignature,
+                            request_id: None,
+                            reason: "signed prekey isn't signed by the account's identity key".to_string(),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    }
+
+                    // Uploads only carry new one-time prekeys, so add them to those still stored
+                    let mut prekeys = state.prekeys.lock().await;
+                    let stored = prekeys.entry(client_id.clone()).or_insert_with(|| StoredPreKeys {
+                        signed_prekey_id,
+                        signed_prekey: Vec::new(),
+                        signed_prekey_signature: Vec::new(),
+                        one_time_prekeys: Vec::new(),
+                    });
+                    stored.signed_prekey_id = signed_prekey_id;
+                    stored.signed_prekey = signed_prekey;
+                    stored.signed_prekey_signature = signed_prekey_signature;
+                    for prekey in one_time_prekeys {
+                        if !stored.one_time_prekeys.iter().any(|stored| stored.id == prekey.id) {
+                            stored.one_time_prekeys.push(prekey);
+                        }
+                    }
+                    println!("Stored {} one-time prekeys for client {}", stored.one_time_prekeys.len(), client_id);
+                    let _ = client_tx.send(ServerMessage::PreKeyCount { remaining: stored.one_time_prekeys.len() });
+                }
+                Ok(ClientMessage::RequestPreKeyBundle { for_client, request_id }) => {
+                    // Bundles outlive the connection, so offline users can still be reached
+                    let accounts = state.accounts.lock().await;
+                    let mut prekeys = state.prekeys.lock().await;
+                    let Some(account) = accounts.get(&for_client) else {
+                        println!("Prekey bundle requested for unknown user {}", for_client);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::UnknownUser,
+                            request_id: Some(request_id),
+                            reason: format!("unknown user {}", for_client),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    };
+                    if let Some(stored) = prekeys.get_mut(&for_client) {
+                        // Each one-time prekey is handed out at most once
+                        let bundle = PreKeyBundle {
+                            identity_key: account.identity_key.clone(),
+                            signed_prekey_id: stored.signed_prekey_id,
+                            signed_prekey: stored.signed_prekey.clone(),
+                            signed_prekey_signature: stored.signed_prekey_signature.clone(),
+                            one_time_prekey: stored.one_time_prekeys.pop(),
+                        };
+                        let remaining = stored.one_time_prekeys.len();
+                        drop(prekeys);
+                        drop(accounts);
+                        let response = ServerMessage::PreKeyBundle { client_id: for_client.clone(), request_id, bundle };
+                        let _ = client_tx.send(response);
+
+                        // Ask the owner for more as they drop below the low-water mark, if it's online
+                        if remaining == PREKEY_LOW_WATER - 1 {
+                            if let Some(owner_tx) = state.clients.lock().await.get(&for_client) {
+                                let _ = owner_tx.send(ServerMessage::PreKeyCount { remaining });
+                            }
+                        }
+                    } else {
+                        println!("Prekey bundle for client {} not found", for_client);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::KeyNotFound,
+                            request_id: Some(request_id),
+                            reason: format!("{} has not published prekeys", for_client),
+                        };
+                        let _ = client_tx.send(response);
                     }
                 }
-                Ok(ClientMessage::RequestPublicKey { for_client }) => {
-                    let public_keys = state.public_keys.lock().await;
-                    if let Some(public_key) = public_keys.get(&for_client) {
-                        let response = json!({
-                            "type": "PublicKeyResponse",
-                            "client_id": for_client,
-                            "public_key": public_key
-                        });
-                        let mut tx = tx.lock().await; // Lock tx for sending
-                        let _ = tx.send(warp::ws::Message::text(response.to_string())).await;
+                Ok(ClientMessage::AdvertiseAddresses { addresses }) => {
+                    // Peers use these to try a direct connection before going through the relay, so
+                    // only a few socket addresses are kept for them to try
+                    let addresses: Vec<String> = addresses
+                        .into_iter()
+                        .filter(|address| address.parse::<SocketAddr>().is_ok())
+                        .take(MAX_PEER_ADDRESSES)
+                        .collect();
+                    println!("User {} is listening on {:?}", client_id, addresses);
+                    state.addresses.lock().await.insert(client_id.clone(), addresses);
+                }
+                Ok(ClientMessage::RequestAddresses { for_client }) => {
+                    // Offline users have no addresses, so the requester sticks to the relay
+                    let addresses = state.addresses.lock().await.get(&for_client).cloned().unwrap_or_default();
+                    let response = ServerMessage::Addresses { client_id: for_client, addresses };
+                    let _ = client_tx.send(response);
+                }
+                Ok(ClientMessage::CreateGroup { roster, request_id }) => {
+                    let clients = state.clients.lock().await;
+                    let accounts = state.accounts.lock().await;
+                    let mut groups = state.groups.lock().await;
+                    let checked = if groups.contains_key(&roster.group_id) {
+                        Err((ErrorCode::NotAllowed, "a group with that ID already exists".to_string()))
                     } else {
-                        println!("Public key for client {} not found", for_client);
+                        check_roster(&accounts, &client_id, &roster, None)
+                    };
+                    if let Err((code, reason)) = checked {
+                        let _ = client_tx.send(ServerMessage::Error { code, request_id: Some(request_id), reason });
+                        continue;
                     }
+
+                    println!("User {} created group {} with {:?}", client_id, roster.group_id, roster.members);
+                    send_to_members(&clients, &roster.members, &ServerMessage::GroupUpdate { roster: roster.clone() });
+                    groups.insert(roster.group_id.clone(), vec![roster]);
                 }
-                Err(_) => {
-                    println!("Invalid message format from client {}", client_id);
+                Ok(ClientMessage::UpdateGroup { roster, request_id }) => {
+                    let clients = state.clients.lock().await;
+                    let accounts = state.accounts.lock().await;
+                    let mut groups = state.groups.lock().await;
+                    let Some(rosters) = groups.get_mut(&roster.group_id) else {
+                        let _ = client_tx.send(unknown_group(&roster.group_id, request_id));
+                        continue;
+                    };
+                    let previous = rosters.last().expect("groups have at least one roster");
+                    if let Err((code, reason)) = check_roster(&accounts, &client_id, &roster, Some(previous)) {
+                        let _ = client_tx.send(ServerMessage::Error { code, request_id: Some(request_id), reason });
+                        continue;
+                    }
+                    println!("User {} changed group {} to {:?}", client_id, roster.group_id, roster.members);
+
+                    // Removed members hear about it too, so they stop using the group
+                    let update = ServerMessage::GroupUpdate { roster: roster.clone() };
+                    let removed: Vec<String> = previous.members.iter().filter(|member| !roster.members.contains(member)).cloned().collect();
+                    send_to_members(&clients, &roster.members, &update);
+                    send_to_members(&clients, &removed, &update);
+                    if roster.members.is_empty() {
+                        groups.remove(&roster.group_id);
+                    } else if roster.signer == previous.owner {
+                        *rosters = vec![roster];
+                    } else {
+                        rosters.push(roster);
+                    }
+                }
+                Ok(ClientMessage::SendGroup { group_id, message, message_id, version }) => {
+                    let clients = state.clients.lock().await;
+                    let groups = state.groups.lock().await;
+                    let Some(group) = groups.get(&group_id).and_then(|rosters| rosters.last()) else {
+                        let _ = client_tx.send(unknown_group(&group_id, message_id));
+                        continue;
+                    };
+                    if !group.members.contains(&client_id) {
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::NotAllowed,
+                            request_id: Some(message_id),
+                            reason: "not a member of the group".to_string(),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    }
+                    let _ = client_tx.send(ServerMessage::Ack { message_id: message_id.clone(), status: RelayStatus::Accepted });
+
+                    // Every other member gets its own copy of the one ciphertext, queued if they're offline
+                    let mut mailbox = state.mailbox.lock().await;
+                    let mut status = RelayStatus::Delivered;
+                    for member in group.members.iter().filter(|member| **member != client_id) {
+                        let envelope = Envelope {
+                            from: client_id.clone(),
+                            to: member.clone(),
+                            message: message.clone(),
+                            message_id: message_id.clone(),
+                            version,
+                            initial: None,
+                            group: Some(group_id.clone()),
+                            mls: None,
+                        };
+                        if !deliver_or_queue(&clients, &mut mailbox, envelope) {
+                            status = RelayStatus::Queued;
+                        }
+                    }
+                    println!("Group message from {} fanned out to group {}", client_id, group_id);
+                    let _ = client_tx.send(ServerMessage::Ack { message_id, status });
+                }
+                Ok(ClientMessage::PublishKeyPackages { key_packages }) => {
+                    // Key packages must be for the account and signed by its identity key
+                    let accounts = state.accounts.lock().await;
+                    let Some(account) = accounts.get(&client_id) else {
+                        println!("Key packages published without an account by client {}", client_id);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::UnknownUser,
+                            request_id: None,
+                            reason: "no account to publish key packages for".to_string(),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    };
+                    let valid = key_packages.iter().all(|key_package| {
+                        key_package.verify() && key_package.member() == client_id && key_package.identity_key() == account.identity_key
+                    });
+                    if !valid {
+                        println!("Invalid key package from client {}", client_id);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::InvalidSignature,
+                            request_id: None,
+                            reason: "key packages must be signed by the account's identity key".to_string(),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    }
+
+                    println!("Stored {} key packages for client {}", key_packages.len(), client_id);
+                    state.key_packages.lock().await.insert(client_id.clone(), key_packages);
+                }
+                Ok(ClientMessage::RequestKeyPackage { for_client, request_id }) => {
+                    if state.accounts.lock().await.get(&for_client).is_none() {
+                        println!("Key package requested for unknown user {}", for_client);
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::UnknownUser,
+                            request_id: Some(request_id),
+                            reason: format!("unknown user {}", for_client),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    }
+
+                    // Each key package is handed out at most once
+                    let key_package = state.key_packages.lock().await.get_mut(&for_client).and_then(Vec::pop);
+                    let response = match key_package {
+                        Some(key_package) => ServerMessage::KeyPackage { client_id: for_client, request_id, key_package },
+                        None => {
+                            println!("No key packages left for client {}", for_client);
+                            ServerMessage::Error {
+                                code: ErrorCode::KeyNotFound,
+                                request_id: Some(request_id),
+                                reason: format!("{} has no key packages left", for_client),
+                            }
+                        }
+                    };
+                    let _ = client_tx.send(response);
+                }
+                Ok(ClientMessage::SendMls { message, message_id, welcome }) => {
+                    // Members a commit adds can only join through its Welcome
+                    let adds_members = match &message.content {
+                        MlsContent::Commit { commit } => commit.proposals.iter().any(|proposal| matches!(proposal, Proposal::Add { .. })),
+                        _ => false,
+                    };
+                    if adds_members && welcome.is_none() {
+                        let response = ServerMessage::Error {
+                            code: ErrorCode::Malformed,
+                            request_id: Some(message_id),
+                            reason: "commit adds members without a welcome".to_string(),
+                        };
+                        let _ = client_tx.send(response);
+                        continue;
+                    }
+
+                    // The delivery service accepts one commit per epoch, so every member sees the same order
+                    let clients = state.clients.lock().await;
+                    let routing = match state.mls.lock().await.route(&client_id, &message) {
+                        Ok(routing) => routing,
+                        Err(e) => {
+                            println!("Refused MLS message from {} for group {}: {}", client_id, message.group_id, e);
+                            let response = ServerMessage::Error { code: e.into(), request_id: Some(message_id), reason: e.to_string() };
+                            let _ = client_tx.send(response);
+                            continue;
+                        }
+                    };
+                    let _ = client_tx.send(ServerMessage::Ack { message_id: message_id.clone(), status: RelayStatus::Accepted });
+
+                    let message_bytes = message.to_bytes();
+                    let welcome_bytes = welcome.map(|welcome| welcome.to_bytes()).unwrap_or_default();
+                    let deliveries = routing
+                        .recipients
+                        .iter()
+                        .map(|member| (member, MlsPayload::Message, &message_bytes))
+                        .chain(routing.welcomed.iter().map(|member| (member, MlsPayload::Welcome, &welcome_bytes)));
+                    let mut mailbox = state.mailbox.lock().await;
+                    let mut status = RelayStatus::Delivered;
+                    for (member, payload, bytes) in deliveries {
+                        let envelope = Envelope {
+                            from: client_id.clone(),
+                            to: member.clone(),
+                            message: bytes.clone(),
+                            message_id: message_id.clone(),
+                            version: PROTOCOL_VERSION,
+                            initial: None,
+                            group: Some(message.group_id.clone()),
+                            mls: Some(payload),
+                        };
+                        if !deliver_or_queue(&clients, &mut mailbox, envelope) {
+                            status = RelayStatus::Queued;
+                        }
+                    }
+                    println!("MLS message from {} fanned out to group {} at epoch {}", client_id, message.group_id, message.epoch);
+                    let _ = client_tx.send(ServerMessage::Ack { message_id, status });
+                }
+                Err(e) => {
+                    println!("Invalid message from client {}: {}", client_id, e);
+                    let _ = client_tx.send(ServerMessage::Error { code: e.into(), request_id: None, reason: e.to_string() });
                 }
             }
         }
     }
 
-    // Remove the client on disconnect
-    {
+    // Take the user offline on disconnect; the account and its prekeys stay
+    if let Some(client_id) = user_id {
         let mut clients = state.clients.lock().await;
         let mut names = state.names.lock().await;
-        let mut public_keys = state.public_keys.lock().await;
 
         clients.remove(&client_id);
         names.remove(&client_id);
-        public_keys.remove(&client_id);
+        state.addresses.lock().await.remove(&client_id);
 
         broadcast_client_list(&clients, &names).await;
+        println!("User disconnected: {}", client_id);
+    } else {
+        println!("Client disconnected: {}", connection_id);
+    }
+}
+
+
+
+/// Send a frame to every member of a group that's online
+fn send_to_members(clients: &HashMap<String, mpsc::UnboundedSender<ServerMessage>>, members: &[String], message: &ServerMessage) {
+    for member in members {
+        if let Some(member_tx) = clients.get(member) {
+            let _ = member_tx.send(message.clone());
+        }
+    }
+}
+
+/// Hand an envelope to its recipient's connection, or queue it if they're offline
+///
+/// Returns whether it was delivered straight away.
+fn deliver_or_queue(
+    clients: &HashMap<String, mpsc::UnboundedSender<ServerMessage>>,
+    mailbox: &mut Mailbox,
+    envelope: Envelope,
+) -> bool {
+    let delivered = clients
+        .get(&envelope.to)
+        .is_some_and(|recipient_tx| recipient_tx.send(ServerMessage::Deliver { envelope: envelope.clone() }).is_ok());
+    if !delivered {
+        let (from, to) = (envelope.from.clone(), envelope.to.clone());
+        if let Err(e) = mailbox.push(&to, envelope) {
+            println!("Dropped group message from {} to {}: {}", from, to, e);
+        }
     }
-    println!("Client disconnected: {}", client_id);
+    delivered
 }
 
+/// Check a roster `client_id` sent may start a group, or follow `previous`
+///
+/// Clients check the signature against the signer's pinned key themselves; the
+/// relay checks it too, so a bad roster is refused before it's fanned out.
+fn check_roster(
+    accounts: &UserRegistry,
+    client_id: &str,
+    roster: &GroupRoster,
+    previous: Option<&GroupRoster>,
+) -> Result<(), (ErrorCode, String)> {
+    if roster.signer != client_id {
+        return Err((ErrorCode::NotAllowed, "rosters are signed by their sender".to_string()));
+    }
+    let allowed = match previous {
+        None => roster.version == 0 && roster.is_well_formed(),
+        Some(previous) => roster.follows(previous),
+    };
+    if !allowed {
+        let reason = "only the owner can change the members, and members can only remove themselves";
+        return Err((ErrorCode::NotAllowed, reason.to_string()));
+    }
+    if previous.is_none() && (!is_valid_username(&roster.name) || uuid::Uuid::parse_str(&roster.group_id).is_err()) {
+        return Err((ErrorCode::Malformed, "group names are 1-32 letters, digits, '-' or '_'".to_string()));
+    }
+    if let Some(unknown) = roster.members.iter().find(|member| accounts.get(member).is_none()) {
+        return Err((ErrorCode::UnknownUser, format!("unknown user {}", unknown)));
+    }
+    let signer_key = &accounts.get(client_id).ok_or((ErrorCode::UnknownUser, format!("unknown user {}", client_id)))?.identity_key;
+    if !roster.verify(signer_key) {
+        return Err((ErrorCode::InvalidSignature, "roster isn't signed by its sender".to_string()));
+    }
+    Ok(())
+}
 
+fn unknown_group(group_id: &str, request_id: String) -> ServerMessage {
+    ServerMessage::Error {
+        code: ErrorCode::UnknownGroup,
+        request_id: Some(request_id),
+        reason: format!("unknown group {}", group_id),
+    }
+}
 
 /// Broadcast the list of connected clients with names and IDs
 async fn broadcast_client_list(
-    clients: &HashMap<String, mpsc::UnboundedSender<String>>,
+    clients: &HashMap<String, mpsc::UnboundedSender<ServerMessage>>,
     names: &HashMap<String, String>,
 ) {
     let client_list: Vec<(String, String)> = names
@@ -158,8 +740,8 @@ async fn broadcast_client_list(
         .map(|(id, name)| (id.clone(), name.clone()))
         .collect();
 
-    let message = serde_json::to_string(&client_list).unwrap();
-    println!("Broadcasting client list! {}", message);
+    let message = ServerMessage::ClientList { clients: client_list };
+    println!("Broadcasting client list! {}", message.to_json());
 
 
     for (client_id, client_tx) in clients.iter() {
@@ -0,0 +1,283 @@
+use crate::accounts::UserRegistry;
+use crate::attachments::CHUNK_SIZE;
+use crate::fallback::{authenticate, error, reply, SeenNonces};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use warp::http::{HeaderMap, StatusCode};
+use warp::reply::{Reply, Response};
+use warp::Filter;
+
+/// Largest encrypted chunk the store accepts: a full chunk plus nonce and tag
+const MAX_CHUNK_LEN: u64 = CHUNK_SIZE as u64 + 64;
+
+/// Encrypted file uploaded in chunks by its owner
+struct Blob {
+    owner: String,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    created_at: Instant,
+}
+
+/// Reasons a chunk can't be stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobError {
+    /// The blob was created by another user
+    NotOwner,
+    /// The uploader already has the maximum number of bytes stored
+    QuotaExceeded,
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::NotOwner => f.write_str("blob belongs to another user"),
+            BlobError::QuotaExceeded => f.write_str("uploader's storage quota is full"),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
+
+/// Encrypted file chunks waiting to be downloaded, keyed by blob ID
+///
+/// The store never sees file keys, only ciphertext. Each user can have at most
+/// `quota` bytes stored, and blobs older than `ttl` are dropped.
+pub struct BlobStore {
+    blobs: HashMap<String, Blob>,
+    quota: usize,
+    ttl: Duration,
+}
+
+impl BlobStore {
+    /// Create an empty store with a per-user quota in bytes and an expiry time
+    pub fn new(quota: usize, ttl: Duration) -> Self {
+        BlobStore { blobs: HashMap::new(), quota, ttl }
+    }
+
+    /// Store chunk `index` of a blob, creating the blob on its first chunk
+    ///
+    /// Uploading a chunk again replaces it, so an interrupted upload can be retried.
+    pub fn put_chunk(&mut self, owner: &str, blob_id: &str, index: u32, data: Vec<u8>) -> Result<(), BlobError> {
+        self.expire_all();
+        if self.blobs.get(blob_id).is_some_and(|blob| blob.owner != owner) {
+            return Err(BlobError::NotOwner);
+        }
+        let replaced = self.blobs.get(blob_id).and_then(|blob| blob.chunks.get(&index)).map_or(0, Vec::len);
+        if self.usage(owner) - replaced + data.len() > self.quota {
+            return Err(BlobError::QuotaExceeded);
+        }
+
+        let blob = self.blobs.entry(blob_id.to_string()).or_insert_with(|| Blob {
+            owner: owner.to_string(),
+            chunks: BTreeMap::new(),
+            created_at: Instant::now(),
+        });
+        blob.chunks.insert(index, data);
+        Ok(())
+    }
+
+    /// Get chunk `index` of a blob
+    pub fn chunk(&self, blob_id: &str, index: u32) -> Option<&[u8]> {
+        let blob = self.blobs.get(blob_id).filter(|blob| blob.created_at.elapsed() < self.ttl)?;
+        blob.chunks.get(&index).map(Vec::as_slice)
+    }
+
+    /// Drop every blob older than the TTL
+    pub fn expire_all(&mut self) {
+        let ttl = self.ttl;
+        self.blobs.retain(|_, blob| blob.created_at.elapsed() < ttl);
+    }
+
+    /// Bytes stored by `owner`
+    fn usage(&self, owner: &str) -> usize {
+        self.blobs
+            .values()
+            .filter(|blob| blob.owner == owner)
+            .flat_map(|blob| blob.chunks.values())
+            .map(Vec::len)
+            .sum()
+    }
+}
+
+/// Relay state the blob store shares with the WebSocket relay
+#[derive(Clone)]
+pub struct ServerState {
+    pub accounts: Arc<Mutex<UserRegistry>>,
+    pub blobs: Arc<Mutex<BlobStore>>,
+    pub nonces: Arc<Mutex<SeenNonces>>,
+}
+
+/// HTTP store for encrypted file chunks
+///
+/// - `PUT /blobs/<blob ID>/<index>` stores a chunk, creating the blob if it's new
+/// - `GET /blobs/<blob ID>/<index>` fetches a chunk
+///
+/// Blob IDs are UUIDs. Every request is signed with the caller's identity key, see
+/// `fallback::sign_request`; only the user who created a blob can add to it.
+pub fn routes(state: ServerState) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let state_filter = warp::any().map(move || state.clone());
+
+    let put_chunk = warp::put()
+        .and(warp::path!("blobs" / String / u32))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(MAX_CHUNK_LEN))
+        .and(warp::body::bytes())
+        .and(state_filter.clone())
+        .and_then(put_chunk);
+
+    let get_chunk = warp::get()
+        .and(warp::path!("blobs" / String / u32))
+        .and(warp::header::headers_cloned())
+        .and(state_filter)
+        .and_then(get_chunk);
+
+    put_chunk.or(get_chunk).unify()
+}
+
+async fn put_chunk(
+    blob_id: String,
+    index: u32,
+    headers: HeaderMap,
+    body: warp::hyper::body::Bytes,
+    state: ServerState,
+) -> Result<Response, Infallible> {
+    let path = format!("/blobs/{}/{}", blob_id, index);
+    let owner = match authenticate(&state.accounts, &state.nonces, &headers, "PUT", &path, &body).await {
+        Ok(owner) => owner,
+        Err(status) => return Ok(error(status, "invalid request signature").into_response()),
+    };
+    if uuid::Uuid::parse_str(&blob_id).is_err() {
+        return Ok(error(StatusCode::BAD_REQUEST, "blob IDs are UUIDs").into_response());
+    }
+
+    let response = match state.blobs.lock().await.put_chunk(&owner, &blob_id, index, body.to_vec()) {
+        Ok(()) => reply(StatusCode::OK, json!({ "stored": index })),
+        Err(BlobError::NotOwner) => error(StatusCode::FORBIDDEN, "blob belongs to another user"),
+        Err(BlobError::QuotaExceeded) => error(StatusCode::INSUFFICIENT_STORAGE, "storage quota is full"),
+    };
+    Ok(response.into_response())
+}
+
+async fn get_chunk(blob_id: String, index: u32, headers: HeaderMap, state: ServerState) -> Result<Response, Infallible> {
+    let path = format!("/blobs/{}/{}", blob_id, index);
+    if let Err(status) = authenticate(&state.accounts, &state.nonces, &headers, "GET", &path, &[]).await {
+        return Ok(error(status, "invalid request signature").into_response());
+    }
+    if uuid::Uuid::parse_str(&blob_id).is_err() {
+        return Ok(error(StatusCode::BAD_REQUEST, "blob IDs are UUIDs").into_response());
+    }
+
+    match state.blobs.lock().await.chunk(&blob_id, index) {
+        Some(chunk) => Ok(chunk.to_vec().into_response()),
+        None => Ok(error(StatusCode::NOT_FOUND, "no such chunk").into_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{challenge_message, new_challenge};
+    use crate::fallback::sign_request;
+    use crate::identity::IdentityKeyPair;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn blob_id() -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+
+    fn state_with(users: &[(&str, &IdentityKeyPair)]) -> ServerState {
+        let mut accounts = UserRegistry::default();
+        for (username, identity) in users {
+            let challenge = new_challenge().unwrap();
+            let response = identity.sign(&challenge_message(username, &challenge));
+            accounts.login(username, identity.public_key(), &challenge, &response).unwrap();
+        }
+        ServerState {
+            accounts: Arc::new(Mutex::new(accounts)),
+            blobs: Arc::new(Mutex::new(BlobStore::new(1024, TTL))),
+            nonces: Arc::new(Mutex::new(SeenNonces::new())),
+        }
+    }
+
+    fn signed(identity: &IdentityKeyPair, username: &str, method: &str, path: &str, body: &[u8]) -> warp::test::RequestBuilder {
+        sign_request(identity, username, method, path, body)
+            .iter()
+            .fold(warp::test::request().method(method).path(path).body(body), |request, (name, value)| {
+                request.header(*name, value)
+            })
+    }
+
+    #[test]
+    fn uploads_past_the_quota_are_rejected() {
+        let mut store = BlobStore::new(100, TTL);
+        let (first, second) = (blob_id(), blob_id());
+        store.put_chunk("alice", &first, 0, vec![0; 60]).unwrap();
+        assert_eq!(store.put_chunk("alice", &second, 0, vec![0; 41]), Err(BlobError::QuotaExceeded));
+        assert!(store.chunk(&second, 0).is_none());
+
+        // Replacing a chunk only counts the difference, and other users have their own quota
+        store.put_chunk("alice", &first, 0, vec![1; 100]).unwrap();
+        store.put_chunk("bob", &second, 0, vec![0; 100]).unwrap();
+        assert_eq!(store.chunk(&first, 0), Some([1; 100].as_slice()));
+    }
+
+    #[test]
+    fn expired_blobs_are_not_returned() {
+        let mut store = BlobStore::new(100, TTL);
+        let (old, new) = (blob_id(), blob_id());
+        store.put_chunk("alice", &old, 0, vec![0; 60]).unwrap();
+        let blob = store.blobs.get_mut(&old).unwrap();
+        blob.created_at = blob.created_at.checked_sub(TTL).unwrap();
+        assert!(store.chunk(&old, 0).is_none());
+
+        // The expired blob no longer counts against the quota
+        store.put_chunk("alice", &new, 0, vec![0; 60]).unwrap();
+        assert!(!store.blobs.contains_key(&old));
+    }
+
+    #[test]
+    fn only_the_owner_adds_to_a_blob() {
+        let mut store = BlobStore::new(100, TTL);
+        let id = blob_id();
+        store.put_chunk("alice", &id, 0, vec![0; 10]).unwrap();
+        assert_eq!(store.put_chunk("mallory", &id, 0, vec![1; 10]), Err(BlobError::NotOwner));
+        assert_eq!(store.put_chunk("mallory", &id, 1, vec![1; 10]), Err(BlobError::NotOwner));
+        assert_eq!(store.chunk(&id, 0), Some([0; 10].as_slice()));
+        assert!(store.chunk(&id, 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn requests_are_checked_for_signer_owner_and_id() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let stranger = IdentityKeyPair::generate();
+        let routes = routes(state_with(&[("alice", &alice), ("bob", &bob)]));
+        let path = format!("/blobs/{}/0", blob_id());
+
+        let response = signed(&alice, "alice", "PUT", &path, b"chunk").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = signed(&bob, "bob", "PUT", &path, b"other").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // Recipients fetch chunks by blob ID, but only registered users with a valid signature
+        let response = signed(&bob, "bob", "GET", &path, &[]).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_ref(), b"chunk");
+        let response = signed(&stranger, "bob", "GET", &path, &[]).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = signed(&stranger, "stranger", "GET", &path, &[]).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        for path in ["/blobs/not-a-uuid/0", "/blobs/..%2Fmailbox/0"] {
+            let response = signed(&alice, "alice", "GET", path, &[]).reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let response = signed(&alice, "alice", "PUT", path, b"chunk").reply(&routes).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What we know about a contact's identity
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Contact {
+    pub identity_key: Vec<u8>,
+    pub verified: bool, // Set once the user has compared safety numbers
+}
+
+/// Result of recording the identity key a contact presented
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyChange {
+    /// First time we see this contact
+    New,
+    /// Same key as before
+    Unchanged,
+    /// The contact's key differs from the pinned one
+    Changed { was_verified: bool },
+}
+
+/// Pinned identity keys of contacts, keyed by user ID
+///
+/// Display names come from the relay, which could hand a contact's name to someone
+/// else, so they are only ever shown to the user, never used to find a pinned key.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ContactStore {
+    contacts: HashMap<String, Contact>,
+}
+
+impl ContactStore {
+    /// Load the plaintext contacts file clients kept before the keystore, to move it in
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Look up a contact by user ID
+    pub fn get(&self, peer_id: &str) -> Option<&Contact> {
+        self.contacts.get(peer_id)
+    }
+
+    /// Check the identity key a contact presented against the pinned one
+    ///
+    /// Nothing is stored; call `pin_identity` for a `New` contact once the handshake
+    /// succeeds, or after the user approved a `Changed` key.
+    pub fn check_identity(&self, peer_id: &str, identity_key: &[u8]) -> KeyChange {
+        match self.contacts.get(peer_id) {
+            Some(contact) if contact.identity_key == identity_key => KeyChange::Unchanged,
+            Some(contact) => KeyChange::Changed { was_verified: contact.verified },
+            None => KeyChange::New,
+        }
+    }
+
+    /// Pin the identity key of a contact, replacing any previous key and clearing the verified flag
+    pub fn pin_identity(&mut self, peer_id: &str, identity_key: &[u8]) {
+        self.contacts.insert(
+            peer_id.to_string(),
+            Contact { identity_key: identity_key.to_vec(), verified: false },
+        );
+    }
+
+    /// Mark a contact as verified, returning `false` if it's unknown
+    pub fn set_verified(&mut self, peer_id: &str, verified: bool) -> bool {
+        match self.contacts.get_mut(peer_id) {
+            Some(contact) => {
+                contact.verified = verified;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presented_keys_are_checked_against_the_pinned_one() {
+        let mut contacts = ContactStore::default();
+        assert_eq!(contacts.check_identity("bob", b"bob key"), KeyChange::New);
+
+        // Checking doesn't pin
+        assert!(contacts.get("bob").is_none());
+        contacts.pin_identity("bob", b"bob key");
+        assert_eq!(contacts.check_identity("bob", b"bob key"), KeyChange::Unchanged);
+        assert_eq!(contacts.check_identity("bob", b"other key"), KeyChange::Changed { was_verified: false });
+        assert_eq!(contacts.check_identity("carol", b"bob key"), KeyChange::New);
+    }
+
+    #[test]
+    fn changed_keys_report_and_clear_verification() {
+        let mut contacts = ContactStore::default();
+        assert!(!contacts.set_verified("bob", true));
+        contacts.pin_identity("bob", b"bob key");
+        assert!(contacts.set_verified("bob", true));
+        assert_eq!(contacts.check_identity("bob", b"bob key"), KeyChange::Unchanged);
+        assert_eq!(contacts.check_identity("bob", b"other key"), KeyChange::Changed { was_verified: true });
+
+        // Pinning the new key after approval starts over unverified
+        contacts.pin_identity("bob", b"other key");
+        assert!(!contacts.get("bob").unwrap().verified);
+        assert_eq!(contacts.check_identity("bob", b"other key"), KeyChange::Unchanged);
+        assert_eq!(contacts.check_identity("bob", b"bob key"), KeyChange::Changed { was_verified: false });
+    }
+}
@@ -0,0 +1,48 @@
+use crate::attachments::Attachment;
+use crate::groups::SenderKeyDistribution;
+use crate::protocol::ProtocolError;
+use serde::{Deserialize, Serialize};
+
+/// What an encrypted message carries
+///
+/// Content is encoded before encryption, so the relay can't tell receipts from text.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Content {
+    Text {
+        body: String,
+    },
+    /// Confirms the recipient's client got, or its user saw, earlier messages
+    Receipt {
+        kind: ReceiptKind,
+        message_ids: Vec<String>,
+    },
+    /// The sender's key for a group; `request_reply` asks for our key in return
+    SenderKey {
+        distribution: SenderKeyDistribution,
+        #[serde(default)]
+        request_reply: bool,
+    },
+    /// A file uploaded to the relay's blob store, with the key to decrypt it
+    File {
+        attachment: Attachment,
+    },
+}
+
+/// How far a message has got on the recipient's side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+impl Content {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Failed to encode message content")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        serde_json::from_slice(bytes).map_err(|_| ProtocolError::Malformed)
+    }
+}
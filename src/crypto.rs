@@ -1,16 +1,155 @@
+use crate::identity::IdentityKeyPair;
+use crate::prekeys::{InitialMessage, PreKeyBundle, PreKeyStore};
 use p256::{
-    ecdh::{EphemeralSecret, SharedSecret},
+    ecdh::EphemeralSecret,
     elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint},
-    EncodedPoint,
+    EncodedPoint, PublicKey, SecretKey,
 };
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
-use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::digest::{digest, Digest, SHA256};
+use ring::hkdf::{Prk, Salt, HKDF_SHA256};
 use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+
+/// Length of the nonce prepended to every ciphertext
+const NONCE_LEN: usize = 12;
+
+/// Version of the message envelope, bound into every message's AEAD tag
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Envelope metadata authenticated along with a message
+///
+/// A relay that rewrites any of these fields, or replays a ciphertext into
+/// another conversation, makes decryption fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AssociatedData<'a> {
+    pub version: u8,
+    pub sender: &'a str,
+    pub recipient: &'a str,
+    pub message_id: &'a str,
+}
+
+impl AssociatedData<'_> {
+    /// Encode the fields unambiguously, with each string length-prefixed
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.version];
+        for field in [self.sender, self.recipient, self.message_id] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes
+    }
+}
+
+/// Errors returned by the crypto layer instead of panicking on bad input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// A peer public key is not a valid P-256 point
+    InvalidPublicKey,
+    /// A signature over a key did not verify
+    InvalidSignature,
+    /// The one-time prekey named by an initial message is unknown or already used
+    UnknownOneTimePreKey,
+    /// The signed prekey named by an initial message has been replaced twice since
+    UnknownSignedPreKey,
+    /// A private key in stored state isn't a valid P-256 scalar
+    CorruptKey,
+    /// Ciphertext is shorter than a nonce plus tag
+    CiphertextTooShort,
+    /// A ratchet message header could not be parsed
+    InvalidHeader,
+    /// Decryption failed, either from a wrong key or tampered ciphertext
+    DecryptionFailed,
+    /// Encryption or key derivation failed inside `ring`
+    EncryptionFailed,
+    /// The system random number generator failed
+    RandomFailed,
+    /// No session exists for the peer
+    NoSession,
+    /// The session can't send until it has received a message
+    NoSendingChain,
+    /// A message skipped more message keys than a session will store
+    TooManySkippedMessages,
+    /// An initial message repeats a handshake that was already answered
+    ReplayedHandshake,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            CryptoError::InvalidPublicKey => "invalid public key",
+            CryptoError::InvalidSignature => "invalid signature",
+            CryptoError::UnknownOneTimePreKey => "unknown one-time prekey",
+            CryptoError::UnknownSignedPreKey => "unknown signed prekey",
+            CryptoError::CorruptKey => "corrupt stored private key",
+            CryptoError::CiphertextTooShort => "ciphertext too short",
+            CryptoError::InvalidHeader => "invalid message header",
+            CryptoError::DecryptionFailed => "decryption failed",
+            CryptoError::EncryptionFailed => "encryption failed",
+            CryptoError::RandomFailed => "random number generator failed",
+            CryptoError::NoSession => "no session with peer",
+            CryptoError::NoSendingChain => "session has no sending chain yet",
+            CryptoError::TooManySkippedMessages => "too many skipped messages",
+            CryptoError::ReplayedHandshake => "replayed handshake",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Prefix of every HKDF info label used by this crate
+const INFO_PREFIX: &[u8] = b"p2p_sparse_messaging v1";
+
+/// Length of the random salt sent with an X3DH initial message
+pub const SALT_LEN: usize = 32;
+
+/// HKDF key schedule bound to a handshake transcript
+///
+/// Every key is expanded with an info label naming its purpose, the public keys of
+/// the sending and receiving party and the transcript hash, so keys from one
+/// session or direction can never be confused with another's.
+pub struct KeySchedule {
+    prk: Prk,
+    transcript_hash: Digest,
+}
+
+impl KeySchedule {
+    /// Extract a key from `shared_secret` with `salt` and hash the handshake transcript
+    pub fn new(shared_secret: &[u8], salt: &[u8], transcript: &[u8]) -> Self {
+        KeySchedule {
+            prk: Salt::new(HKDF_SHA256, salt).extract(shared_secret),
+            transcript_hash: digest(&SHA256, transcript),
+        }
+    }
+
+    /// Expand the key for `label` on the direction `from_public_key` -> `to_public_key`
+    pub fn derive(&self, label: &str, from_public_key: &[u8], to_public_key: &[u8]) -> Result<[u8; 32], CryptoError> {
+        let mut info = INFO_PREFIX.to_vec();
+        for field in [label.as_bytes(), from_public_key, to_public_key] {
+            info.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            info.extend_from_slice(field);
+        }
+        info.extend_from_slice(self.transcript_hash.as_ref());
+
+        let mut okm = [0u8; 32];
+        self.prk
+            .expand(&[&info], &AES_256_GCM)
+            .and_then(|okm_material| okm_material.fill(&mut okm))
+            .map_err(|_| CryptoError::EncryptionFailed)?;
+        Ok(okm)
+    }
+}
 
 pub struct Crypto {
     private_key: EphemeralSecret,
     public_key: Vec<u8>,
-    shared_secret: Option<[u8; 32]>, // Store the shared secret instead of `LessSafeKey`
+}
+
+impl Default for Crypto {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Crypto {
@@ -26,7 +165,6 @@ impl Crypto {
         Crypto {
             private_key,
             public_key,
-            shared_secret: None,
         }
     }
 
@@ -35,15 +173,16 @@ impl Crypto {
         &self.public_key
     }
 
-    /// Derive a shared secret using the peer's public key
-    pub fn derive_session_key(&mut self, peer_public_key: &[u8]) {
-        use p256::PublicKey;
-
+    /// Derive the shared secret with a peer from its public key
+    ///
+    /// Nothing is stored on `self`, so each peer gets its own secret.
+    pub fn derive_session_key(&self, peer_public_key: &[u8]) -> Result<[u8; 32], CryptoError> {
         // Decode the peer's public key as an EncodedPoint
-        let peer_encoded = EncodedPoint::from_bytes(peer_public_key).expect("Invalid public key");
+        let peer_encoded = EncodedPoint::from_bytes(peer_public_key).map_err(|_| CryptoError::InvalidPublicKey)?;
 
         // Convert the EncodedPoint to a PublicKey
-        let peer_key = PublicKey::from_encoded_point(&peer_encoded).unwrap();
+        let peer_key = Option::<PublicKey>::from(PublicKey::from_encoded_point(&peer_encoded))
+            .ok_or(CryptoError::InvalidPublicKey)?;
 
         // Compute the shared secret
         let shared_secret = self.private_key.diffie_hellman(&peer_key);
@@ -51,64 +190,321 @@ impl Crypto {
         // Convert the GenericArray<u8, U32> into a [u8; 32]
         let mut secret_bytes = [0u8; 32];
         secret_bytes.copy_from_slice(shared_secret.as_bytes());
+        Ok(secret_bytes)
+    }
+
+    /// Run the initiator side of X3DH against a peer's prekey bundle
+    ///
+    /// Returns the shared secret and the header the peer needs to derive it too.
+    pub fn x3dh_initiate(
+        identity: &IdentityKeyPair,
+        bundle: &PreKeyBundle,
+    ) -> Result<([u8; 32], InitialMessage), CryptoError> {
+        if !bundle.verify() {
+            return Err(CryptoError::InvalidSignature);
+        }
+
+        let ephemeral = SecretKey::random(&mut rand_core::OsRng);
+        let identity_secret = identity.secret_key();
+        let mut salt = vec![0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| CryptoError::RandomFailed)?;
+
+        // DH1 = DH(IK_A, SPK_B), DH2 = DH(EK_A, IK_B), DH3 = DH(EK_A, SPK_B), DH4 = DH(EK_A, OPK_B)
+        let mut dh = Vec::with_capacity(128);
+        dh.extend_from_slice(&Self::diffie_hellman(&identity_secret, &bundle.signed_prekey)?);
+        dh.extend_from_slice(&Self::diffie_hellman(&ephemeral, &bundle.identity_key)?);
+        dh.extend_from_slice(&Self::diffie_hellman(&ephemeral, &bundle.signed_prekey)?);
+        if let Some(one_time_prekey) = &bundle.one_time_prekey {
+            dh.extend_from_slice(&Self::diffie_hellman(&ephemeral, &one_time_prekey.public_key)?);
+        }
 
-        // Store the shared secret
-        self.shared_secret = Some(secret_bytes);
+        let initial = InitialMessage {
+            identity_key: identity.public_key().to_vec(),
+            ephemeral_key: ephemeral.public_key().to_encoded_point(false).as_bytes().to_vec(),
+            signed_prekey_id: bundle.signed_prekey_id,
+            one_time_prekey_id: bundle.one_time_prekey.as_ref().map(|prekey| prekey.id),
+            salt,
+        };
+
+        let secret = Self::x3dh_kdf(&dh, &initial, &bundle.identity_key, &bundle.signed_prekey)?;
+        Ok((secret, initial))
+    }
+
+    /// Run the responder side of X3DH for an incoming initial message
+    ///
+    /// The one-time prekey named in the header is left in `prekeys`, to be removed
+    /// once the first message of the session decrypts.
+    pub fn x3dh_respond(
+        identity: &IdentityKeyPair,
+        prekeys: &PreKeyStore,
+        initial: &InitialMessage,
+    ) -> Result<[u8; 32], CryptoError> {
+        if initial.salt.len() != SALT_LEN {
+            return Err(CryptoError::InvalidHeader);
+        }
+        let signed_prekey = prekeys.signed_prekey_secret(initial.signed_prekey_id)?;
+
+        let mut dh = Vec::with_capacity(128);
+        dh.extend_from_slice(&Self::diffie_hellman(&signed_prekey, &initial.identity_key)?);
+        dh.extend_from_slice(&Self::diffie_hellman(&identity.secret_key(), &initial.ephemeral_key)?);
+        dh.extend_from_slice(&Self::diffie_hellman(&signed_prekey, &initial.ephemeral_key)?);
+        if let Some(id) = initial.one_time_prekey_id {
+            let one_time_prekey = prekeys.one_time_prekey_secret(id).ok_or(CryptoError::UnknownOneTimePreKey)?;
+            dh.extend_from_slice(&Self::diffie_hellman(&one_time_prekey, &initial.ephemeral_key)?);
+        }
+
+        let signed_prekey = signed_prekey.public_key().to_encoded_point(false);
+        Self::x3dh_kdf(&dh, initial, identity.public_key(), signed_prekey.as_bytes())
     }
 
-    /// Get the shared secret for storage or external use
-    pub fn get_shared_secret(&self) -> [u8; 32] {
-        self.shared_secret.expect("Shared secret not derived")
+    /// ECDH between a static secret and an encoded peer public key
+    pub(crate) fn diffie_hellman(secret: &SecretKey, peer_public_key: &[u8]) -> Result<[u8; 32], CryptoError> {
+        let peer_key = PublicKey::from_sec1_bytes(peer_public_key).map_err(|_| CryptoError::InvalidPublicKey)?;
+        let shared_secret = p256::elliptic_curve::ecdh::diffie_hellman(secret.to_nonzero_scalar(), peer_key.as_affine());
+
+        let mut secret_bytes = [0u8; 32];
+        secret_bytes.copy_from_slice(shared_secret.as_bytes());
+        Ok(secret_bytes)
     }
 
-    /// Create a symmetric encryption key from a shared secret
-    pub fn create_symmetric_key(shared_secret: &[u8; 32]) -> LessSafeKey {
-        let salt = Salt::new(HKDF_SHA256, b"example-salt");
+    /// Combine the X3DH DH outputs into a single shared secret
+    ///
+    /// The secret is bound to both identity keys, the signed prekey and the initial message.
+    fn x3dh_kdf(
+        dh: &[u8],
+        initial: &InitialMessage,
+        responder_identity_key: &[u8],
+        signed_prekey: &[u8],
+    ) -> Result<[u8; 32], CryptoError> {
+        // Prefix 32 0xFF bytes as in the X3DH spec
+        let input = [&[0xFFu8; 32][..], dh].concat();
+        let one_time_prekey_id = initial
+            .one_time_prekey_id
+            .map(|id| id.to_be_bytes().to_vec())
+            .unwrap_or_default();
+        let transcript = [
+            initial.identity_key.as_slice(),
+            responder_identity_key,
+            signed_prekey,
+            &initial.ephemeral_key,
+            &one_time_prekey_id,
+        ]
+        .concat();
+
+        KeySchedule::new(&input, &initial.salt, &transcript).derive("x3dh", &initial.identity_key, responder_identity_key)
+    }
+
+    /// Create a symmetric encryption key from key material, bound to a `salt` and purpose `label`
+    pub fn create_symmetric_key(key_material: &[u8; 32], salt: &[u8], label: &str) -> Result<LessSafeKey, CryptoError> {
+        let info = [INFO_PREFIX, label.as_bytes()].concat();
         let mut okm = [0u8; 32];
-        salt.extract(shared_secret)
-            .expand(&[], &AES_256_GCM)
-            .unwrap()
-            .fill(&mut okm)
-            .unwrap();
+        Salt::new(HKDF_SHA256, salt)
+            .extract(key_material)
+            .expand(&[&info], &AES_256_GCM)
+            .and_then(|prk| prk.fill(&mut okm))
+            .map_err(|_| CryptoError::EncryptionFailed)?;
 
-        let unbound_key = UnboundKey::new(&AES_256_GCM, &okm).unwrap();
-        LessSafeKey::new(unbound_key)
+        let unbound_key = UnboundKey::new(&AES_256_GCM, &okm).map_err(|_| CryptoError::EncryptionFailed)?;
+        Ok(LessSafeKey::new(unbound_key))
     }
 
     /// Encrypt plaintext with a specified symmetric key
-    pub fn encrypt_with_key(key: &LessSafeKey, plaintext: &[u8]) -> Vec<u8> {
-        let nonce = Self::generate_nonce();
+    pub fn encrypt_with_key(key: &LessSafeKey, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Self::encrypt_with_aad(key, plaintext, &[])
+    }
+
+    /// Encrypt plaintext, binding `associated_data` into the authentication tag
+    pub fn encrypt_with_aad(
+        key: &LessSafeKey,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let nonce = Self::generate_nonce()?;
         let nonce_slice = nonce.as_ref().to_vec();
 
         // Encrypt the plaintext
         let mut ciphertext = plaintext.to_vec();
-        key.seal_in_place_append_tag(nonce, Aad::empty(), &mut ciphertext)
-            .expect("Encryption failed");
+        key.seal_in_place_append_tag(nonce, Aad::from(associated_data), &mut ciphertext)
+            .map_err(|_| CryptoError::EncryptionFailed)?;
 
         // Combine nonce and ciphertext
-        [nonce_slice, ciphertext].concat()
+        Ok([nonce_slice, ciphertext].concat())
     }
 
     /// Decrypt ciphertext with a specified symmetric key
-    pub fn decrypt_with_key(key: &LessSafeKey, ciphertext: &[u8]) -> Vec<u8> {
+    pub fn decrypt_with_key(key: &LessSafeKey, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Self::decrypt_with_aad(key, ciphertext, &[])
+    }
+
+    /// Decrypt ciphertext, failing unless `associated_data` matches what it was encrypted with
+    pub fn decrypt_with_aad(
+        key: &LessSafeKey,
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if ciphertext.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+            return Err(CryptoError::CiphertextTooShort);
+        }
+
         // Split nonce and ciphertext
-        let (nonce_bytes, ciphertext) = ciphertext.split_at(12);
-        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes.try_into().unwrap()).unwrap();
+        let (nonce_bytes, ciphertext) = ciphertext.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).map_err(|_| CryptoError::CiphertextTooShort)?;
 
         let mut ciphertext = ciphertext.to_vec();
         let plaintext_len = key
-            .open_in_place(nonce, Aad::empty(), &mut ciphertext)
-            .expect("Decryption failed")
+            .open_in_place(nonce, Aad::from(associated_data), &mut ciphertext)
+            .map_err(|_| CryptoError::DecryptionFailed)?
             .len();
 
         ciphertext.truncate(plaintext_len);
-        ciphertext
+        Ok(ciphertext)
     }
 
     /// Generate a secure random nonce
-    fn generate_nonce() -> Nonce {
-        let mut nonce_bytes = [0u8; 12];
-        SystemRandom::new().fill(&mut nonce_bytes).unwrap();
-        Nonce::assume_unique_for_key(nonce_bytes)
+    fn generate_nonce() -> Result<Nonce, CryptoError> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce_bytes).map_err(|_| CryptoError::RandomFailed)?;
+        Ok(Nonce::assume_unique_for_key(nonce_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(identity: &IdentityKeyPair, prekeys: &mut PreKeyStore, one_time: bool) -> PreKeyBundle {
+        PreKeyBundle {
+            identity_key: identity.public_key().to_vec(),
+            signed_prekey_id: prekeys.signed_prekey_id(),
+            signed_prekey: prekeys.signed_prekey().unwrap(),
+            signed_prekey_signature: prekeys.signed_prekey_signature().to_vec(),
+            one_time_prekey: if one_time { prekeys.publish_one_time_prekeys().pop() } else { None },
+        }
+    }
+
+    fn key() -> LessSafeKey {
+        Crypto::create_symmetric_key(&[7; 32], b"salt", "test").unwrap()
+    }
+
+    #[test]
+    fn x3dh_agrees_with_and_without_a_one_time_prekey() {
+        for one_time in [true, false] {
+            let alice = IdentityKeyPair::generate();
+            let bob = IdentityKeyPair::generate();
+            let mut prekeys = PreKeyStore::generate(&bob, 3);
+
+            let (secret, initial) = Crypto::x3dh_initiate(&alice, &bundle(&bob, &mut prekeys, one_time)).unwrap();
+            assert_eq!(initial.one_time_prekey_id.is_some(), one_time);
+            assert_eq!(Crypto::x3dh_respond(&bob, &prekeys, &initial).unwrap(), secret);
+        }
+    }
+
+    #[test]
+    fn x3dh_refuses_bundles_not_signed_by_their_identity() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let mut prekeys = PreKeyStore::generate(&bob, 1);
+
+        let mut forged = bundle(&bob, &mut prekeys, true);
+        forged.identity_key = IdentityKeyPair::generate().public_key().to_vec();
+        assert_eq!(Crypto::x3dh_initiate(&alice, &forged).err(), Some(CryptoError::InvalidSignature));
+
+        let mut forged = bundle(&bob, &mut prekeys, false);
+        forged.signed_prekey = Crypto::new().public_key().to_vec();
+        assert_eq!(Crypto::x3dh_initiate(&alice, &forged).err(), Some(CryptoError::InvalidSignature));
+    }
+
+    #[test]
+    fn tampered_initial_messages_give_a_different_secret_or_an_error() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let mut prekeys = PreKeyStore::generate(&bob, 0);
+        let (secret, initial) = Crypto::x3dh_initiate(&alice, &bundle(&bob, &mut prekeys, false)).unwrap();
+
+        let mut tampered = initial.clone();
+        tampered.salt[0] ^= 1;
+        assert_ne!(Crypto::x3dh_respond(&bob, &prekeys, &tampered).unwrap(), secret);
+
+        let mut tampered = initial.clone();
+        tampered.identity_key = IdentityKeyPair::generate().public_key().to_vec();
+        assert_ne!(Crypto::x3dh_respond(&bob, &prekeys, &tampered).unwrap(), secret);
+
+        let mut tampered = initial.clone();
+        tampered.salt.pop();
+        assert_eq!(Crypto::x3dh_respond(&bob, &prekeys, &tampered), Err(CryptoError::InvalidHeader));
+
+        let mut tampered = initial.clone();
+        tampered.ephemeral_key[1] ^= 1;
+        assert_eq!(Crypto::x3dh_respond(&bob, &prekeys, &tampered), Err(CryptoError::InvalidPublicKey));
+
+        let mut tampered = initial.clone();
+        tampered.signed_prekey_id += 1;
+        assert_eq!(Crypto::x3dh_respond(&bob, &prekeys, &tampered), Err(CryptoError::UnknownSignedPreKey));
+
+        let mut tampered = initial;
+        tampered.one_time_prekey_id = Some(99);
+        assert_eq!(Crypto::x3dh_respond(&bob, &prekeys, &tampered), Err(CryptoError::UnknownOneTimePreKey));
+    }
+
+    #[test]
+    fn ecdh_agrees_and_refuses_invalid_points() {
+        let alice = Crypto::new();
+        let bob = Crypto::new();
+        assert_eq!(alice.derive_session_key(bob.public_key()), bob.derive_session_key(alice.public_key()));
+
+        let mut off_curve = bob.public_key().to_vec();
+        off_curve[64] ^= 1;
+        for invalid in [&[][..], &[4; 65], &bob.public_key()[..33], &off_curve] {
+            assert_eq!(alice.derive_session_key(invalid), Err(CryptoError::InvalidPublicKey));
+        }
+    }
+
+    #[test]
+    fn key_schedule_separates_labels_directions_and_transcripts() {
+        let schedule = KeySchedule::new(&[1; 32], b"salt", b"transcript");
+        let key = schedule.derive("message", b"alice", b"bob").unwrap();
+
+        assert_eq!(KeySchedule::new(&[1; 32], b"salt", b"transcript").derive("message", b"alice", b"bob").unwrap(), key);
+        assert_ne!(schedule.derive("message", b"bob", b"alice").unwrap(), key);
+        assert_ne!(schedule.derive("header", b"alice", b"bob").unwrap(), key);
+        assert_ne!(KeySchedule::new(&[1; 32], b"salt", b"other").derive("message", b"alice", b"bob").unwrap(), key);
+        assert_ne!(KeySchedule::new(&[1; 32], b"pepper", b"transcript").derive("message", b"alice", b"bob").unwrap(), key);
+    }
+
+    #[test]
+    fn aead_round_trips_with_fresh_nonces() {
+        let key = key();
+        let first = Crypto::encrypt_with_aad(&key, b"plaintext", b"header").unwrap();
+        let second = Crypto::encrypt_with_aad(&key, b"plaintext", b"header").unwrap();
+        assert_ne!(first, second);
+
+        assert_eq!(Crypto::decrypt_with_aad(&key, &first, b"header").unwrap(), b"plaintext");
+        let empty = Crypto::encrypt_with_key(&key, b"").unwrap();
+        assert_eq!(Crypto::decrypt_with_key(&key, &empty).unwrap(), b"");
+    }
+
+    #[test]
+    fn aead_rejects_tampering_and_garbage() {
+        let key = key();
+        let ciphertext = Crypto::encrypt_with_aad(&key, b"plaintext", b"header").unwrap();
+
+        for index in [0, NONCE_LEN, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[index] ^= 1;
+            assert_eq!(Crypto::decrypt_with_aad(&key, &tampered, b"header"), Err(CryptoError::DecryptionFailed));
+        }
+        assert_eq!(Crypto::decrypt_with_aad(&key, &ciphertext, b"other"), Err(CryptoError::DecryptionFailed));
+        let other_key = Crypto::create_symmetric_key(&[7; 32], b"salt", "other").unwrap();
+        assert_eq!(Crypto::decrypt_with_aad(&other_key, &ciphertext, b"header"), Err(CryptoError::DecryptionFailed));
+
+        for len in [0, NONCE_LEN, NONCE_LEN + AES_256_GCM.tag_len() - 1] {
+            assert_eq!(Crypto::decrypt_with_key(&key, &vec![0; len]), Err(CryptoError::CiphertextTooShort));
+        }
+    }
+
+    #[test]
+    fn associated_data_fields_cannot_run_together() {
+        let split = |sender, recipient| AssociatedData { version: 1, sender, recipient, message_id: "1" }.to_bytes();
+        assert_ne!(split("ab", "c"), split("a", "bc"));
     }
 }
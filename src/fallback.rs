@@ -1,45 +1,408 @@
+use crate::accounts::UserRegistry;
+use crate::identity::IdentityKeyPair;
+use crate::mailbox::{Mailbox, QueueError};
+use crate::prekeys::InitialMessage;
+use crate::protocol::{ciphertext, Envelope, ServerMessage};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, Mutex};
+use warp::http::{HeaderMap, StatusCode};
+use warp::reply::{Json, WithStatus};
 use warp::Filter;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use uuid::Uuid;
 
+/// Largest request body the mailbox accepts
+const MAX_BODY_LEN: u64 = 1024 * 1024;
+
+/// How far a signed request's timestamp may be from the server's clock, in seconds
+const MAX_CLOCK_SKEW: u64 = 300;
+
+/// Longest nonce a signed request may carry
+const MAX_NONCE_LEN: usize = 64;
+
+/// Relay state the HTTP mailbox shares with the WebSocket relay
 #[derive(Clone)]
 pub struct ServerState {
-    pub messages: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    pub accounts: Arc<Mutex<UserRegistry>>,
+    pub clients: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<ServerMessage>>>>,
+    pub mailbox: Arc<Mutex<Mailbox>>,
+    pub nonces: Arc<Mutex<SeenNonces>>,
 }
 
-pub async fn run_server() {
-    let state = ServerState {
-        messages: Arc::new(Mutex::new(HashMap::new())),
-    };
+/// Nonces of signed requests recent enough to pass the timestamp check, so none is accepted twice
+#[derive(Default)]
+pub struct SeenNonces {
+    /// Username and nonce of each request, oldest first, with when it arrived
+    order: VecDeque<(u64, (String, String))>,
+    seen: HashSet<(String, String)>,
+}
+
+impl SeenNonces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a request's nonce, returning false if the user has already sent it
+    fn insert(&mut self, username: &str, nonce: &str) -> bool {
+        // A timestamp passes for at most twice the skew after the request first arrived
+        let now = unix_time();
+        while self.order.front().is_some_and(|(arrived, _)| now.saturating_sub(*arrived) > 2 * MAX_CLOCK_SKEW) {
+            if let Some((_, key)) = self.order.pop_front() {
+                self.seen.remove(&key);
+            }
+        }
+
+        let key = (username.to_string(), nonce.to_string());
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+        self.order.push_back((now, key));
+        true
+    }
+}
+
+/// Envelope posted to the mailbox; the sender comes from the request signature
+#[derive(Deserialize)]
+struct PostedEnvelope {
+    to: String,
+    #[serde(with = "ciphertext")]
+    message: Vec<u8>,
+    message_id: String,
+    version: u8,
+    #[serde(default)]
+    initial: Option<InitialMessage>,
+}
+
+#[derive(Deserialize)]
+struct Ack {
+    ids: Vec<u64>,
+}
 
+/// HTTP mailbox for clients without a live WebSocket
+///
+/// - `POST /mailbox` relays an envelope, queueing it if the recipient is offline
+/// - `GET /mailbox` lists the envelopes waiting for the caller
+/// - `POST /mailbox/ack` with `{"ids": [..]}` removes envelopes the caller has stored
+///
+/// Every request is signed with the caller's identity key, see `sign_request`.
+/// A signed request is only accepted once.
+pub fn routes(state: ServerState) -> impl Filter<Extract = (WithStatus<Json>,), Error = warp::Rejection> + Clone {
     let state_filter = warp::any().map(move || state.clone());
 
-    let send_message = warp::post()
-        .and(warp::path("send"))
-        .and(warp::body::json())
+    let post_envelope = warp::post()
+        .and(warp::path!("mailbox"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(MAX_BODY_LEN))
+        .and(warp::body::bytes())
         .and(state_filter.clone())
-        .map(|msg: String, state: ServerState| {
-            let id = Uuid::new_v4().to_string();
-            state.messages.lock().unwrap().insert(id.clone(), msg.into_bytes());
-            warp::reply::json(&id)
-        });
-
-    let retrieve_message = warp::get()
-        .and(warp::path("receive"))
-        .and(warp::path::param())
+        .and_then(post_envelope);
+
+    let get_pending = warp::get()
+        .and(warp::path!("mailbox"))
+        .and(warp::header::headers_cloned())
+        .and(state_filter.clone())
+        .and_then(get_pending);
+
+    let ack = warp::post()
+        .and(warp::path!("mailbox" / "ack"))
+        .and(warp::header::headers_cloned())
+        .and(warp::body::content_length_limit(MAX_BODY_LEN))
+        .and(warp::body::bytes())
         .and(state_filter)
-        .map(|id: String, state: ServerState| {
-            let mut messages = state.messages.lock().unwrap();
-            if let Some(msg) = messages.remove(&id) {
-                warp::reply::json(&String::from_utf8(msg).unwrap())
-            } else {
-                warp::reply::json(&"Message not found")
-            }
-        });
+        .and_then(ack);
 
-    let routes = send_message.or(retrieve_message);
+    post_envelope.or(get_pending).unify().or(ack).unify()
+}
+
+/// Build the message a client signs to authenticate a mailbox request
+pub fn request_auth_message(username: &str, timestamp: u64, nonce: &str, method: &str, path: &str, body: &[u8]) -> Vec<u8> {
+    [
+        b"p2p_sparse_messaging mailbox".as_slice(),
+        username.as_bytes(),
+        b"\n",
+        &timestamp.to_be_bytes(),
+        nonce.as_bytes(),
+        b"\n",
+        method.as_bytes(),
+        b" ",
+        path.as_bytes(),
+        digest(&SHA256, body).as_ref(),
+    ]
+    .concat()
+}
+
+/// Headers authenticating a request to the relay's HTTP endpoints as `username`
+pub fn sign_request(
+    identity: &IdentityKeyPair,
+    username: &str,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Vec<(&'static str, String)> {
+    let timestamp = unix_time();
+    let nonce = uuid::Uuid::new_v4().to_string();
+    let signature = identity.sign(&request_auth_message(username, timestamp, &nonce, method, path, body));
+    vec![
+        ("x-username", username.to_string()),
+        ("x-timestamp", timestamp.to_string()),
+        ("x-nonce", nonce),
+        ("x-signature", BASE64.encode(signature)),
+    ]
+}
+
+/// Check a request's signature against the identity key bound to its username, and that it isn't a replay
+pub(crate) async fn authenticate(
+    accounts: &Mutex<UserRegistry>,
+    nonces: &Mutex<SeenNonces>,
+    headers: &HeaderMap,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<String, StatusCode> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let (Some(username), Some(timestamp), Some(nonce), Some(signature)) =
+        (header("x-username"), header("x-timestamp"), header("x-nonce"), header("x-signature"))
+    else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    let timestamp: u64 = timestamp.parse().map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let signature = BASE64.decode(signature).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    if unix_time().abs_diff(timestamp) > MAX_CLOCK_SKEW || nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let accounts = accounts.lock().await;
+    let account = accounts.get(username).ok_or(StatusCode::UNAUTHORIZED)?;
+    let message = request_auth_message(username, timestamp, nonce, method, path, body);
+    if !IdentityKeyPair::verify(&account.identity_key, &message, &signature) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    // Only signed requests count, so nobody else can use up a user's nonces
+    if !nonces.lock().await.insert(username, nonce) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(username.to_string())
+}
+
+async fn post_envelope(
+    headers: HeaderMap,
+    body: warp::hyper::body::Bytes,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let from = match authenticate(&state.accounts, &state.nonces, &headers, "POST", "/mailbox", &body).await {
+        Ok(from) => from,
+        Err(status) => return Ok(error(status, "invalid request signature")),
+    };
+    let Ok(posted) = serde_json::from_slice::<PostedEnvelope>(&body) else {
+        return Ok(error(StatusCode::BAD_REQUEST, "malformed envelope"));
+    };
+
+    let envelope = Envelope {
+        from: from.clone(),
+        to: posted.to.clone(),
+        message: posted.message,
+        message_id: posted.message_id,
+        version: posted.version,
+        initial: posted.initial,
+        group: None,
+        mls: None,
+    };
+
+    // Hand the envelope straight to the recipient if they're connected
+    let clients = state.clients.lock().await;
+    if let Some(recipient_tx) = clients.get(&posted.to) {
+        let delivery = ServerMessage::Deliver { envelope: envelope.clone() };
+        if recipient_tx.send(delivery).is_ok() {
+            println!("Mailbox message delivered from {} to {}", from, posted.to);
+            return Ok(reply(StatusCode::OK, json!({ "status": "delivered" })));
+        }
+    }
+
+    if state.accounts.lock().await.get(&posted.to).is_none() {
+        return Ok(error(StatusCode::NOT_FOUND, "unknown recipient"));
+    }
+    match state.mailbox.lock().await.push(&posted.to, envelope) {
+        Ok(id) => {
+            println!("Mailbox message queued from {} for {}", from, posted.to);
+            Ok(reply(StatusCode::ACCEPTED, json!({ "status": "queued", "id": id })))
+        }
+        Err(QueueError::QuotaExceeded) => Ok(error(StatusCode::TOO_MANY_REQUESTS, "recipient's queue is full")),
+    }
+}
+
+async fn get_pending(headers: HeaderMap, state: ServerState) -> Result<WithStatus<Json>, Infallible> {
+    let username = match authenticate(&state.accounts, &state.nonces, &headers, "GET", "/mailbox", &[]).await {
+        Ok(username) => username,
+        Err(status) => return Ok(error(status, "invalid request signature")),
+    };
+
+    let pending = state.mailbox.lock().await.pending(&username);
+    Ok(reply(StatusCode::OK, json!(pending)))
+}
+
+async fn ack(
+    headers: HeaderMap,
+    body: warp::hyper::body::Bytes,
+    state: ServerState,
+) -> Result<WithStatus<Json>, Infallible> {
+    let username = match authenticate(&state.accounts, &state.nonces, &headers, "POST", "/mailbox/ack", &body).await {
+        Ok(username) => username,
+        Err(status) => return Ok(error(status, "invalid request signature")),
+    };
+    let Ok(ack) = serde_json::from_slice::<Ack>(&body) else {
+        return Ok(error(StatusCode::BAD_REQUEST, "malformed ack"));
+    };
+
+    let removed = state.mailbox.lock().await.ack(&username, &ack.ids);
+    Ok(reply(StatusCode::OK, json!({ "removed": removed })))
+}
+
+pub(crate) fn reply(status: StatusCode, body: serde_json::Value) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+pub(crate) fn error(status: StatusCode, reason: &str) -> WithStatus<Json> {
+    reply(status, json!({ "error": reason }))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::{challenge_message, new_challenge};
+    use crate::mailbox::PendingEnvelope;
+
+    async fn state_with(users: &[(&str, &IdentityKeyPair)]) -> ServerState {
+        let mut accounts = UserRegistry::default();
+        for (username, identity) in users {
+            let challenge = new_challenge().unwrap();
+            let response = identity.sign(&challenge_message(username, &challenge));
+            accounts.login(username, identity.public_key(), &challenge, &response).unwrap();
+        }
+        ServerState {
+            accounts: Arc::new(Mutex::new(accounts)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            mailbox: Arc::new(Mutex::new(Mailbox::new(10, std::time::Duration::from_secs(60)))),
+            nonces: Arc::new(Mutex::new(SeenNonces::new())),
+        }
+    }
+
+    fn get_mailbox(headers: &[(&'static str, String)]) -> warp::test::RequestBuilder {
+        headers
+            .iter()
+            .fold(warp::test::request().method("GET").path("/mailbox"), |request, (name, value)| request.header(*name, value))
+    }
+
+    fn signed(identity: &IdentityKeyPair, username: &str, method: &str, path: &str, body: &[u8]) -> warp::test::RequestBuilder {
+        sign_request(identity, username, method, path, body)
+            .iter()
+            .fold(warp::test::request().method(method).path(path).body(body), |request, (name, value)| {
+                request.header(*name, value)
+            })
+    }
+
+    #[tokio::test]
+    async fn posted_envelopes_are_fetched_and_acknowledged() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let state = state_with(&[("alice", &alice), ("bob", &bob)]).await;
+        let routes = routes(state.clone());
+
+        // The sender is whoever signed the request, not anything in the body
+        let body = json!({ "to": "bob", "from": "mallory", "message": BASE64.encode(b"sealed"), "message_id": "1", "version": 1 });
+        let body = serde_json::to_vec(&body).unwrap();
+        let response = signed(&alice, "alice", "POST", "/mailbox", &body).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+        let response = signed(&bob, "bob", "GET", "/mailbox", &[]).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let pending: Vec<PendingEnvelope> = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(pending.len(), 1);
+        let envelope = &pending[0].envelope;
+        assert_eq!((envelope.from.as_str(), envelope.to.as_str(), envelope.message_id.as_str()), ("alice", "bob", "1"));
+        assert_eq!(envelope.message, b"sealed");
+
+        // Only the recipient's own queue is listed
+        let response = signed(&alice, "alice", "GET", "/mailbox", &[]).reply(&routes).await;
+        assert_eq!(response.body().as_ref(), b"[]");
+
+        let ack = serde_json::to_vec(&json!({ "ids": [pending[0].id] })).unwrap();
+        let response = signed(&bob, "bob", "POST", "/mailbox/ack", &ack).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = signed(&bob, "bob", "GET", "/mailbox", &[]).reply(&routes).await;
+        assert_eq!(response.body().as_ref(), b"[]");
+
+        // A connected recipient gets the envelope straight away
+        let (bob_tx, mut bob_rx) = mpsc::unbounded_channel();
+        state.clients.lock().await.insert("bob".to_string(), bob_tx);
+        let response = signed(&alice, "alice", "POST", "/mailbox", &body).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let Ok(ServerMessage::Deliver { envelope }) = bob_rx.try_recv() else {
+            panic!("envelope wasn't delivered");
+        };
+        assert_eq!((envelope.from.as_str(), envelope.message.as_slice()), ("alice", b"sealed".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn posts_are_checked_for_signer_and_recipient() {
+        let alice = IdentityKeyPair::generate();
+        let routes = routes(state_with(&[("alice", &alice)]).await);
+        let body = |to: &str| serde_json::to_vec(&json!({ "to": to, "message": "", "message_id": "1", "version": 1 })).unwrap();
+
+        let response = signed(&alice, "alice", "POST", "/mailbox", &body("carol")).reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // The signature covers the body
+        let headers = sign_request(&alice, "alice", "POST", "/mailbox", &body("carol"));
+        let request = headers
+            .iter()
+            .fold(warp::test::request().method("POST").path("/mailbox").body(body("alice")), |request, (name, value)| {
+                request.header(*name, value)
+            });
+        assert_eq!(request.reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+
+        let response = signed(&alice, "alice", "POST", "/mailbox", b"not json").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn signed_requests_are_accepted_once() {
+        let identity = IdentityKeyPair::generate();
+        let routes = routes(state_with(&[("alice", &identity)]).await);
+
+        let headers = sign_request(&identity, "alice", "GET", "/mailbox", &[]);
+        assert_eq!(get_mailbox(&headers).reply(&routes).await.status(), StatusCode::OK);
+        assert_eq!(get_mailbox(&headers).reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+
+        // A fresh signature carries a fresh nonce
+        let headers = sign_request(&identity, "alice", "GET", "/mailbox", &[]);
+        assert_eq!(get_mailbox(&headers).reply(&routes).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn nonces_are_bound_to_the_signature() {
+        let identity = IdentityKeyPair::generate();
+        let routes = routes(state_with(&[("alice", &identity)]).await);
+
+        let mut headers = sign_request(&identity, "alice", "GET", "/mailbox", &[]);
+        for (name, value) in headers.iter_mut() {
+            if *name == "x-nonce" {
+                *value = "another nonce".to_string();
+            }
+        }
+        assert_eq!(get_mailbox(&headers).reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
 
-    println!("Fallback server running on 127.0.0.1:3030");
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+        headers.retain(|(name, _)| *name != "x-nonce");
+        assert_eq!(get_mailbox(&headers).reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+    }
 }
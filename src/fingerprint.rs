@@ -0,0 +1,84 @@
+use ring::digest::{Context, SHA512};
+
+/// Version of the fingerprint format, hashed into every fingerprint
+const FINGERPRINT_VERSION: u16 = 0;
+
+/// Hash iterations, making it expensive to grind out colliding keys
+const ITERATIONS: usize = 5200;
+
+/// Number of 5-digit groups in one party's fingerprint
+const GROUPS: usize = 6;
+
+/// Derive a 30-digit numeric fingerprint for a single identity key
+pub fn fingerprint(identity_key: &[u8]) -> String {
+    let mut context = Context::new(&SHA512);
+    context.update(&FINGERPRINT_VERSION.to_be_bytes());
+    context.update(identity_key);
+    let mut hash = context.finish().as_ref().to_vec();
+
+    for _ in 1..ITERATIONS {
+        let mut context = Context::new(&SHA512);
+        context.update(&hash);
+        context.update(identity_key);
+        hash = context.finish().as_ref().to_vec();
+    }
+
+    // Each 5-byte chunk becomes a 5-digit group
+    hash.chunks(5)
+        .take(GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+/// Derive the safety number two parties compare to check their identity keys
+///
+/// Both sides get the same number, as the two fingerprints are sorted before joining.
+pub fn safety_number(own_identity_key: &[u8], peer_identity_key: &[u8]) -> String {
+    let mut fingerprints = [fingerprint(own_identity_key), fingerprint(peer_identity_key)];
+    fingerprints.sort();
+    let digits = fingerprints.concat();
+
+    // Show as 12 groups of 5 digits, 4 groups per line
+    digits
+        .as_bytes()
+        .chunks(5)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .chunks(4)
+        .map(|line| line.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::IdentityKeyPair;
+
+    #[test]
+    fn both_sides_get_the_same_safety_number() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let number = safety_number(alice.public_key(), bob.public_key());
+        assert_eq!(number, safety_number(bob.public_key(), alice.public_key()));
+
+        // 3 lines of 4 groups of 5 digits
+        let lines: Vec<_> = number.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() == 23));
+        assert!(number.chars().all(|c| c.is_ascii_digit() || c == ' ' || c == '\n'));
+    }
+
+    #[test]
+    fn safety_numbers_change_with_either_key() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let mallory = IdentityKeyPair::generate();
+        let number = safety_number(alice.public_key(), bob.public_key());
+        assert_ne!(number, safety_number(mallory.public_key(), bob.public_key()));
+        assert_ne!(number, safety_number(alice.public_key(), mallory.public_key()));
+    }
+}
@@ -0,0 +1,161 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Version byte carried by every frame
+pub const FRAME_VERSION: u8 = 1;
+
+/// Largest payload a frame may carry
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Bytes in a frame header: 4-byte length, version and type
+const FRAME_HEADER_LEN: usize = 6;
+
+/// Kinds of frame sent over a direct P2P link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameType {
+    /// JSON-encoded `Envelope`, encrypted with the link's transport key
+    Envelope = 1,
+    /// Handshake message, sent before any envelope
+    Handshake = 2,
+    /// Message ID of an envelope the peer has received, encrypted like envelopes
+    Ack = 3,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            1 => Ok(FrameType::Envelope),
+            2 => Ok(FrameType::Handshake),
+            3 => Ok(FrameType::Ack),
+            _ => Err(invalid_data(format!("unknown frame type {}", value))),
+        }
+    }
+}
+
+/// A single message on a framed stream
+///
+/// On the wire: payload length (u32, big-endian), version, type, payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(frame_type: FrameType, payload: Vec<u8>) -> Self {
+        Frame { frame_type, payload }
+    }
+}
+
+/// Read the next frame, returning `None` if the stream ended cleanly between frames
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    match reader.read_exact(&mut header[..1]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut header[1..]).await?;
+
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if header[4] != FRAME_VERSION {
+        return Err(invalid_data(format!("unsupported frame version {}", header[4])));
+    }
+    let frame_type = FrameType::try_from(header[5])?;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("frame of {} bytes exceeds the limit", len)));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some(Frame { frame_type, payload }))
+}
+
+/// Write a frame and flush it
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    if frame.payload.len() > MAX_FRAME_LEN {
+        return Err(invalid_data(format!("frame of {} bytes exceeds the limit", frame.payload.len())));
+    }
+
+    let mut bytes = Vec::with_capacity(FRAME_HEADER_LEN + frame.payload.len());
+    bytes.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+    bytes.push(FRAME_VERSION);
+    bytes.push(frame.frame_type as u8);
+    bytes.extend_from_slice(&frame.payload);
+    writer.write_all(&bytes).await?;
+    writer.flush().await
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn encode(frames: &[Frame]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for frame in frames {
+            write_frame(&mut bytes, frame).await.unwrap();
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip_in_order_then_end_cleanly() {
+        let frames = [
+            Frame::new(FrameType::Handshake, b"hello".to_vec()),
+            Frame::new(FrameType::Envelope, Vec::new()),
+            Frame::new(FrameType::Ack, vec![0xff; MAX_FRAME_LEN]),
+        ];
+        let bytes = encode(&frames).await;
+
+        let mut reader = &bytes[..];
+        for frame in &frames {
+            assert_eq!(read_frame(&mut reader).await.unwrap().as_ref(), Some(frame));
+        }
+        assert_eq!(read_frame(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn unknown_versions_and_types_are_refused() {
+        let bytes = encode(&[Frame::new(FrameType::Envelope, b"payload".to_vec())]).await;
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = FRAME_VERSION + 1;
+        let mut bad_type = bytes;
+        bad_type[5] = 0;
+        for bytes in [bad_version, bad_type] {
+            let error = read_frame(&mut &bytes[..]).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_both_ways() {
+        let mut writer = Vec::new();
+        let oversized = Frame::new(FrameType::Envelope, vec![0; MAX_FRAME_LEN + 1]);
+        let error = write_frame(&mut writer, &oversized).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(writer.is_empty());
+
+        let mut header = ((MAX_FRAME_LEN + 1) as u32).to_be_bytes().to_vec();
+        header.extend_from_slice(&[FRAME_VERSION, FrameType::Envelope as u8]);
+        let error = read_frame(&mut &header[..]).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_errors_not_clean_ends() {
+        let bytes = encode(&[Frame::new(FrameType::Envelope, b"payload".to_vec())]).await;
+
+        for len in [1, FRAME_HEADER_LEN - 1, FRAME_HEADER_LEN, bytes.len() - 1] {
+            let error = read_frame(&mut &bytes[..len]).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+}
@@ -0,0 +1,587 @@
+use crate::crypto::{AssociatedData, Crypto, CryptoError};
+use crate::identity::IdentityKeyPair;
+use crate::session::kdf_chain;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Maximum number of message keys kept for group messages that haven't arrived yet
+const MAX_SKIP: u32 = 1000;
+
+/// How long a member's replaced sender key still decrypts messages sent before the change
+const PREVIOUS_KEY_TTL: Duration = Duration::from_secs(120);
+
+/// HKDF label turning a sender chain's message key into an AES-256-GCM key
+const MESSAGE_KEY_LABEL: &str = "sender key message";
+
+/// A member's sender key, handed to the rest of the group over pairwise sessions
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SenderKeyDistribution {
+    pub group_id: String,
+    pub key_id: u32,
+    pub iteration: u32,
+    pub chain_key: Vec<u8>,
+    pub signing_key: Vec<u8>,
+}
+
+/// A group's name, owner and members, signed by whoever made the change
+///
+/// The relay fans rosters out but can't forge one: members check the signature
+/// against the signer's pinned identity key before trusting the member list,
+/// and with it who gets their sender key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupRoster {
+    pub group_id: String,
+    pub name: String,
+    pub owner: String,
+    pub members: Vec<String>,
+    /// Increases with every change, so an older roster can't be replayed
+    pub version: u64,
+    /// The owner, or a member signing its own departure
+    pub signer: String,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl GroupRoster {
+    /// The first roster of a new group, signed by its owner
+    pub fn new(group_id: &str, name: &str, owner: &str, members: Vec<String>, identity: &IdentityKeyPair) -> Self {
+        let mut roster = GroupRoster {
+            group_id: group_id.to_string(),
+            name: name.to_string(),
+            owner: owner.to_string(),
+            members,
+            version: 0,
+            signer: owner.to_string(),
+            signature: Vec::new(),
+        };
+        roster.signature = identity.sign(&roster.signed_bytes());
+        roster
+    }
+
+    /// The roster after this one, with a new owner and members, signed by `signer`
+    pub fn next(&self, owner: &str, members: Vec<String>, signer: &str, identity: &IdentityKeyPair) -> Self {
+        let mut roster = GroupRoster {
+            owner: owner.to_string(),
+            members,
+            version: self.version + 1,
+            signer: signer.to_string(),
+            signature: Vec::new(),
+            ..self.clone()
+        };
+        roster.signature = identity.sign(&roster.signed_bytes());
+        roster
+    }
+
+    /// Check the signature against the signer's identity key
+    pub fn verify(&self, signer_key: &[u8]) -> bool {
+        IdentityKeyPair::verify(signer_key, &self.signed_bytes(), &self.signature)
+    }
+
+    /// Check the first roster of a group: signed by its owner, who is a member, and no one listed twice
+    pub fn is_well_formed(&self) -> bool {
+        let unique = self.members.iter().enumerate().all(|(i, member)| !self.members[..i].contains(member));
+        self.signer == self.owner && self.members.contains(&self.owner) && unique
+    }
+
+    /// Check this roster may replace `previous`
+    ///
+    /// It must be newer, and either come from the previous owner or only remove
+    /// the member who signed it. The owner hands the group on when it leaves.
+    pub fn follows(&self, previous: &GroupRoster) -> bool {
+        if self.group_id != previous.group_id || self.name != previous.name || self.version <= previous.version {
+            return false;
+        }
+        let unique = self.members.iter().enumerate().all(|(i, member)| !self.members[..i].contains(member));
+        if self.signer == previous.owner {
+            return unique && (self.members.is_empty() || self.members.contains(&self.owner));
+        }
+        let mut remaining = previous.members.clone();
+        remaining.retain(|member| *member != self.signer);
+        previous.members.contains(&self.signer) && self.owner == previous.owner && self.members == remaining
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut bytes = b"group roster".to_vec();
+        let members = self.members.iter().map(String::as_bytes);
+        let fields = [self.group_id.as_bytes(), self.name.as_bytes(), self.owner.as_bytes(), self.signer.as_bytes()];
+        for field in fields.into_iter().chain(members) {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes.extend_from_slice(&(self.members.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes
+    }
+}
+
+/// Group ciphertext, signed with the signing key of the sender key it was encrypted with
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupMessage {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl GroupMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Failed to encode group message")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CryptoError> {
+        serde_json::from_slice(bytes).map_err(|_| CryptoError::InvalidHeader)
+    }
+
+    /// Bytes covered by the signature: the key position, ciphertext and envelope metadata
+    fn signed_bytes(key_id: u32, iteration: u32, ciphertext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        [&key_id.to_be_bytes(), &iteration.to_be_bytes(), ciphertext, associated_data].concat()
+    }
+}
+
+/// Our sending chain in a group
+struct OwnSenderKey {
+    key_id: u32,
+    chain_key: [u8; 32],
+    iteration: u32,
+    signing_key: IdentityKeyPair,
+}
+
+impl OwnSenderKey {
+    fn generate(key_id: u32) -> Self {
+        let mut chain_key = [0u8; 32];
+        SystemRandom::new().fill(&mut chain_key).expect("Random number generator failed");
+        OwnSenderKey { key_id, chain_key, iteration: 0, signing_key: IdentityKeyPair::generate() }
+    }
+}
+
+/// Another member's sending chain, as far as we've followed it
+struct MemberSenderKey {
+    key_id: u32,
+    chain_key: [u8; 32],
+    iteration: u32,
+    signing_key: Vec<u8>,
+    skipped: HashMap<u32, [u8; 32]>,
+}
+
+/// A member's sender key that a newer one replaced, kept for messages still in flight
+struct RetiredSenderKey {
+    key: MemberSenderKey,
+    retired_at: Instant,
+}
+
+/// A group conversation and the Sender Keys used to encrypt to it
+///
+/// Each member encrypts with its own hash chain and hands the chain key to the
+/// others over pairwise sessions, so a message is encrypted once for the whole
+/// group. Changing the members rotates our chain and forgets the chains of
+/// removed members, so they can't read what's sent afterwards.
+///
+/// The members come from a `GroupRoster` the caller has verified.
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    pub owner: String,
+    roster: GroupRoster,
+    own_key: OwnSenderKey,
+    member_keys: HashMap<String, MemberSenderKey>,
+    /// Each member's sender key from before its last rotation
+    retired_keys: HashMap<String, RetiredSenderKey>,
+}
+
+impl Group {
+    pub fn new(roster: GroupRoster) -> Self {
+        Group {
+            id: roster.group_id.clone(),
+            name: roster.name.clone(),
+            owner: roster.owner.clone(),
+            roster,
+            own_key: OwnSenderKey::generate(0),
+            member_keys: HashMap::new(),
+            retired_keys: HashMap::new(),
+        }
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.roster.members
+    }
+
+    /// The roster the group's members were last taken from
+    pub fn roster(&self) -> &GroupRoster {
+        &self.roster
+    }
+
+    /// Move to a newer roster, rotating our sender key if the members changed
+    ///
+    /// Returns whether the members changed, in which case the new key must be
+    /// distributed to everyone still in the group.
+    pub fn set_roster(&mut self, roster: GroupRoster) -> bool {
+        let changed = roster.members != self.roster.members;
+        self.owner = roster.owner.clone();
+        self.roster = roster;
+        if !changed {
+            return false;
+        }
+        let members = &self.roster.members;
+        self.member_keys.retain(|member, _| members.contains(member));
+        self.retired_keys.retain(|member, _| members.contains(member));
+        self.rotate();
+        true
+    }
+
+    /// Start a new sending chain that earlier recipients of our key can't follow
+    pub fn rotate(&mut self) {
+        self.own_key = OwnSenderKey::generate(self.own_key.key_id.wrapping_add(1));
+    }
+
+    /// Our current sender key, for the other members
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        SenderKeyDistribution {
+            group_id: self.id.clone(),
+            key_id: self.own_key.key_id,
+            iteration: self.own_key.iteration,
+            chain_key: self.own_key.chain_key.to_vec(),
+            signing_key: self.own_key.signing_key.public_key().to_vec(),
+        }
+    }
+
+    /// Store a member's sender key
+    ///
+    /// A key with a new ID replaces the current one, which is kept for
+    /// `PREVIOUS_KEY_TTL` so messages sent just before the rotation still decrypt.
+    /// A key with the current ID is ignored unless it's further along the chain,
+    /// so a stale distribution can't rewind it and replay delivered messages.
+    pub fn add_sender_key(&mut self, sender: &str, distribution: &SenderKeyDistribution) -> Result<(), CryptoError> {
+        if !self.members().iter().any(|member| member == sender) || distribution.group_id != self.id {
+            return Err(CryptoError::NoSession);
+        }
+        let chain_key: [u8; 32] = distribution.chain_key.as_slice().try_into().map_err(|_| CryptoError::InvalidHeader)?;
+        if self
+            .member_keys
+            .get(sender)
+            .is_some_and(|current| current.key_id == distribution.key_id && current.iteration >= distribution.iteration)
+        {
+            return Ok(());
+        }
+        let key = MemberSenderKey {
+            key_id: distribution.key_id,
+            chain_key,
+            iteration: distribution.iteration,
+            signing_key: distribution.signing_key.clone(),
+            skipped: HashMap::new(),
+        };
+        if let Some(replaced) = self.member_keys.insert(sender.to_string(), key) {
+            if replaced.key_id != distribution.key_id {
+                let retired = RetiredSenderKey { key: replaced, retired_at: Instant::now() };
+                self.retired_keys.insert(sender.to_string(), retired);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether we can decrypt messages from `sender`
+    pub fn has_sender_key(&self, sender: &str) -> bool {
+        self.member_keys.contains_key(sender)
+    }
+
+    /// Encrypt a message to the group with the next key of our chain
+    pub fn encrypt(&mut self, plaintext: &[u8], associated_data: &AssociatedData) -> Result<GroupMessage, CryptoError> {
+        let (chain_key, message_key) = kdf_chain(&self.own_key.chain_key);
+        let iteration = self.own_key.iteration;
+        let aad = associated_data.to_bytes();
+
+        let key = Crypto::create_symmetric_key(&message_key, &[], MESSAGE_KEY_LABEL)?;
+        let ciphertext = Crypto::encrypt_with_aad(&key, plaintext, &aad)?;
+        let signature = self
+            .own_key
+            .signing_key
+            .sign(&GroupMessage::signed_bytes(self.own_key.key_id, iteration, &ciphertext, &aad));
+
+        self.own_key.chain_key = chain_key;
+        self.own_key.iteration += 1;
+        Ok(GroupMessage { key_id: self.own_key.key_id, iteration, ciphertext, signature })
+    }
+
+    /// Decrypt a message `sender` encrypted to the group
+    ///
+    /// The sender's chain only moves forward once the message verifies and decrypts.
+    pub fn decrypt(
+        &mut self,
+        sender: &str,
+        message: &GroupMessage,
+        associated_data: &AssociatedData,
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.retired_keys.retain(|_, retired| retired.retired_at.elapsed() < PREVIOUS_KEY_TTL);
+        let current = self.member_keys.get_mut(sender).ok_or(CryptoError::NoSession)?;
+        let sender_key = if message.key_id == current.key_id {
+            current
+        } else {
+            match self.retired_keys.get_mut(sender) {
+                Some(retired) if retired.key.key_id == message.key_id => &mut retired.key,
+                _ => return Err(CryptoError::DecryptionFailed),
+            }
+        };
+        let aad = associated_data.to_bytes();
+        let signed = GroupMessage::signed_bytes(message.key_id, message.iteration, &message.ciphertext, &aad);
+        if !IdentityKeyPair::verify(&sender_key.signing_key, &signed, &message.signature) {
+            return Err(CryptoError::InvalidSignature);
+        }
+
+        // An earlier message arriving late uses a key we stored when skipping past it
+        if message.iteration < sender_key.iteration {
+            let message_key = sender_key.skipped.get(&message.iteration).ok_or(CryptoError::DecryptionFailed)?;
+            let key = Crypto::create_symmetric_key(message_key, &[], MESSAGE_KEY_LABEL)?;
+            let plaintext = Crypto::decrypt_with_aad(&key, &message.ciphertext, &aad)?;
+            sender_key.skipped.remove(&message.iteration);
+            return Ok(plaintext);
+        }
+        if message.iteration - sender_key.iteration > MAX_SKIP {
+            return Err(CryptoError::TooManySkippedMessages);
+        }
+
+        let mut chain_key = sender_key.chain_key;
+        let mut skipped = Vec::new();
+        for iteration in sender_key.iteration..message.iteration {
+            let (next_chain, message_key) = kdf_chain(&chain_key);
+            skipped.push((iteration, message_key));
+            chain_key = next_chain;
+        }
+        let (next_chain, message_key) = kdf_chain(&chain_key);
+        let key = Crypto::create_symmetric_key(&message_key, &[], MESSAGE_KEY_LABEL)?;
+        let plaintext = Crypto::decrypt_with_aad(&key, &message.ciphertext, &aad)?;
+
+        sender_key.chain_key = next_chain;
+        sender_key.iteration = message.iteration + 1;
+        sender_key.skipped.extend(skipped);
+        if sender_key.skipped.len() > MAX_SKIP as usize {
+            let oldest = sender_key.iteration.saturating_sub(MAX_SKIP);
+            sender_key.skipped.retain(|iteration, _| *iteration >= oldest);
+        }
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn aad<'a>(sender: &'a str, message_id: &'a str) -> AssociatedData<'a> {
+        AssociatedData { version: 1, sender, recipient: "group", message_id }
+    }
+
+    /// Alice's group with Bob and Carol, everyone holding everyone else's sender key
+    fn group() -> (IdentityKeyPair, HashMap<&'static str, Group>) {
+        let identity = IdentityKeyPair::generate();
+        let roster = GroupRoster::new("group", "friends", "alice", members(&["alice", "bob", "carol"]), &identity);
+        let mut groups: HashMap<_, _> = ["alice", "bob", "carol"].into_iter().map(|name| (name, Group::new(roster.clone()))).collect();
+        let distributions: Vec<_> = groups.iter().map(|(name, group)| (*name, group.distribution())).collect();
+        for (name, group) in groups.iter_mut() {
+            for (sender, distribution) in &distributions {
+                if sender != name {
+                    group.add_sender_key(sender, distribution).unwrap();
+                }
+            }
+        }
+        (identity, groups)
+    }
+
+    #[test]
+    fn every_member_decrypts_a_message() {
+        let (_, mut groups) = group();
+        let message = groups.get_mut("alice").unwrap().encrypt(b"hello", &aad("alice", "1")).unwrap();
+        let message = GroupMessage::from_bytes(&message.to_bytes()).unwrap();
+
+        for member in ["bob", "carol"] {
+            let plaintext = groups.get_mut(member).unwrap().decrypt("alice", &message, &aad("alice", "1")).unwrap();
+            assert_eq!(plaintext, b"hello");
+        }
+    }
+
+    #[test]
+    fn messages_decrypt_out_of_order_but_only_once() {
+        let (_, mut groups) = group();
+        let ids = ["1", "2", "3"];
+        let messages: Vec<_> =
+            ids.iter().map(|id| groups.get_mut("alice").unwrap().encrypt(id.as_bytes(), &aad("alice", id)).unwrap()).collect();
+        let bob = groups.get_mut("bob").unwrap();
+
+        for index in [2, 0, 1] {
+            let plaintext = bob.decrypt("alice", &messages[index], &aad("alice", ids[index])).unwrap();
+            assert_eq!(plaintext, ids[index].as_bytes());
+        }
+        for index in 0..3 {
+            assert_eq!(bob.decrypt("alice", &messages[index], &aad("alice", ids[index])), Err(CryptoError::DecryptionFailed));
+        }
+    }
+
+    #[test]
+    fn skipping_too_far_ahead_is_refused() {
+        let (_, mut groups) = group();
+        let alice = groups.get_mut("alice").unwrap();
+        for _ in 0..=MAX_SKIP {
+            alice.own_key.chain_key = kdf_chain(&alice.own_key.chain_key).0;
+            alice.own_key.iteration += 1;
+        }
+        let message = alice.encrypt(b"too late", &aad("alice", "1")).unwrap();
+
+        let bob = groups.get_mut("bob").unwrap();
+        assert_eq!(bob.decrypt("alice", &message, &aad("alice", "1")), Err(CryptoError::TooManySkippedMessages));
+    }
+
+    #[test]
+    fn tampered_or_forged_messages_are_refused_without_advancing_the_chain() {
+        let (_, mut groups) = group();
+        let message = groups.get_mut("alice").unwrap().encrypt(b"hello", &aad("alice", "1")).unwrap();
+        let forged = groups.get_mut("carol").unwrap().encrypt(b"hello", &aad("alice", "1")).unwrap();
+        let bob = groups.get_mut("bob").unwrap();
+
+        let mut ciphertext = message.clone();
+        ciphertext.ciphertext[0] ^= 1;
+        let mut iteration = message.clone();
+        iteration.iteration += 1;
+        for tampered in [&ciphertext, &iteration, &forged] {
+            assert_eq!(bob.decrypt("alice", tampered, &aad("alice", "1")), Err(CryptoError::InvalidSignature));
+        }
+        assert_eq!(bob.decrypt("alice", &message, &aad("alice", "2")), Err(CryptoError::InvalidSignature));
+        assert_eq!(bob.decrypt("carol", &message, &aad("alice", "1")), Err(CryptoError::InvalidSignature));
+
+        assert_eq!(bob.decrypt("alice", &message, &aad("alice", "1")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn sender_keys_are_only_taken_from_members_of_the_group() {
+        let (_, mut groups) = group();
+        let mut distribution = groups["alice"].distribution();
+        let bob = groups.get_mut("bob").unwrap();
+
+        assert_eq!(bob.add_sender_key("mallory", &distribution), Err(CryptoError::NoSession));
+        distribution.group_id = "other".to_string();
+        assert_eq!(bob.add_sender_key("alice", &distribution), Err(CryptoError::NoSession));
+        distribution.group_id = "group".to_string();
+        distribution.chain_key.pop();
+        assert_eq!(bob.add_sender_key("alice", &distribution), Err(CryptoError::InvalidHeader));
+    }
+
+    #[test]
+    fn stale_distributions_do_not_rewind_the_chain() {
+        let (_, mut groups) = group();
+        let stale = groups["alice"].distribution();
+        let message = groups.get_mut("alice").unwrap().encrypt(b"hello", &aad("alice", "1")).unwrap();
+        let bob = groups.get_mut("bob").unwrap();
+        assert_eq!(bob.decrypt("alice", &message, &aad("alice", "1")).unwrap(), b"hello");
+
+        bob.add_sender_key("alice", &stale).unwrap();
+        assert_eq!(bob.decrypt("alice", &message, &aad("alice", "1")), Err(CryptoError::DecryptionFailed));
+
+        // A distribution further along the same chain still moves it forward
+        let alice = groups.get_mut("alice").unwrap();
+        alice.encrypt(b"skipped", &aad("alice", "2")).unwrap();
+        let ahead = alice.distribution();
+        let next = alice.encrypt(b"next", &aad("alice", "3")).unwrap();
+        let bob = groups.get_mut("bob").unwrap();
+        bob.add_sender_key("alice", &ahead).unwrap();
+        assert_eq!(bob.decrypt("alice", &next, &aad("alice", "3")).unwrap(), b"next");
+    }
+
+    #[test]
+    fn retired_keys_decrypt_in_flight_messages_until_they_expire() {
+        let (_, mut groups) = group();
+        let alice = groups.get_mut("alice").unwrap();
+        let before = alice.encrypt(b"before", &aad("alice", "1")).unwrap();
+        let also_before = alice.encrypt(b"also before", &aad("alice", "2")).unwrap();
+        alice.rotate();
+        let distribution = alice.distribution();
+        let after = alice.encrypt(b"after", &aad("alice", "3")).unwrap();
+        assert_ne!(before.key_id, after.key_id);
+
+        let bob = groups.get_mut("bob").unwrap();
+        bob.add_sender_key("alice", &distribution).unwrap();
+        assert_eq!(bob.decrypt("alice", &after, &aad("alice", "3")).unwrap(), b"after");
+        assert_eq!(bob.decrypt("alice", &before, &aad("alice", "1")).unwrap(), b"before");
+
+        let retired = bob.retired_keys.get_mut("alice").unwrap();
+        retired.retired_at = Instant::now().checked_sub(PREVIOUS_KEY_TTL).unwrap();
+        assert_eq!(bob.decrypt("alice", &also_before, &aad("alice", "2")), Err(CryptoError::DecryptionFailed));
+    }
+
+    #[test]
+    fn removing_a_member_rotates_our_key_and_forgets_theirs() {
+        let (identity, mut groups) = group();
+        let alice = groups.get_mut("alice").unwrap();
+        let key_id = alice.distribution().key_id;
+
+        let roster = alice.roster().next("alice", members(&["alice", "bob"]), "alice", &identity);
+        assert!(alice.set_roster(roster.clone()));
+        assert_ne!(alice.distribution().key_id, key_id);
+        assert!(!alice.has_sender_key("carol"));
+        assert!(alice.has_sender_key("bob"));
+
+        let renewed = roster.next("alice", members(&["alice", "bob"]), "alice", &identity);
+        assert!(!alice.set_roster(renewed));
+    }
+
+    #[test]
+    fn rosters_verify_against_their_signer_only() {
+        let owner = IdentityKeyPair::generate();
+        let roster = GroupRoster::new("group", "friends", "alice", members(&["alice", "bob"]), &owner);
+        assert!(roster.is_well_formed());
+        assert!(roster.verify(owner.public_key()));
+        assert!(!roster.verify(IdentityKeyPair::generate().public_key()));
+
+        let mut tampered = roster.clone();
+        tampered.members.push("mallory".to_string());
+        assert!(!tampered.verify(owner.public_key()));
+        let mut tampered = roster;
+        tampered.version += 1;
+        assert!(!tampered.verify(owner.public_key()));
+    }
+
+    #[test]
+    fn first_rosters_must_be_signed_by_an_owner_who_is_a_member() {
+        let identity = IdentityKeyPair::generate();
+        let duplicated = GroupRoster::new("group", "friends", "alice", members(&["alice", "bob", "bob"]), &identity);
+        let absent_owner = GroupRoster::new("group", "friends", "alice", members(&["bob"]), &identity);
+        let roster = GroupRoster::new("group", "friends", "alice", members(&["alice", "bob"]), &identity);
+        let by_member = roster.next("alice", members(&["alice", "bob"]), "bob", &identity);
+
+        for roster in [duplicated, absent_owner, by_member] {
+            assert!(!roster.is_well_formed());
+        }
+    }
+
+    #[test]
+    fn rosters_follow_only_owner_changes_and_self_removal() {
+        let identity = IdentityKeyPair::generate();
+        let roster = GroupRoster::new("group", "friends", "alice", members(&["alice", "bob", "carol"]), &identity);
+
+        let added = roster.next("alice", members(&["alice", "bob", "carol", "dave"]), "alice", &identity);
+        let handed_on = roster.next("bob", members(&["bob", "carol"]), "alice", &identity);
+        let left = roster.next("alice", members(&["alice", "carol"]), "bob", &identity);
+        let disbanded = roster.next("alice", Vec::new(), "alice", &identity);
+        for next in [&added, &handed_on, &left, &disbanded] {
+            assert!(next.follows(&roster));
+        }
+
+        let removed_other = roster.next("alice", members(&["alice", "bob"]), "bob", &identity);
+        let took_over = roster.next("bob", members(&["alice", "carol"]), "bob", &identity);
+        let outsider = roster.next("alice", members(&["alice", "bob"]), "mallory", &identity);
+        let owner_absent = roster.next("bob", members(&["alice", "carol"]), "alice", &identity);
+        let duplicated = roster.next("alice", members(&["alice", "bob", "bob"]), "alice", &identity);
+        let mut renamed = added.clone();
+        renamed.name = "enemies".to_string();
+        let mut other_group = added.clone();
+        other_group.group_id = "other".to_string();
+        for next in [&removed_other, &took_over, &outsider, &owner_absent, &duplicated, &renamed, &other_group] {
+            assert!(!next.follows(&roster));
+        }
+
+        assert!(!roster.follows(&added));
+        assert!(!added.follows(&added));
+        assert!(!left.follows(&added));
+    }
+}
@@ -0,0 +1,350 @@
+use crate::crypto::{Crypto, CryptoError, KeySchedule};
+use crate::framing::{read_frame, write_frame, Frame, FrameType};
+use crate::identity::IdentityKeyPair;
+use ring::aead::{LessSafeKey, UnboundKey, AES_256_GCM};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Salt for every handshake key, so they can't collide with keys of other protocols
+const HANDSHAKE_SALT: &[u8] = b"p2p_sparse_messaging p2p handshake";
+
+/// Length of an uncompressed SEC1 P-256 public key
+const PUBLIC_KEY_LEN: usize = 65;
+
+/// Length of an encrypted public key: nonce, key and tag
+const SEALED_KEY_LEN: usize = 12 + PUBLIC_KEY_LEN + 16;
+
+/// Encrypts frames sent on a direct link
+///
+/// Each frame's sequence number is bound into its tag, so dropped, reordered or
+/// replayed frames fail to decrypt.
+pub struct SendCipher {
+    key: LessSafeKey,
+    sequence: u64,
+}
+
+impl SendCipher {
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let ciphertext = Crypto::encrypt_with_aad(&self.key, plaintext, &self.sequence.to_be_bytes())?;
+        self.sequence += 1;
+        Ok(ciphertext)
+    }
+}
+
+/// Decrypts frames received on a direct link
+pub struct ReceiveCipher {
+    key: LessSafeKey,
+    sequence: u64,
+}
+
+impl ReceiveCipher {
+    pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let plaintext = Crypto::decrypt_with_aad(&self.key, ciphertext, &self.sequence.to_be_bytes())?;
+        self.sequence += 1;
+        Ok(plaintext)
+    }
+}
+
+/// Outcome of a completed handshake
+pub struct SecureLink {
+    /// User ID the peer authenticated as
+    pub peer_id: String,
+    /// Identity key the peer proved it holds
+    pub peer_identity_key: Vec<u8>,
+    pub send_cipher: SendCipher,
+    pub receive_cipher: ReceiveCipher,
+}
+
+/// Run the initiator side of the handshake with a peer whose identity key we know
+///
+/// The pattern follows Noise XX with our identity keys as static keys:
+///
+/// ```text
+/// -> e
+/// <- e, ee, s, es
+/// -> s, se, user ID
+/// <- confirmation
+/// ```
+///
+/// Fails unless the responder proves it holds `peer_identity_key`.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    identity: &IdentityKeyPair,
+    own_id: &str,
+    peer_id: &str,
+    peer_identity_key: &[u8],
+) -> io::Result<SecureLink> {
+    let ephemeral = Crypto::new();
+    let message1 = ephemeral.public_key().to_vec();
+    write_frame(stream, &Frame::new(FrameType::Handshake, message1.clone())).await?;
+
+    // <- e, ee, s, es
+    let message2 = read_handshake(stream).await?;
+    if message2.len() != PUBLIC_KEY_LEN + SEALED_KEY_LEN {
+        return Err(invalid_data("malformed handshake response"));
+    }
+    let (remote_ephemeral, sealed_static) = message2.split_at(PUBLIC_KEY_LEN);
+    let ee = ephemeral.derive_session_key(remote_ephemeral).map_err(crypto_error)?;
+    let transcript = [message1.as_slice(), remote_ephemeral].concat();
+    let key = handshake_key(&ee, &transcript, "handshake static", remote_ephemeral, ephemeral.public_key())?;
+    let remote_static = Crypto::decrypt_with_key(&key, sealed_static).map_err(crypto_error)?;
+    if remote_static != peer_identity_key {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "peer identity key mismatch"));
+    }
+    let es = ephemeral.derive_session_key(&remote_static).map_err(crypto_error)?;
+
+    // -> s, se, user ID
+    let secrets = [ee, es].concat();
+    let transcript = [message1.as_slice(), &message2].concat();
+    let key = handshake_key(&secrets, &transcript, "handshake static", ephemeral.public_key(), remote_ephemeral)?;
+    let sealed_static = Crypto::encrypt_with_key(&key, identity.public_key()).map_err(crypto_error)?;
+    let se = Crypto::diffie_hellman(&identity.secret_key(), remote_ephemeral).map_err(crypto_error)?;
+
+    let secrets = [ee, es, se].concat();
+    let transcript = [transcript.as_slice(), &sealed_static].concat();
+    let key = handshake_key(&secrets, &transcript, "handshake payload", ephemeral.public_key(), remote_ephemeral)?;
+    let sealed_id = Crypto::encrypt_with_key(&key, own_id.as_bytes()).map_err(crypto_error)?;
+    let message3 = [sealed_static, sealed_id].concat();
+    write_frame(stream, &Frame::new(FrameType::Handshake, message3.clone())).await?;
+
+    // <- confirmation, sent with the first transport key
+    let transcript = [message1.as_slice(), &message2, &message3].concat();
+    let mut link = transport(&secrets, &transcript, ephemeral.public_key(), remote_ephemeral)?;
+    let confirmation = read_handshake(stream).await?;
+    link.1.open(&confirmation).map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "peer refused the handshake"))?;
+
+    Ok(SecureLink {
+        peer_id: peer_id.to_string(),
+        peer_identity_key: remote_static,
+        send_cipher: link.0,
+        receive_cipher: link.1,
+    })
+}
+
+/// Run the responder side of the handshake
+///
+/// `authorize` is called with the user ID and identity key the initiator proved;
+/// the link is refused unless it returns `true`.
+pub async fn respond<S, F>(stream: &mut S, identity: &IdentityKeyPair, authorize: F) -> io::Result<SecureLink>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&str, &[u8]) -> bool,
+{
+    // -> e
+    let message1 = read_handshake(stream).await?;
+    if message1.len() != PUBLIC_KEY_LEN {
+        return Err(invalid_data("malformed handshake"));
+    }
+    let remote_ephemeral = message1.as_slice();
+
+    // <- e, ee, s, es
+    let ephemeral = Crypto::new();
+    let ee = ephemeral.derive_session_key(remote_ephemeral).map_err(crypto_error)?;
+    let transcript = [remote_ephemeral, ephemeral.public_key()].concat();
+    let key = handshake_key(&ee, &transcript, "handshake static", ephemeral.public_key(), remote_ephemeral)?;
+    let sealed_static = Crypto::encrypt_with_key(&key, identity.public_key()).map_err(crypto_error)?;
+    let es = Crypto::diffie_hellman(&identity.secret_key(), remote_ephemeral).map_err(crypto_error)?;
+    let message2 = [ephemeral.public_key(), &sealed_static].concat();
+    write_frame(stream, &Frame::new(FrameType::Handshake, message2.clone())).await?;
+
+    // -> s, se, user ID
+    let message3 = read_handshake(stream).await?;
+    if message3.len() <= SEALED_KEY_LEN {
+        return Err(invalid_data("malformed handshake"));
+    }
+    let (sealed_static, sealed_id) = message3.split_at(SEALED_KEY_LEN);
+    let secrets = [ee, es].concat();
+    let transcript = [message1.as_slice(), &message2].concat();
+    let key = handshake_key(&secrets, &transcript, "handshake static", remote_ephemeral, ephemeral.public_key())?;
+    let remote_static = Crypto::decrypt_with_key(&key, sealed_static).map_err(crypto_error)?;
+    let se = ephemeral.derive_session_key(&remote_static).map_err(crypto_error)?;
+
+    let secrets = [ee, es, se].concat();
+    let transcript = [transcript.as_slice(), sealed_static].concat();
+    let key = handshake_key(&secrets, &transcript, "handshake payload", remote_ephemeral, ephemeral.public_key())?;
+    let peer_id = Crypto::decrypt_with_key(&key, sealed_id).map_err(crypto_error)?;
+    let peer_id = String::from_utf8(peer_id).map_err(|_| invalid_data("malformed user ID"))?;
+    if !authorize(&peer_id, &remote_static) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "unknown peer identity"));
+    }
+
+    // <- confirmation
+    let transcript = [message1.as_slice(), &message2, &message3].concat();
+    let (mut send_cipher, receive_cipher) = transport(&secrets, &transcript, ephemeral.public_key(), remote_ephemeral)?;
+    let confirmation = send_cipher.seal(&[]).map_err(crypto_error)?;
+    write_frame(stream, &Frame::new(FrameType::Handshake, confirmation)).await?;
+
+    Ok(SecureLink { peer_id, peer_identity_key: remote_static, send_cipher, receive_cipher })
+}
+
+/// Derive a handshake AEAD key for the direction `from` -> `to`
+fn handshake_key(secrets: &[u8], transcript: &[u8], label: &str, from: &[u8], to: &[u8]) -> io::Result<LessSafeKey> {
+    let key = KeySchedule::new(secrets, HANDSHAKE_SALT, transcript).derive(label, from, to).map_err(crypto_error)?;
+    aead_key(&key)
+}
+
+/// Derive the transport ciphers once the whole handshake transcript is known
+fn transport(
+    secrets: &[u8],
+    transcript: &[u8],
+    own_ephemeral: &[u8],
+    remote_ephemeral: &[u8],
+) -> io::Result<(SendCipher, ReceiveCipher)> {
+    let schedule = KeySchedule::new(secrets, HANDSHAKE_SALT, transcript);
+    let send_key = schedule.derive("p2p transport", own_ephemeral, remote_ephemeral).map_err(crypto_error)?;
+    let receive_key = schedule.derive("p2p transport", remote_ephemeral, own_ephemeral).map_err(crypto_error)?;
+    Ok((
+        SendCipher { key: aead_key(&send_key)?, sequence: 0 },
+        ReceiveCipher { key: aead_key(&receive_key)?, sequence: 0 },
+    ))
+}
+
+fn aead_key(key: &[u8; 32]) -> io::Result<LessSafeKey> {
+    let unbound_key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| crypto_error(CryptoError::EncryptionFailed))?;
+    Ok(LessSafeKey::new(unbound_key))
+}
+
+/// Read the next frame, which must be a handshake frame
+async fn read_handshake<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    match read_frame(stream).await? {
+        Some(frame) if frame.frame_type == FrameType::Handshake => Ok(frame.payload),
+        Some(_) => Err(invalid_data("expected a handshake frame")),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "peer closed the handshake")),
+    }
+}
+
+fn crypto_error(e: CryptoError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    struct Peers {
+        alice: IdentityKeyPair,
+        bob: IdentityKeyPair,
+    }
+
+    impl Peers {
+        fn new() -> Self {
+            Peers { alice: IdentityKeyPair::generate(), bob: IdentityKeyPair::generate() }
+        }
+
+        /// Run both sides, with alice expecting `bob_key` and bob accepting alice only if `accept`
+        async fn connect(
+            &self,
+            mut alice_stream: DuplexStream,
+            mut bob_stream: DuplexStream,
+            bob_key: &[u8],
+            accept: bool,
+        ) -> (io::Result<SecureLink>, io::Result<SecureLink>) {
+            let alice_key = self.alice.public_key().to_vec();
+            let initiator = async move {
+                initiate(&mut alice_stream, &self.alice, "alice", "bob", bob_key).await
+            };
+            let responder = async move {
+                respond(&mut bob_stream, &self.bob, |id, key| accept && id == "alice" && key == alice_key).await
+            };
+            tokio::join!(initiator, responder)
+        }
+    }
+
+    /// Forward the four handshake frames between the two streams, passing each through `tamper`
+    async fn relay(mut alice: DuplexStream, mut bob: DuplexStream, tamper: impl Fn(usize, &mut Vec<u8>)) {
+        for index in 0..4 {
+            let (from, to) = if index % 2 == 0 { (&mut alice, &mut bob) } else { (&mut bob, &mut alice) };
+            let Ok(Some(mut frame)) = read_frame(from).await else { return };
+            tamper(index, &mut frame.payload);
+            if write_frame(to, &frame).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_gives_matching_transport_ciphers() {
+        let peers = Peers::new();
+        let (alice_stream, bob_stream) = duplex(4096);
+        let (alice, bob) = peers.connect(alice_stream, bob_stream, peers.bob.public_key(), true).await;
+        let (mut alice, mut bob) = (alice.unwrap(), bob.unwrap());
+
+        assert_eq!(alice.peer_id, "bob");
+        assert_eq!(alice.peer_identity_key, peers.bob.public_key());
+        assert_eq!(bob.peer_id, "alice");
+        assert_eq!(bob.peer_identity_key, peers.alice.public_key());
+
+        for round in 0..3u8 {
+            let sealed = alice.send_cipher.seal(&[round]).unwrap();
+            assert_eq!(bob.receive_cipher.open(&sealed).unwrap(), [round]);
+            let sealed = bob.send_cipher.seal(&[round, round]).unwrap();
+            assert_eq!(alice.receive_cipher.open(&sealed).unwrap(), [round, round]);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_or_unauthorized_identities_are_refused() {
+        let peers = Peers::new();
+        let impostor = IdentityKeyPair::generate();
+
+        let (alice_stream, bob_stream) = duplex(4096);
+        let (alice, bob) = peers.connect(alice_stream, bob_stream, impostor.public_key(), true).await;
+        assert_eq!(alice.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+        assert!(bob.is_err());
+
+        let (alice_stream, bob_stream) = duplex(4096);
+        let (alice, bob) = peers.connect(alice_stream, bob_stream, peers.bob.public_key(), false).await;
+        assert!(alice.is_err());
+        assert_eq!(bob.err().map(|e| e.kind()), Some(io::ErrorKind::PermissionDenied));
+    }
+
+    #[tokio::test]
+    async fn tampered_handshake_messages_fail_both_sides() {
+        let peers = Peers::new();
+        for tampered in 0..4 {
+            let (alice_stream, alice_end) = duplex(4096);
+            let (bob_stream, bob_end) = duplex(4096);
+            let relay = tokio::spawn(relay(alice_end, bob_end, move |index, payload| {
+                if index == tampered {
+                    let last = payload.len() - 1;
+                    payload[last] ^= 1;
+                }
+            }));
+
+            let (alice, bob) = peers.connect(alice_stream, bob_stream, peers.bob.public_key(), true).await;
+            assert!(alice.is_err(), "alice accepted tampered message {}", tampered);
+            // Bob has finished by the time his confirmation is tampered with
+            if tampered < 3 {
+                assert!(bob.is_err(), "bob accepted tampered message {}", tampered);
+            }
+            relay.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn transport_refuses_tampered_replayed_and_reordered_frames() {
+        let peers = Peers::new();
+        let (alice_stream, bob_stream) = duplex(4096);
+        let (alice, bob) = peers.connect(alice_stream, bob_stream, peers.bob.public_key(), true).await;
+        let (mut alice, mut bob) = (alice.unwrap(), bob.unwrap());
+
+        let first = alice.send_cipher.seal(b"first").unwrap();
+        let second = alice.send_cipher.seal(b"second").unwrap();
+        let mut tampered = first.clone();
+        tampered[20] ^= 1;
+
+        assert_eq!(bob.receive_cipher.open(&second), Err(CryptoError::DecryptionFailed));
+        assert_eq!(bob.receive_cipher.open(&tampered), Err(CryptoError::DecryptionFailed));
+        assert_eq!(bob.receive_cipher.open(&first).unwrap(), b"first");
+        assert_eq!(bob.receive_cipher.open(&first), Err(CryptoError::DecryptionFailed));
+        assert_eq!(bob.receive_cipher.open(&second).unwrap(), b"second");
+
+        let reflected = bob.send_cipher.seal(b"reflected").unwrap();
+        assert!(bob.receive_cipher.open(&reflected).is_err());
+    }
+}
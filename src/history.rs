@@ -0,0 +1,277 @@
+use crate::crypto::{Crypto, CryptoError};
+use ring::aead::LessSafeKey;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Name sled gives the tree it creates for itself
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// Suffix of the database a history is copied into while its key changes
+const REKEY_SUFFIX: &str = ".rekey";
+
+/// A message as kept in the local history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Peer ID for a direct conversation, or `#` and the name for a group
+    pub conversation: String,
+    pub sender: String,
+    pub body: String,
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+}
+
+/// Errors reading or writing the history
+#[derive(Debug)]
+pub enum HistoryError {
+    Storage(sled::Error),
+    Crypto(CryptoError),
+    /// A record decrypted but isn't a history entry
+    Corrupt,
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Storage(e) => write!(f, "history storage error: {}", e),
+            HistoryError::Crypto(e) => write!(f, "history encryption error: {}", e),
+            HistoryError::Corrupt => f.write_str("corrupt history record"),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<sled::Error> for HistoryError {
+    fn from(e: sled::Error) -> Self {
+        HistoryError::Storage(e)
+    }
+}
+
+impl From<CryptoError> for HistoryError {
+    fn from(e: CryptoError) -> Self {
+        HistoryError::Crypto(e)
+    }
+}
+
+/// Messages sent and received, encrypted at rest in an embedded database
+///
+/// Each conversation is a tree named by an HMAC of the conversation, so the
+/// database doesn't reveal who the user talks to. Records are keyed by an
+/// increasing ID, so a tree iterates oldest first.
+pub struct MessageHistory {
+    db: sled::Db,
+    record_key: LessSafeKey,
+    name_key: hmac::Key,
+}
+
+impl MessageHistory {
+    /// Open the history at `path`, creating it if missing, with keys derived from `key_material`
+    pub fn open(path: impl AsRef<Path>, key_material: &[u8; 32]) -> Result<Self, HistoryError> {
+        let name_key = Salt::new(HKDF_SHA256, &[])
+            .extract(key_material)
+            .expand(&[b"p2p_sparse_messaging v1 history names"], hmac::HMAC_SHA256)
+            .map_err(|_| CryptoError::EncryptionFailed)?
+            .into();
+
+        Ok(MessageHistory {
+            db: sled::open(path)?,
+            record_key: Crypto::create_symmetric_key(key_material, &[], "history records")?,
+            name_key,
+        })
+    }
+
+    /// Add a message to the end of its conversation
+    pub fn append(&self, entry: &HistoryEntry) -> Result<(), HistoryError> {
+        let tree_name = self.tree_name(&entry.conversation);
+        let record = serde_json::to_vec(entry).map_err(|_| HistoryError::Corrupt)?;
+        let ciphertext = Crypto::encrypt_with_aad(&self.record_key, &record, &tree_name)?;
+
+        let tree = self.db.open_tree(&tree_name)?;
+        tree.insert(self.db.generate_id()?.to_be_bytes(), ciphertext)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Up to `count` messages of a conversation, oldest first, skipping the `skip` most recent
+    pub fn recent(&self, conversation: &str, count: usize, skip: usize) -> Result<Vec<HistoryEntry>, HistoryError> {
+        let tree_name = self.tree_name(conversation);
+        if !self.db.tree_names().iter().any(|name| name == tree_name.as_slice()) {
+            return Ok(Vec::new());
+        }
+
+        let tree = self.db.open_tree(&tree_name)?;
+        let mut entries = tree
+            .iter()
+            .rev()
+            .skip(skip)
+            .take(count)
+            .map(|record| self.decrypt(&tree_name, &record?.1))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    /// Every message containing `query`, ignoring case, oldest first
+    pub fn search(&self, query: &str) -> Result<Vec<HistoryEntry>, HistoryError> {
+        let query = query.to_lowercase();
+        let mut matches = Vec::new();
+        for tree_name in self.db.tree_names().into_iter().filter(|name| name != DEFAULT_TREE) {
+            for record in self.db.open_tree(&tree_name)?.iter() {
+                let (id, ciphertext) = record?;
+                let entry = self.decrypt(&tree_name, &ciphertext)?;
+                if entry.body.to_lowercase().contains(&query) {
+                    matches.push((id, entry));
+                }
+            }
+        }
+
+        // IDs increase across the whole database, so they order messages between conversations too
+        matches.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(matches.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// Copy the history at `path` into a new database encrypted with `new_key`
+    ///
+    /// The copy only replaces the original in `finish_rekey`, which should be called
+    /// once `new_key` is stored, so a crash in between loses neither.
+    pub fn rekey(path: impl AsRef<Path>, old_key: &[u8; 32], new_key: &[u8; 32]) -> Result<(), HistoryError> {
+        let copy_path = rekey_path(path.as_ref());
+        if copy_path.exists() {
+            fs::remove_dir_all(&copy_path).map_err(|e| HistoryError::Storage(e.into()))?;
+        }
+        let old = MessageHistory::open(path, old_key)?;
+        let new = MessageHistory::open(&copy_path, new_key)?;
+
+        // Every message matches an empty search, and they come out in the order they were added
+        for entry in old.search("")? {
+            new.append(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Replace a history with the copy `rekey` made, if there is one
+    pub fn finish_rekey(path: impl AsRef<Path>) -> io::Result<()> {
+        let copy_path = rekey_path(path.as_ref());
+        if !copy_path.exists() {
+            return Ok(());
+        }
+        if path.as_ref().exists() {
+            fs::remove_dir_all(path.as_ref())?;
+        }
+        fs::rename(copy_path, path)
+    }
+
+    fn tree_name(&self, conversation: &str) -> Vec<u8> {
+        hmac::sign(&self.name_key, conversation.as_bytes()).as_ref().to_vec()
+    }
+
+    /// Decrypt a record, which only succeeds in the tree it was written to
+    fn decrypt(&self, tree_name: &[u8], ciphertext: &[u8]) -> Result<HistoryEntry, HistoryError> {
+        let record = Crypto::decrypt_with_aad(&self.record_key, ciphertext, tree_name)?;
+        serde_json::from_slice(&record).map_err(|_| HistoryError::Corrupt)
+    }
+}
+
+fn rekey_path(path: &Path) -> PathBuf {
+    let mut copy_path = path.as_os_str().to_owned();
+    copy_path.push(REKEY_SUFFIX);
+    PathBuf::from(copy_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database path in the temporary directory, removed with any rekey copy when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            TempPath(std::env::temp_dir().join(format!("history-test-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+            let _ = fs::remove_dir_all(rekey_path(&self.0));
+        }
+    }
+
+    fn entry(conversation: &str, body: &str) -> HistoryEntry {
+        HistoryEntry { conversation: conversation.to_string(), sender: "alice".to_string(), body: body.to_string(), timestamp: 0 }
+    }
+
+    fn bodies(entries: Vec<HistoryEntry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.body).collect()
+    }
+
+    #[test]
+    fn recent_pages_back_from_the_newest_message() {
+        let path = TempPath::new();
+        let history = MessageHistory::open(&path.0, &[1; 32]).unwrap();
+        for body in ["one", "two", "three", "four"] {
+            history.append(&entry("bob", body)).unwrap();
+        }
+        history.append(&entry("carol", "elsewhere")).unwrap();
+
+        assert_eq!(bodies(history.recent("bob", 10, 0).unwrap()), ["one", "two", "three", "four"]);
+        assert_eq!(bodies(history.recent("bob", 2, 0).unwrap()), ["three", "four"]);
+        assert_eq!(bodies(history.recent("bob", 2, 2).unwrap()), ["one", "two"]);
+        assert!(history.recent("bob", 2, 4).unwrap().is_empty());
+        assert!(history.recent("dave", 2, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn search_spans_conversations_in_order() {
+        let path = TempPath::new();
+        let history = MessageHistory::open(&path.0, &[1; 32]).unwrap();
+        history.append(&entry("bob", "Lunch tomorrow?")).unwrap();
+        history.append(&entry("#friends", "no lunch for me")).unwrap();
+        history.append(&entry("carol", "dinner")).unwrap();
+        history.append(&entry("bob", "LUNCH it is")).unwrap();
+
+        let found = history.search("lunch").unwrap();
+        let conversations: Vec<_> = found.iter().map(|entry| entry.conversation.as_str()).collect();
+        assert_eq!(conversations, ["bob", "#friends", "bob"]);
+        assert_eq!(bodies(found), ["Lunch tomorrow?", "no lunch for me", "LUNCH it is"]);
+    }
+
+    #[test]
+    fn records_only_decrypt_in_their_own_conversation() {
+        let path = TempPath::new();
+        let history = MessageHistory::open(&path.0, &[1; 32]).unwrap();
+        history.append(&entry("bob", "for bob")).unwrap();
+        history.append(&entry("carol", "for carol")).unwrap();
+
+        // Move Bob's record into Carol's tree
+        let bob = history.db.open_tree(history.tree_name("bob")).unwrap();
+        let (id, record) = bob.iter().next().unwrap().unwrap();
+        history.db.open_tree(history.tree_name("carol")).unwrap().insert(id, record).unwrap();
+        assert!(matches!(history.recent("carol", 10, 0), Err(HistoryError::Crypto(CryptoError::DecryptionFailed))));
+    }
+
+    #[test]
+    fn rekeyed_histories_keep_their_messages_under_the_new_key_only() {
+        let path = TempPath::new();
+        let history = MessageHistory::open(&path.0, &[1; 32]).unwrap();
+        history.append(&entry("bob", "first")).unwrap();
+        history.append(&entry("#friends", "second")).unwrap();
+        history.append(&entry("bob", "third")).unwrap();
+        drop(history);
+
+        MessageHistory::rekey(&path.0, &[1; 32], &[2; 32]).unwrap();
+        assert_eq!(bodies(MessageHistory::open(&path.0, &[1; 32]).unwrap().recent("bob", 10, 0).unwrap()), ["first", "third"]);
+        MessageHistory::finish_rekey(&path.0).unwrap();
+
+        let history = MessageHistory::open(&path.0, &[2; 32]).unwrap();
+        assert_eq!(bodies(history.search("").unwrap()), ["first", "second", "third"]);
+        drop(history);
+        assert!(MessageHistory::open(&path.0, &[1; 32]).unwrap().recent("bob", 10, 0).unwrap().is_empty());
+    }
+}
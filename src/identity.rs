@@ -0,0 +1,71 @@
+use p256::ecdsa::signature::{Signature as _, Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use std::io;
+
+/// Long-term identity key pair used to sign the ephemeral session keys
+pub struct IdentityKeyPair {
+    signing_key: SigningKey,
+    public_key: Vec<u8>,
+}
+
+impl IdentityKeyPair {
+    /// Generate a new ECDSA (P-256) identity key pair
+    pub fn generate() -> Self {
+        Self::from_signing_key(SigningKey::random(&mut rand_core::OsRng))
+    }
+
+    /// Rebuild an identity key pair from its 32-byte secret scalar
+    pub fn from_secret_bytes(secret: &[u8]) -> io::Result<Self> {
+        let signing_key = SigningKey::from_bytes(secret)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid identity key"))?;
+        Ok(Self::from_signing_key(signing_key))
+    }
+
+    fn from_signing_key(signing_key: SigningKey) -> Self {
+        let public_key = signing_key
+            .verifying_key()
+            .to_encoded_point(false) // Uncompressed point, same as `Crypto`
+            .as_bytes()
+            .to_vec();
+
+        IdentityKeyPair {
+            signing_key,
+            public_key,
+        }
+    }
+
+    /// Get the raw secret scalar of this identity
+    pub fn secret_bytes(&self) -> Vec<u8> {
+        self.signing_key.to_bytes().to_vec()
+    }
+
+    /// Get the identity as a P-256 secret key for X3DH
+    pub(crate) fn secret_key(&self) -> p256::SecretKey {
+        p256::SecretKey::from_be_bytes(&self.signing_key.to_bytes()).expect("Invalid identity key")
+    }
+
+    /// Get the public identity key (SEC1 uncompressed point)
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Sign a message with the identity key
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let signature: Signature = self.signing_key.sign(message);
+        signature.as_bytes().to_vec()
+    }
+
+    /// Check a signature made by the owner of `public_key`
+    pub fn verify(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+        let verifying_key = match VerifyingKey::from_sec1_bytes(public_key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        let signature = match Signature::from_bytes(signature) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+
+        verifying_key.verify(message, &signature).is_ok()
+    }
+}
@@ -0,0 +1,318 @@
+use crate::crypto::{Crypto, CryptoError};
+use argon2::{Algorithm, Argon2, Params, Version};
+use ring::aead::LessSafeKey;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Version of the keystore file format
+const KEYSTORE_VERSION: u8 = 1;
+
+/// Length of the random salt the passphrase is stretched with
+const SALT_LEN: usize = 16;
+
+/// Argon2id cost for new keystores: 19 MiB, two passes, one lane
+const MEMORY_KIB: u32 = 19 * 1024;
+const ITERATIONS: u32 = 2;
+const PARALLELISM: u32 = 1;
+
+/// Name the passphrase check is sealed under, which no entry can use
+const CHECK_ENTRY: &str = "";
+
+/// How the key is derived from the passphrase, stored in the clear so it can be derived again
+#[derive(Clone, Serialize, Deserialize)]
+struct KdfParams {
+    salt: Vec<u8>,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl KdfParams {
+    fn generate() -> Result<Self, KeystoreError> {
+        let mut salt = vec![0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| CryptoError::RandomFailed)?;
+        Ok(KdfParams { salt, memory_kib: MEMORY_KIB, iterations: ITERATIONS, parallelism: PARALLELISM })
+    }
+
+    /// Stretch a passphrase into the key the entries are sealed with
+    fn derive_key(&self, passphrase: &str) -> Result<LessSafeKey, KeystoreError> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|_| KeystoreError::Malformed)?;
+        let mut key_material = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key_material)
+            .map_err(|_| KeystoreError::Malformed)?;
+        Ok(Crypto::create_symmetric_key(&key_material, &self.salt, "keystore")?)
+    }
+}
+
+/// Keystore as written to disk
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u8,
+    kdf: KdfParams,
+    /// Sealed entries by name, the check included
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+/// Errors opening or writing a keystore
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    Crypto(CryptoError),
+    /// The passphrase doesn't open the keystore
+    WrongPassphrase,
+    /// The file or one of its entries isn't a keystore this version can read
+    Malformed,
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "{}", e),
+            KeystoreError::Crypto(e) => write!(f, "{}", e),
+            KeystoreError::WrongPassphrase => f.write_str("wrong passphrase"),
+            KeystoreError::Malformed => f.write_str("malformed keystore"),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+impl From<CryptoError> for KeystoreError {
+    fn from(e: CryptoError) -> Self {
+        KeystoreError::Crypto(e)
+    }
+}
+
+/// Named secrets sealed with a key derived from a passphrase
+///
+/// Each entry is encrypted with AES-256-GCM under its own name, so entries can't be
+/// swapped. The file is rewritten whenever an entry changes, and the key is kept
+/// in memory so that doesn't need the passphrase again.
+pub struct Keystore {
+    path: PathBuf,
+    file: KeystoreFile,
+    key: LessSafeKey,
+}
+
+impl Keystore {
+    /// Create an empty keystore at `path` protected by `passphrase`
+    pub fn create(path: impl AsRef<Path>, passphrase: &str) -> Result<Self, KeystoreError> {
+        let kdf = KdfParams::generate()?;
+        let key = kdf.derive_key(passphrase)?;
+        let mut keystore = Keystore {
+            path: path.as_ref().to_path_buf(),
+            file: KeystoreFile { version: KEYSTORE_VERSION, kdf, entries: BTreeMap::new() },
+            key,
+        };
+        keystore.seal(CHECK_ENTRY, &[])?;
+        keystore.write()?;
+        Ok(keystore)
+    }
+
+    /// Open the keystore at `path`, failing with `WrongPassphrase` if the passphrase doesn't match
+    pub fn unlock(path: impl AsRef<Path>, passphrase: &str) -> Result<Self, KeystoreError> {
+        let contents = fs::read(path.as_ref())?;
+        let file: KeystoreFile = serde_json::from_slice(&contents).map_err(|_| KeystoreError::Malformed)?;
+        if file.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::Malformed);
+        }
+
+        let key = file.kdf.derive_key(passphrase)?;
+        let keystore = Keystore { path: path.as_ref().to_path_buf(), file, key };
+        keystore.check(&keystore.key)?;
+        Ok(keystore)
+    }
+
+    /// Get an entry, or `None` if it hasn't been stored
+    pub fn get<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, KeystoreError> {
+        let Some(plaintext) = self.open(name)? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&plaintext).map(Some).map_err(|_| KeystoreError::Malformed)
+    }
+
+    /// Store an entry, replacing any previous value, and write the keystore to disk
+    pub fn put<T: Serialize>(&mut self, name: &str, value: &T) -> Result<(), KeystoreError> {
+        if name == CHECK_ENTRY {
+            return Err(KeystoreError::Malformed);
+        }
+        let plaintext = serde_json::to_vec(value).map_err(|_| KeystoreError::Malformed)?;
+        self.seal(name, &plaintext)?;
+        self.write()
+    }
+
+    /// Reseal every entry under a new passphrase, after checking the current one
+    pub fn change_passphrase(&mut self, current: &str, new: &str) -> Result<(), KeystoreError> {
+        self.check(&self.file.kdf.derive_key(current)?)?;
+
+        let mut entries = Vec::new();
+        for name in self.file.entries.keys() {
+            entries.push((name.clone(), self.open(name)?.ok_or(KeystoreError::Malformed)?));
+        }
+
+        // A fresh salt, so the old passphrase derives nothing useful for the new file
+        let kdf = KdfParams::generate()?;
+        self.key = kdf.derive_key(new)?;
+        self.file.kdf = kdf;
+        for (name, plaintext) in entries {
+            self.seal(&name, &plaintext)?;
+        }
+        self.write()
+    }
+
+    /// Fail with `WrongPassphrase` unless `key` opens the check entry
+    fn check(&self, key: &LessSafeKey) -> Result<(), KeystoreError> {
+        let ciphertext = self.file.entries.get(CHECK_ENTRY).ok_or(KeystoreError::Malformed)?;
+        Crypto::decrypt_with_aad(key, ciphertext, CHECK_ENTRY.as_bytes()).map_err(|_| KeystoreError::WrongPassphrase)?;
+        Ok(())
+    }
+
+    fn seal(&mut self, name: &str, plaintext: &[u8]) -> Result<(), KeystoreError> {
+        let ciphertext = Crypto::encrypt_with_aad(&self.key, plaintext, name.as_bytes())?;
+        self.file.entries.insert(name.to_string(), ciphertext);
+        Ok(())
+    }
+
+    fn open(&self, name: &str) -> Result<Option<Vec<u8>>, KeystoreError> {
+        let Some(ciphertext) = self.file.entries.get(name) else {
+            return Ok(None);
+        };
+        Ok(Some(Crypto::decrypt_with_aad(&self.key, ciphertext, name.as_bytes())?))
+    }
+
+    /// Write to a temporary file and rename it over the keystore, so a crash can't leave half a file
+    ///
+    /// The file is only readable by its owner.
+    fn write(&self) -> Result<(), KeystoreError> {
+        let contents = serde_json::to_vec(&self.file).map_err(|_| KeystoreError::Malformed)?;
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        // The mode only applies to a new file, so don't reuse one left behind by a crash
+        let _ = fs::remove_file(&temporary);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut file = options.open(&temporary)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A keystore path in the temporary directory, removed when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new() -> Self {
+            TempPath(std::env::temp_dir().join(format!("keystore-test-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn entries_round_trip_through_the_file() {
+        let path = TempPath::new();
+        let mut keystore = Keystore::create(&path.0, "passphrase").unwrap();
+        keystore.put("identity", &vec![1u8, 2, 3]).unwrap();
+        keystore.put("contacts", &"bob".to_string()).unwrap();
+
+        let keystore = Keystore::unlock(&path.0, "passphrase").unwrap();
+        assert_eq!(keystore.get::<Vec<u8>>("identity").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(keystore.get::<String>("contacts").unwrap(), Some("bob".to_string()));
+        assert_eq!(keystore.get::<String>("prekeys").unwrap(), None);
+        assert!(matches!(keystore.get::<String>("identity"), Err(KeystoreError::Malformed)));
+    }
+
+    #[test]
+    fn the_wrong_passphrase_is_refused() {
+        let path = TempPath::new();
+        Keystore::create(&path.0, "passphrase").unwrap();
+        assert!(matches!(Keystore::unlock(&path.0, "Passphrase"), Err(KeystoreError::WrongPassphrase)));
+    }
+
+    #[test]
+    fn tampered_swapped_or_missing_entries_are_refused() {
+        let path = TempPath::new();
+        let mut keystore = Keystore::create(&path.0, "passphrase").unwrap();
+        keystore.put("identity", &1).unwrap();
+        keystore.put("contacts", &2).unwrap();
+        assert!(matches!(keystore.put(CHECK_ENTRY, &3), Err(KeystoreError::Malformed)));
+
+        let entries = keystore.file.entries.clone();
+        keystore.file.entries.get_mut("identity").unwrap()[20] ^= 1;
+        keystore.file.entries.insert("contacts".to_string(), entries["identity"].clone());
+        keystore.write().unwrap();
+        let unlocked = Keystore::unlock(&path.0, "passphrase").unwrap();
+        for name in ["identity", "contacts"] {
+            assert!(matches!(unlocked.get::<u32>(name), Err(KeystoreError::Crypto(CryptoError::DecryptionFailed))));
+        }
+
+        keystore.file.entries.remove(CHECK_ENTRY);
+        keystore.write().unwrap();
+        assert!(matches!(Keystore::unlock(&path.0, "passphrase"), Err(KeystoreError::Malformed)));
+
+        keystore.file.entries = entries;
+        keystore.file.version += 1;
+        keystore.write().unwrap();
+        assert!(matches!(Keystore::unlock(&path.0, "passphrase"), Err(KeystoreError::Malformed)));
+    }
+
+    #[test]
+    fn changing_the_passphrase_reseals_every_entry() {
+        let path = TempPath::new();
+        let mut keystore = Keystore::create(&path.0, "old").unwrap();
+        keystore.put("identity", &7).unwrap();
+
+        assert!(matches!(keystore.change_passphrase("wrong", "new"), Err(KeystoreError::WrongPassphrase)));
+        assert!(Keystore::unlock(&path.0, "old").is_ok());
+
+        keystore.change_passphrase("old", "new").unwrap();
+        assert!(matches!(Keystore::unlock(&path.0, "old"), Err(KeystoreError::WrongPassphrase)));
+        let keystore = Keystore::unlock(&path.0, "new").unwrap();
+        assert_eq!(keystore.get::<u32>("identity").unwrap(), Some(7));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_owner_can_read_the_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = TempPath::new();
+        let mut temporary = path.0.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, b"left behind").unwrap();
+        fs::set_permissions(&temporary, fs::Permissions::from_mode(0o644)).unwrap();
+
+        Keystore::create(&path.0, "passphrase").unwrap();
+        assert_eq!(fs::metadata(&path.0).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!Path::new(&temporary).exists());
+    }
+}
@@ -1 +1,20 @@
-pub mod crypto;
\ No newline at end of file
+pub mod accounts;
+pub mod attachments;
+pub mod blobs;
+pub mod contacts;
+pub mod content;
+pub mod crypto;
+pub mod fallback;
+pub mod fingerprint;
+pub mod framing;
+pub mod groups;
+pub mod handshake;
+pub mod history;
+pub mod identity;
+pub mod keystore;
+pub mod mailbox;
+pub mod mls;
+pub mod p2p;
+pub mod prekeys;
+pub mod protocol;
+pub mod session;
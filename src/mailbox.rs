@@ -0,0 +1,208 @@
+use crate::protocol::Envelope;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Queued envelope with the ID used to acknowledge it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingEnvelope {
+    pub id: u64,
+    pub envelope: Envelope,
+}
+
+/// Envelope waiting for its recipient to come online
+struct QueuedEnvelope {
+    id: u64,
+    envelope: Envelope,
+    queued_at: Instant,
+}
+
+/// Reasons an envelope can't be queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The recipient already has the maximum number of envelopes waiting
+    QuotaExceeded,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueueError::QuotaExceeded => f.write_str("recipient's offline queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Per-user queues of envelopes for offline recipients, keyed by user ID
+///
+/// Envelopes are handed back in the order they were queued. Each user can have at
+/// most `quota` envelopes waiting, and envelopes older than `ttl` are dropped.
+pub struct Mailbox {
+    queues: HashMap<String, VecDeque<QueuedEnvelope>>,
+    next_id: u64,
+    quota: usize,
+    ttl: Duration,
+}
+
+impl Mailbox {
+    /// Create an empty mailbox with a per-user quota and an expiry time
+    pub fn new(quota: usize, ttl: Duration) -> Self {
+        Mailbox { queues: HashMap::new(), next_id: 0, quota, ttl }
+    }
+
+    /// Queue an envelope for an offline recipient, returning its ID
+    pub fn push(&mut self, recipient: &str, envelope: Envelope) -> Result<u64, QueueError> {
+        let ttl = self.ttl;
+        let queue = self.queues.entry(recipient.to_string()).or_default();
+        expire(queue, ttl);
+        if queue.len() >= self.quota {
+            return Err(QueueError::QuotaExceeded);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        queue.push_back(QueuedEnvelope { id, envelope, queued_at: Instant::now() });
+        Ok(id)
+    }
+
+    /// Remove and return every unexpired envelope queued for a user, oldest first
+    pub fn take_all(&mut self, recipient: &str) -> Vec<Envelope> {
+        let Some(mut queue) = self.queues.remove(recipient) else {
+            return Vec::new();
+        };
+        expire(&mut queue, self.ttl);
+        queue.into_iter().map(|queued| queued.envelope).collect()
+    }
+
+    /// Return the unexpired envelopes queued for a user without removing them
+    pub fn pending(&mut self, recipient: &str) -> Vec<PendingEnvelope> {
+        let Some(queue) = self.queues.get_mut(recipient) else {
+            return Vec::new();
+        };
+        expire(queue, self.ttl);
+        queue
+            .iter()
+            .map(|queued| PendingEnvelope { id: queued.id, envelope: queued.envelope.clone() })
+            .collect()
+    }
+
+    /// Remove envelopes the user has acknowledged, returning how many were removed
+    pub fn ack(&mut self, recipient: &str, ids: &[u64]) -> usize {
+        let Some(queue) = self.queues.get_mut(recipient) else {
+            return 0;
+        };
+        let before = queue.len();
+        queue.retain(|queued| !ids.contains(&queued.id));
+        let removed = before - queue.len();
+        if queue.is_empty() {
+            self.queues.remove(recipient);
+        }
+        removed
+    }
+
+    /// Number of envelopes waiting for a user
+    pub fn len(&self, recipient: &str) -> usize {
+        self.queues.get(recipient).map_or(0, VecDeque::len)
+    }
+
+    /// Drop expired envelopes for every user
+    pub fn expire_all(&mut self) {
+        let ttl = self.ttl;
+        self.queues.retain(|_, queue| {
+            expire(queue, ttl);
+            !queue.is_empty()
+        });
+    }
+}
+
+/// Drop envelopes older than `ttl` from the front of a queue
+fn expire(queue: &mut VecDeque<QueuedEnvelope>, ttl: Duration) {
+    while queue.front().is_some_and(|queued| queued.queued_at.elapsed() > ttl) {
+        queue.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn envelope(to: &str, message_id: &str) -> Envelope {
+        Envelope {
+            from: "alice".to_string(),
+            to: to.to_string(),
+            message: Vec::new(),
+            message_id: message_id.to_string(),
+            version: 1,
+            initial: None,
+            group: None,
+            mls: None,
+        }
+    }
+
+    fn message_ids(envelopes: Vec<Envelope>) -> Vec<String> {
+        envelopes.into_iter().map(|envelope| envelope.message_id).collect()
+    }
+
+    #[test]
+    fn envelopes_come_back_in_the_order_they_were_queued() {
+        let mut mailbox = Mailbox::new(10, TTL);
+        for message_id in ["1", "2", "3"] {
+            mailbox.push("bob", envelope("bob", message_id)).unwrap();
+        }
+        mailbox.push("carol", envelope("carol", "4")).unwrap();
+
+        let pending: Vec<_> = mailbox.pending("bob").into_iter().map(|pending| pending.envelope).collect();
+        assert_eq!(message_ids(pending), ["1", "2", "3"]);
+        assert_eq!(message_ids(mailbox.take_all("bob")), ["1", "2", "3"]);
+        assert!(mailbox.take_all("bob").is_empty());
+        assert_eq!(mailbox.len("carol"), 1);
+    }
+
+    #[test]
+    fn each_recipient_has_its_own_quota() {
+        let mut mailbox = Mailbox::new(2, TTL);
+        mailbox.push("bob", envelope("bob", "1")).unwrap();
+        mailbox.push("bob", envelope("bob", "2")).unwrap();
+
+        assert_eq!(mailbox.push("bob", envelope("bob", "3")), Err(QueueError::QuotaExceeded));
+        assert_eq!(message_ids(mailbox.pending("bob").into_iter().map(|pending| pending.envelope).collect()), ["1", "2"]);
+        assert!(mailbox.push("carol", envelope("carol", "4")).is_ok());
+    }
+
+    #[test]
+    fn expired_envelopes_are_dropped_and_free_the_quota() {
+        let mut mailbox = Mailbox::new(2, TTL);
+        mailbox.push("bob", envelope("bob", "old")).unwrap();
+        mailbox.push("bob", envelope("bob", "new")).unwrap();
+        mailbox.push("carol", envelope("carol", "old")).unwrap();
+        for queue in mailbox.queues.values_mut() {
+            queue[0].queued_at = Instant::now().checked_sub(TTL * 2).unwrap();
+        }
+
+        mailbox.push("bob", envelope("bob", "newer")).unwrap();
+        assert_eq!(message_ids(mailbox.take_all("bob")), ["new", "newer"]);
+        mailbox.expire_all();
+        assert_eq!(mailbox.len("carol"), 0);
+        assert!(mailbox.pending("carol").is_empty());
+    }
+
+    #[test]
+    fn ack_removes_only_the_acknowledged_envelopes() {
+        let mut mailbox = Mailbox::new(10, TTL);
+        let ids: Vec<u64> = ["1", "2", "3"].iter().map(|id| mailbox.push("bob", envelope("bob", id)).unwrap()).collect();
+        let carol = mailbox.push("carol", envelope("carol", "4")).unwrap();
+
+        assert_eq!(mailbox.ack("bob", &[ids[0], ids[2], carol, 99]), 2);
+        let pending = mailbox.pending("bob");
+        assert_eq!(pending.iter().map(|pending| pending.id).collect::<Vec<_>>(), [ids[1]]);
+        assert_eq!(mailbox.len("carol"), 1);
+
+        assert_eq!(mailbox.ack("bob", &[ids[1]]), 1);
+        assert_eq!(mailbox.ack("bob", &[ids[1]]), 0);
+        assert_eq!(mailbox.len("bob"), 0);
+    }
+}
@@ -0,0 +1,1697 @@
+use crate::crypto::{Crypto, CryptoError};
+use crate::identity::IdentityKeyPair;
+use crate::session::kdf_chain;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::SecretKey;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, SHA256};
+use ring::hkdf::{Prk, Salt, HKDF_SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+
+/// Maximum number of message keys kept for application messages that haven't arrived yet
+const MAX_SKIP: u32 = 1000;
+
+/// Prefix of every label the key schedule derives secrets with
+const LABEL_PREFIX: &[u8] = b"p2p_sparse_messaging mls ";
+
+/// HKDF label for keys encrypting to a tree node's public key
+const HPKE_KEY_LABEL: &str = "mls hpke";
+
+/// HKDF label for the key encrypting a Welcome's group info
+const WELCOME_KEY_LABEL: &str = "mls welcome";
+
+/// HKDF label turning an application message key into an AES-256-GCM key
+const APPLICATION_KEY_LABEL: &str = "mls application";
+
+/// Reasons an MLS operation fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MlsError {
+    /// Encryption, decryption or a key agreement failed
+    Crypto(CryptoError),
+    /// The message is for another group
+    WrongGroup,
+    /// The message is for an epoch other than the group's current one
+    WrongEpoch,
+    /// The sender or a named member isn't in the group
+    UnknownMember,
+    /// The message claims to be from us; the relay never echoes our own messages
+    OwnMessage,
+    /// A key package is for someone already in the group
+    AlreadyMember,
+    /// A key package or leaf isn't signed by the identity key in it
+    InvalidKeyPackage,
+    /// A message or group info isn't signed by its sender
+    InvalidSignature,
+    /// A commit doesn't lead to the keys or secrets its sender claims
+    InvalidCommit,
+    /// Members can't commit their own removal; they propose it instead
+    CannotRemoveSelf,
+    /// One of our commits is still waiting for the server
+    CommitPending,
+    /// A Welcome isn't for any of our key packages
+    NoMatchingKeyPackage,
+    /// A message or Welcome couldn't be decoded
+    Malformed,
+}
+
+impl fmt::Display for MlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            MlsError::Crypto(e) => return write!(f, "{}", e),
+            MlsError::WrongGroup => "message is for another group",
+            MlsError::WrongEpoch => "message is for another epoch",
+            MlsError::UnknownMember => "not a member of the group",
+            MlsError::OwnMessage => "message claims to be from us",
+            MlsError::AlreadyMember => "already a member of the group",
+            MlsError::InvalidKeyPackage => "invalid key package",
+            MlsError::InvalidSignature => "invalid signature",
+            MlsError::InvalidCommit => "invalid commit",
+            MlsError::CannotRemoveSelf => "members can't commit their own removal",
+            MlsError::CommitPending => "a commit is already waiting for the server",
+            MlsError::NoMatchingKeyPackage => "welcome isn't for any of our key packages",
+            MlsError::Malformed => "malformed message",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for MlsError {}
+
+impl From<CryptoError> for MlsError {
+    fn from(error: CryptoError) -> Self {
+        MlsError::Crypto(error)
+    }
+}
+
+/// A member's place in the tree: the key others encrypt to and the key it signs with
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeafNode {
+    pub member: String,
+    #[serde(with = "serde_bytes")]
+    pub encryption_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub signature_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl LeafNode {
+    fn new(member: &str, encryption_key: Vec<u8>, identity: &IdentityKeyPair) -> Self {
+        let signature_key = identity.public_key().to_vec();
+        let signature = identity.sign(&Self::signed_bytes(member, &encryption_key, &signature_key));
+        LeafNode { member: member.to_string(), encryption_key, signature_key, signature }
+    }
+
+    fn signed_bytes(member: &str, encryption_key: &[u8], signature_key: &[u8]) -> Vec<u8> {
+        encode_fields(&[b"leaf", member.as_bytes(), encryption_key, signature_key])
+    }
+
+    /// Check the leaf is signed by the identity key in it
+    pub fn verify(&self) -> bool {
+        let signed = Self::signed_bytes(&self.member, &self.encryption_key, &self.signature_key);
+        IdentityKeyPair::verify(&self.signature_key, &signed, &self.signature)
+    }
+}
+
+/// What a member publishes so others can add it to a group while it's offline
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyPackage {
+    #[serde(with = "serde_bytes")]
+    pub init_key: Vec<u8>,
+    pub leaf: LeafNode,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+impl KeyPackage {
+    fn signed_bytes(init_key: &[u8], leaf: &LeafNode) -> Vec<u8> {
+        let leaf = serde_json::to_vec(leaf).expect("Failed to encode leaf node");
+        encode_fields(&[b"key package", init_key, &leaf])
+    }
+
+    pub fn member(&self) -> &str {
+        &self.leaf.member
+    }
+
+    /// The identity key the member signs with
+    pub fn identity_key(&self) -> &[u8] {
+        &self.leaf.signature_key
+    }
+
+    /// Check the key package and its leaf are signed by the identity key in it
+    pub fn verify(&self) -> bool {
+        self.leaf.verify()
+            && IdentityKeyPair::verify(&self.leaf.signature_key, &Self::signed_bytes(&self.init_key, &self.leaf), &self.signature)
+    }
+
+    /// Hash naming the key package in a Welcome
+    pub fn reference(&self) -> Vec<u8> {
+        let bytes = serde_json::to_vec(self).expect("Failed to encode key package");
+        digest(&SHA256, &bytes).as_ref().to_vec()
+    }
+}
+
+/// A key package with the private keys needed to join a group through it
+pub struct KeyPackageBundle {
+    pub key_package: KeyPackage,
+    init_secret: SecretKey,
+    leaf_secret: SecretKey,
+}
+
+impl KeyPackageBundle {
+    pub fn generate(member: &str, identity: &IdentityKeyPair) -> Self {
+        let init_secret = SecretKey::random(&mut rand_core::OsRng);
+        let leaf_secret = SecretKey::random(&mut rand_core::OsRng);
+        let init_key = public_bytes(&init_secret);
+        let leaf = LeafNode::new(member, public_bytes(&leaf_secret), identity);
+        let signature = identity.sign(&KeyPackage::signed_bytes(&init_key, &leaf));
+        KeyPackageBundle { key_package: KeyPackage { init_key, leaf, signature }, init_secret, leaf_secret }
+    }
+}
+
+/// A change to the group's members, applied by the next commit
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Proposal {
+    Add { key_package: KeyPackage },
+    Remove { member: String },
+}
+
+/// Ciphertext for the holder of a P-256 private key, sealed with an ephemeral ECDH key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HpkeCiphertext {
+    #[serde(with = "serde_bytes")]
+    pub kem_output: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl HpkeCiphertext {
+    fn seal(public_key: &[u8], context: &[u8], plaintext: &[u8]) -> Result<Self, MlsError> {
+        let ephemeral = SecretKey::random(&mut rand_core::OsRng);
+        let kem_output = public_bytes(&ephemeral);
+        let shared_secret = Crypto::diffie_hellman(&ephemeral, public_key)?;
+        let key = Crypto::create_symmetric_key(&shared_secret, &[kem_output.as_slice(), public_key].concat(), HPKE_KEY_LABEL)?;
+        let ciphertext = Crypto::encrypt_with_aad(&key, plaintext, context)?;
+        Ok(HpkeCiphertext { kem_output, ciphertext })
+    }
+
+    fn open(&self, secret_key: &SecretKey, context: &[u8]) -> Result<Vec<u8>, MlsError> {
+        let shared_secret = Crypto::diffie_hellman(secret_key, &self.kem_output)?;
+        let salt = [self.kem_output.clone(), public_bytes(secret_key)].concat();
+        let key = Crypto::create_symmetric_key(&shared_secret, &salt, HPKE_KEY_LABEL)?;
+        Ok(Crypto::decrypt_with_aad(&key, &self.ciphertext, context)?)
+    }
+}
+
+/// New public key for a node on the committer's direct path
+///
+/// The node's path secret is encrypted to every node in the resolution of the
+/// sibling subtree, so each member below it can derive the new key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdatePathNode {
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+    pub encrypted_path_secret: Vec<HpkeCiphertext>,
+}
+
+/// The committer's new leaf and the new keys for every node above it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdatePath {
+    pub leaf: LeafNode,
+    pub nodes: Vec<UpdatePathNode>,
+}
+
+/// Proposals to apply and fresh keys for the committer's path, starting a new epoch
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Commit {
+    pub proposals: Vec<Proposal>,
+    pub path: UpdatePath,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MlsContent {
+    Proposal { proposal: Proposal },
+    Commit { commit: Commit },
+    /// Message encrypted with the sender's ratchet for the epoch
+    Application {
+        generation: u32,
+        #[serde(with = "serde_bytes")]
+        ciphertext: Vec<u8>,
+    },
+}
+
+/// A signed message for the rest of a group at one epoch
+///
+/// Proposals and commits are readable by the relay, so it can order commits and
+/// keep track of who to fan messages out to; only application messages are encrypted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MlsMessage {
+    pub group_id: String,
+    pub epoch: u64,
+    pub sender: String,
+    pub content: MlsContent,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+    /// MAC over the new epoch's transcript, proving the committer derived the same secrets
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub confirmation_tag: Option<Vec<u8>>,
+}
+
+impl MlsMessage {
+    fn new(group_id: &str, epoch: u64, sender: &str, content: MlsContent, identity: &IdentityKeyPair) -> Self {
+        let signature = identity.sign(&Self::framed_bytes(group_id, epoch, sender, &content));
+        MlsMessage {
+            group_id: group_id.to_string(),
+            epoch,
+            sender: sender.to_string(),
+            content,
+            signature,
+            confirmation_tag: None,
+        }
+    }
+
+    fn framed_bytes(group_id: &str, epoch: u64, sender: &str, content: &MlsContent) -> Vec<u8> {
+        let content = serde_json::to_vec(content).expect("Failed to encode MLS content");
+        encode_fields(&[group_id.as_bytes(), &epoch.to_be_bytes(), sender.as_bytes(), &content])
+    }
+
+    fn verify(&self, signature_key: &[u8]) -> bool {
+        let framed = Self::framed_bytes(&self.group_id, self.epoch, &self.sender, &self.content);
+        IdentityKeyPair::verify(signature_key, &framed, &self.signature)
+    }
+
+    /// What the transcript hash covers: the framed content and its signature
+    fn transcript_bytes(&self) -> Vec<u8> {
+        let framed = Self::framed_bytes(&self.group_id, self.epoch, &self.sender, &self.content);
+        encode_fields(&[&framed, &self.signature])
+    }
+
+    pub fn is_commit(&self) -> bool {
+        matches!(self.content, MlsContent::Commit { .. })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Failed to encode MLS message")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MlsError> {
+        serde_json::from_slice(bytes).map_err(|_| MlsError::Malformed)
+    }
+}
+
+/// Group secrets for one new member, encrypted to its key package's init key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedGroupSecrets {
+    #[serde(with = "serde_bytes")]
+    pub key_package_ref: Vec<u8>,
+    pub encrypted_group_secrets: HpkeCiphertext,
+}
+
+/// Everything members added by a commit need to join the group at the new epoch
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Welcome {
+    pub secrets: Vec<EncryptedGroupSecrets>,
+    #[serde(with = "serde_bytes")]
+    pub encrypted_group_info: Vec<u8>,
+}
+
+impl Welcome {
+    /// Whether the Welcome has secrets for the holder of `key_package`
+    pub fn is_for(&self, key_package: &KeyPackage) -> bool {
+        let reference = key_package.reference();
+        self.secrets.iter().any(|secrets| secrets.key_package_ref == reference)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Failed to encode welcome")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MlsError> {
+        serde_json::from_slice(bytes).map_err(|_| MlsError::Malformed)
+    }
+}
+
+/// Secrets a new member decrypts from a Welcome
+#[derive(Serialize, Deserialize)]
+struct GroupSecrets {
+    joiner_secret: Vec<u8>,
+    /// Path secret of the lowest node above both the committer and the new member
+    path_secret: Option<Vec<u8>>,
+}
+
+/// The group's public state at the new epoch, signed by the committer
+#[derive(Serialize, Deserialize)]
+struct GroupInfo {
+    group_id: String,
+    name: String,
+    epoch: u64,
+    tree: RatchetTree,
+    confirmed_transcript_hash: Vec<u8>,
+    confirmation_tag: Vec<u8>,
+    signer: String,
+    signature: Vec<u8>,
+}
+
+impl GroupInfo {
+    fn signed_bytes(&self) -> Vec<u8> {
+        let tree = serde_json::to_vec(&self.tree).expect("Failed to encode ratchet tree");
+        encode_fields(&[
+            self.group_id.as_bytes(),
+            self.name.as_bytes(),
+            &self.epoch.to_be_bytes(),
+            &tree,
+            &self.confirmed_transcript_hash,
+            &self.confirmation_tag,
+            self.signer.as_bytes(),
+        ])
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ParentNode {
+    public_key: Vec<u8>,
+    /// Leaves added below this node since its key was last set; they don't know its private key
+    unmerged_leaves: Vec<u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Node {
+    Leaf(LeafNode),
+    Parent(ParentNode),
+}
+
+/// The public ratchet tree, as an array with leaves at even indices and parents at odd ones
+///
+/// The tree always has a power of two leaves; blank nodes are `None`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct RatchetTree {
+    nodes: Vec<Option<Node>>,
+}
+
+impl RatchetTree {
+    fn new(leaf: LeafNode) -> Self {
+        RatchetTree { nodes: vec![Some(Node::Leaf(leaf))] }
+    }
+
+    fn leaf_count(&self) -> u32 {
+        (self.nodes.len() as u32).div_ceil(2)
+    }
+
+    fn root(&self) -> u32 {
+        self.leaf_count() - 1
+    }
+
+    /// Check a tree a peer sent has a power of two leaves and every node where it belongs
+    ///
+    /// The index arithmetic assumes a full tree, so anything else has to be turned
+    /// away before it's walked.
+    fn is_well_formed(&self) -> bool {
+        let len = self.nodes.len();
+        if len == 0 || len >= u32::MAX as usize || !(len + 1).is_power_of_two() {
+            return false;
+        }
+        let leaf_count = self.leaf_count();
+        self.nodes.iter().enumerate().all(|(node, slot)| match slot {
+            None => true,
+            Some(Node::Leaf(_)) => node % 2 == 0,
+            Some(Node::Parent(parent)) => {
+                node % 2 == 1 && parent.unmerged_leaves.iter().all(|&leaf_index| leaf_index < leaf_count)
+            }
+        })
+    }
+
+    fn leaf(&self, leaf_index: u32) -> Option<&LeafNode> {
+        match self.nodes.get(2 * leaf_index as usize) {
+            Some(Some(Node::Leaf(leaf))) => Some(leaf),
+            _ => None,
+        }
+    }
+
+    fn leaves(&self) -> impl Iterator<Item = &LeafNode> {
+        (0..self.leaf_count()).filter_map(|leaf_index| self.leaf(leaf_index))
+    }
+
+    fn find_member(&self, member: &str) -> Option<u32> {
+        (0..self.leaf_count()).find(|&leaf_index| self.leaf(leaf_index).is_some_and(|leaf| leaf.member == member))
+    }
+
+    fn public_key(&self, node: u32) -> Option<&[u8]> {
+        match self.nodes.get(node as usize) {
+            Some(Some(Node::Leaf(leaf))) => Some(&leaf.encryption_key),
+            Some(Some(Node::Parent(parent))) => Some(&parent.public_key),
+            _ => None,
+        }
+    }
+
+    /// Nodes from a leaf's parent up to the root
+    fn direct_path(&self, leaf_index: u32) -> Vec<u32> {
+        let root = self.root();
+        let mut node = 2 * leaf_index;
+        let mut path = Vec::new();
+        while node != root {
+            node = parent(node);
+            path.push(node);
+        }
+        path
+    }
+
+    /// Siblings of a leaf and of each node on its direct path below the root
+    fn copath(&self, leaf_index: u32) -> Vec<u32> {
+        let root = self.root();
+        let mut node = 2 * leaf_index;
+        let mut copath = Vec::new();
+        while node != root {
+            copath.push(sibling(node));
+            node = parent(node);
+        }
+        copath
+    }
+
+    /// The fewest non-blank nodes whose private keys cover every member below `node`
+    fn resolution(&self, node: u32) -> Vec<u32> {
+        match &self.nodes[node as usize] {
+            Some(Node::Leaf(_)) => vec![node],
+            Some(Node::Parent(parent)) => {
+                iter::once(node).chain(parent.unmerged_leaves.iter().map(|leaf_index| 2 * leaf_index)).collect()
+            }
+            None if level(node) == 0 => Vec::new(),
+            None => [self.resolution(left(node)), self.resolution(right(node))].concat(),
+        }
+    }
+
+    /// Put a new member in the leftmost blank leaf, doubling the tree if it's full
+    fn add_leaf(&mut self, leaf: LeafNode) -> u32 {
+        let leaf_index = match (0..self.leaf_count()).find(|&leaf_index| self.nodes[2 * leaf_index as usize].is_none()) {
+            Some(leaf_index) => leaf_index,
+            None => {
+                let leaf_index = self.leaf_count();
+                self.nodes.resize(2 * self.nodes.len() + 1, None);
+                leaf_index
+            }
+        };
+        self.nodes[2 * leaf_index as usize] = Some(Node::Leaf(leaf));
+        for node in self.direct_path(leaf_index) {
+            if let Some(Node::Parent(parent)) = &mut self.nodes[node as usize] {
+                parent.unmerged_leaves.push(leaf_index);
+            }
+        }
+        leaf_index
+    }
+
+    /// Blank a leaf and its direct path, then drop right halves of the tree left empty
+    fn remove_leaf(&mut self, leaf_index: u32) {
+        for node in iter::once(2 * leaf_index).chain(self.direct_path(leaf_index)) {
+            self.nodes[node as usize] = None;
+        }
+        while self.nodes.len() > 1 && self.nodes[self.nodes.len().div_ceil(2)..].iter().all(Option::is_none) {
+            self.nodes.truncate(self.nodes.len() / 2);
+        }
+    }
+
+    /// Apply a commit's proposals, removals first, returning the leaves of added members
+    fn apply_proposals(&mut self, proposals: &[Proposal]) -> Result<Vec<u32>, MlsError> {
+        for proposal in proposals {
+            if let Proposal::Remove { member } = proposal {
+                let leaf_index = self.find_member(member).ok_or(MlsError::UnknownMember)?;
+                self.remove_leaf(leaf_index);
+            }
+        }
+        let mut added = Vec::new();
+        for proposal in proposals {
+            if let Proposal::Add { key_package } = proposal {
+                if !key_package.verify() {
+                    return Err(MlsError::InvalidKeyPackage);
+                }
+                if self.find_member(key_package.member()).is_some() {
+                    return Err(MlsError::AlreadyMember);
+                }
+                added.push(self.add_leaf(key_package.leaf.clone()));
+            }
+        }
+        Ok(added)
+    }
+
+    /// Install a committer's new leaf and the new public keys above it
+    fn apply_path(&mut self, leaf_index: u32, path: &UpdatePath) -> Result<(), MlsError> {
+        let direct_path = self.direct_path(leaf_index);
+        if direct_path.len() != path.nodes.len() {
+            return Err(MlsError::InvalidCommit);
+        }
+        self.nodes[2 * leaf_index as usize] = Some(Node::Leaf(path.leaf.clone()));
+        for (node, update) in direct_path.into_iter().zip(&path.nodes) {
+            let parent = ParentNode { public_key: update.public_key.clone(), unmerged_leaves: Vec::new() };
+            self.nodes[node as usize] = Some(Node::Parent(parent));
+        }
+        Ok(())
+    }
+
+    fn hash(&self) -> Vec<u8> {
+        let bytes = serde_json::to_vec(self).expect("Failed to encode ratchet tree");
+        digest(&SHA256, &bytes).as_ref().to_vec()
+    }
+}
+
+/// A member's hash ratchet for application messages in one epoch
+struct SenderRatchet {
+    chain_key: [u8; 32],
+    generation: u32,
+    skipped: HashMap<u32, [u8; 32]>,
+}
+
+impl SenderRatchet {
+    fn new(encryption_secret: &[u8; 32], leaf_index: u32) -> Self {
+        let chain_key = derive_secret(encryption_secret, "application", &leaf_index.to_be_bytes());
+        SenderRatchet { chain_key, generation: 0, skipped: HashMap::new() }
+    }
+}
+
+/// Secrets of one epoch, all derived from the epoch's joiner secret
+struct EpochSecrets {
+    encryption_secret: [u8; 32],
+    confirmation_key: [u8; 32],
+    init_secret: [u8; 32],
+}
+
+impl EpochSecrets {
+    fn derive(joiner_secret: &[u8; 32], group_context: &[u8]) -> Self {
+        let epoch_secret = derive_secret(joiner_secret, "epoch", group_context);
+        EpochSecrets {
+            encryption_secret: derive_secret(&epoch_secret, "encryption", &[]),
+            confirmation_key: derive_secret(&epoch_secret, "confirm", &[]),
+            init_secret: derive_secret(&epoch_secret, "init", &[]),
+        }
+    }
+}
+
+/// Everything about a group that changes with each commit
+struct EpochState {
+    epoch: u64,
+    tree: RatchetTree,
+    /// Private keys of our leaf and the nodes above it whose path secret we know
+    private_keys: HashMap<u32, SecretKey>,
+    confirmed_transcript_hash: Vec<u8>,
+    interim_transcript_hash: Vec<u8>,
+    secrets: EpochSecrets,
+    senders: HashMap<u32, SenderRatchet>,
+}
+
+impl EpochState {
+    /// Enter the epoch `commit` starts, returning its joiner secret and confirmation tag
+    fn next(
+        &self,
+        group_id: &str,
+        tree: RatchetTree,
+        private_keys: HashMap<u32, SecretKey>,
+        commit_secret: &[u8; 32],
+        commit: &MlsMessage,
+    ) -> (EpochState, [u8; 32], Vec<u8>) {
+        let epoch = self.epoch + 1;
+        let confirmed_transcript_hash = sha256(&[&self.interim_transcript_hash, &commit.transcript_bytes()]);
+        let context = group_context(group_id, epoch, &tree.hash(), &confirmed_transcript_hash);
+
+        // The previous epoch's init secret ties the new secrets to everything before
+        let joiner_secret = derive_secret(&extract_secret(&self.secrets.init_secret, commit_secret), "joiner", &context);
+        let secrets = EpochSecrets::derive(&joiner_secret, &context);
+        let confirmation_tag = mac(&secrets.confirmation_key, &confirmed_transcript_hash);
+        let state = EpochState {
+            epoch,
+            tree,
+            private_keys,
+            interim_transcript_hash: sha256(&[&confirmed_transcript_hash, &confirmation_tag]),
+            confirmed_transcript_hash,
+            secrets,
+            senders: HashMap::new(),
+        };
+        (state, joiner_secret, confirmation_tag)
+    }
+}
+
+/// What processing a message did to the group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Processed {
+    Application { sender: String, plaintext: Vec<u8> },
+    /// A proposal waiting for the next commit
+    Proposal { sender: String, proposal: Proposal },
+    Commit { sender: String, added: Vec<String>, removed: Vec<String> },
+    /// The commit removed us from the group
+    Removed { sender: String },
+}
+
+/// An experimental group using TreeKEM key agreement, after MLS (RFC 9420)
+///
+/// Members sit at the leaves of a binary tree of P-256 keys, and every member knows
+/// the private keys on the path from its leaf to the root. A commit replaces the
+/// committer's path and encrypts the new path secrets to one node per sibling
+/// subtree, so adding or removing a member costs O(log n) ciphertexts instead of
+/// a new pairwise key for everyone. The root secret feeds a key schedule that
+/// gives each epoch fresh application keys.
+///
+/// Our own commits only take effect once the relay has accepted them, see
+/// `merge_pending_commit`; until then a commit from someone else wins.
+pub struct MlsGroup {
+    group_id: String,
+    name: String,
+    own_leaf: u32,
+    state: EpochState,
+    pending_proposals: Vec<Proposal>,
+    pending_commit: Option<EpochState>,
+}
+
+impl MlsGroup {
+    /// Start a group with only us in it
+    pub fn create(group_id: &str, name: &str, member: &str, identity: &IdentityKeyPair) -> Self {
+        let leaf_secret = SecretKey::random(&mut rand_core::OsRng);
+        let tree = RatchetTree::new(LeafNode::new(member, public_bytes(&leaf_secret), identity));
+        let context = group_context(group_id, 0, &tree.hash(), &[]);
+        let secrets = EpochSecrets::derive(&random_secret(), &context);
+        MlsGroup {
+            group_id: group_id.to_string(),
+            name: name.to_string(),
+            own_leaf: 0,
+            state: EpochState {
+                epoch: 0,
+                tree,
+                private_keys: HashMap::from([(0, leaf_secret)]),
+                confirmed_transcript_hash: Vec::new(),
+                interim_transcript_hash: Vec::new(),
+                secrets,
+                senders: HashMap::new(),
+            },
+            pending_proposals: Vec::new(),
+            pending_commit: None,
+        }
+    }
+
+    /// Join a group from a Welcome for one of our key packages
+    pub fn join(welcome: &Welcome, key_package: &KeyPackageBundle) -> Result<Self, MlsError> {
+        let reference = key_package.key_package.reference();
+        let secrets = welcome
+            .secrets
+            .iter()
+            .find(|secrets| secrets.key_package_ref == reference)
+            .ok_or(MlsError::NoMatchingKeyPackage)?;
+        let group_secrets = secrets.encrypted_group_secrets.open(&key_package.init_secret, &reference)?;
+        let group_secrets: GroupSecrets = serde_json::from_slice(&group_secrets).map_err(|_| MlsError::Malformed)?;
+        let joiner_secret: [u8; 32] = group_secrets.joiner_secret.as_slice().try_into().map_err(|_| MlsError::Malformed)?;
+
+        let welcome_key = Crypto::create_symmetric_key(&derive_secret(&joiner_secret, "welcome", &[]), &[], WELCOME_KEY_LABEL)?;
+        let group_info = Crypto::decrypt_with_aad(&welcome_key, &welcome.encrypted_group_info, &[])?;
+        let group_info: GroupInfo = serde_json::from_slice(&group_info).map_err(|_| MlsError::Malformed)?;
+        let tree = &group_info.tree;
+        if !tree.is_well_formed() {
+            return Err(MlsError::Malformed);
+        }
+
+        // The committer vouches for the tree, and every leaf must be signed by its member
+        let signer_leaf = tree.find_member(&group_info.signer).ok_or(MlsError::UnknownMember)?;
+        let signer_key = &tree.leaf(signer_leaf).ok_or(MlsError::UnknownMember)?.signature_key;
+        if !IdentityKeyPair::verify(signer_key, &group_info.signed_bytes(), &group_info.signature) {
+            return Err(MlsError::InvalidSignature);
+        }
+        if !tree.leaves().all(LeafNode::verify) {
+            return Err(MlsError::InvalidKeyPackage);
+        }
+        let own_leaf = tree
+            .find_member(key_package.key_package.member())
+            .filter(|&leaf_index| tree.leaf(leaf_index) == Some(&key_package.key_package.leaf))
+            .ok_or(MlsError::NoMatchingKeyPackage)?;
+
+        let context = group_context(&group_info.group_id, group_info.epoch, &tree.hash(), &group_info.confirmed_transcript_hash);
+        let secrets = EpochSecrets::derive(&joiner_secret, &context);
+        let expected_tag = mac(&secrets.confirmation_key, &group_info.confirmed_transcript_hash);
+        if verify_slices_are_equal(&expected_tag, &group_info.confirmation_tag).is_err() {
+            return Err(MlsError::InvalidCommit);
+        }
+
+        // The path secret we're given is for the lowest node above both us and the
+        // committer; the nodes above it follow from it
+        let mut private_keys = HashMap::from([(2 * own_leaf, key_package.leaf_secret.clone())]);
+        if let Some(path_secret) = group_secrets.path_secret {
+            let mut path_secret: [u8; 32] = path_secret.as_slice().try_into().map_err(|_| MlsError::Malformed)?;
+            let direct_path = tree.direct_path(own_leaf);
+            let start = direct_path
+                .iter()
+                .position(|&node| in_subtree(node, 2 * signer_leaf))
+                .ok_or(MlsError::InvalidCommit)?;
+            for &node in &direct_path[start..] {
+                let secret_key = derive_key_pair(&path_secret);
+                if tree.public_key(node) != Some(public_bytes(&secret_key).as_slice()) {
+                    return Err(MlsError::InvalidCommit);
+                }
+                private_keys.insert(node, secret_key);
+                path_secret = derive_secret(&path_secret, "path", &[]);
+            }
+        }
+
+        Ok(MlsGroup {
+            name: group_info.name,
+            own_leaf,
+            state: EpochState {
+                epoch: group_info.epoch,
+                tree: group_info.tree,
+                private_keys,
+                interim_transcript_hash: sha256(&[&group_info.confirmed_transcript_hash, &group_info.confirmation_tag]),
+                confirmed_transcript_hash: group_info.confirmed_transcript_hash,
+                secrets,
+                senders: HashMap::new(),
+            },
+            group_id: group_info.group_id,
+            pending_proposals: Vec::new(),
+            pending_commit: None,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.group_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.state.epoch
+    }
+
+    /// Members in leaf order
+    pub fn members(&self) -> Vec<String> {
+        self.state.tree.leaves().map(|leaf| leaf.member.clone()).collect()
+    }
+
+    pub fn has_pending_commit(&self) -> bool {
+        self.pending_commit.is_some()
+    }
+
+    fn own_member(&self) -> String {
+        self.state.tree.leaf(self.own_leaf).map(|leaf| leaf.member.clone()).unwrap_or_default()
+    }
+
+    /// Propose removing a member, ourselves included, for another member to commit
+    pub fn propose_remove(&self, member: &str, identity: &IdentityKeyPair) -> Result<MlsMessage, MlsError> {
+        if self.state.tree.find_member(member).is_none() {
+            return Err(MlsError::UnknownMember);
+        }
+        let content = MlsContent::Proposal { proposal: Proposal::Remove { member: member.to_string() } };
+        Ok(MlsMessage::new(&self.group_id, self.state.epoch, &self.own_member(), content, identity))
+    }
+
+    /// Commit the proposals received this epoch along with our own adds and removes
+    ///
+    /// Our path gets fresh keys, so removed members can't follow the next epoch. The
+    /// commit is kept pending until `merge_pending_commit`; the Welcome, if anyone
+    /// was added, goes to the new members.
+    pub fn commit(
+        &mut self,
+        add: Vec<KeyPackage>,
+        remove: Vec<String>,
+        identity: &IdentityKeyPair,
+    ) -> Result<(MlsMessage, Option<Welcome>), MlsError> {
+        if self.pending_commit.is_some() {
+            return Err(MlsError::CommitPending);
+        }
+        let own_member = self.own_member();
+        if remove.contains(&own_member) {
+            return Err(MlsError::CannotRemoveSelf);
+        }
+
+        // Someone proposing our removal has to wait for another member to commit it
+        let mut proposals: Vec<Proposal> = Vec::new();
+        let requested = remove
+            .into_iter()
+            .map(|member| Proposal::Remove { member })
+            .chain(add.into_iter().map(|key_package| Proposal::Add { key_package }));
+        for proposal in self.pending_proposals.iter().cloned().chain(requested) {
+            let own_removal = matches!(&proposal, Proposal::Remove { member } if *member == own_member);
+            if !own_removal && !proposals.contains(&proposal) {
+                proposals.push(proposal);
+            }
+        }
+        let key_packages: Vec<KeyPackage> = proposals
+            .iter()
+            .filter_map(|proposal| match proposal {
+                Proposal::Add { key_package } => Some(key_package.clone()),
+                Proposal::Remove { .. } => None,
+            })
+            .collect();
+
+        let mut tree = self.state.tree.clone();
+        let added = tree.apply_proposals(&proposals)?;
+
+        // Fresh keys for our leaf and a chain of path secrets for every node above it
+        let leaf_secret = SecretKey::random(&mut rand_core::OsRng);
+        let leaf = LeafNode::new(&own_member, public_bytes(&leaf_secret), identity);
+        let direct_path = tree.direct_path(self.own_leaf);
+        let mut path_secrets = Vec::with_capacity(direct_path.len());
+        let mut path_secret = random_secret();
+        for _ in &direct_path {
+            path_secrets.push(path_secret);
+            path_secret = derive_secret(&path_secret, "path", &[]);
+        }
+        let commit_secret = path_secret;
+        let node_keys: Vec<SecretKey> = path_secrets.iter().map(derive_key_pair).collect();
+
+        let nodes = node_keys
+            .iter()
+            .map(|secret_key| UpdatePathNode { public_key: public_bytes(secret_key), encrypted_path_secret: Vec::new() })
+            .collect();
+        let mut path = UpdatePath { leaf, nodes };
+        tree.apply_path(self.own_leaf, &path)?;
+
+        // Each path secret goes to the subtree on the other side; new members get theirs in the Welcome
+        let context = hpke_context(&self.group_id, self.state.epoch + 1, &tree.hash());
+        let excluded: Vec<u32> = added.iter().map(|leaf_index| 2 * leaf_index).collect();
+        for ((update, copath_node), path_secret) in path.nodes.iter_mut().zip(tree.copath(self.own_leaf)).zip(&path_secrets) {
+            for node in tree.resolution(copath_node).into_iter().filter(|node| !excluded.contains(node)) {
+                let public_key = tree.public_key(node).ok_or(MlsError::InvalidCommit)?;
+                update.encrypted_path_secret.push(HpkeCiphertext::seal(public_key, &context, path_secret)?);
+            }
+        }
+
+        let content = MlsContent::Commit { commit: Commit { proposals, path } };
+        let mut message = MlsMessage::new(&self.group_id, self.state.epoch, &own_member, content, identity);
+        let mut private_keys = HashMap::from([(2 * self.own_leaf, leaf_secret)]);
+        private_keys.extend(direct_path.iter().copied().zip(node_keys));
+        let (next, joiner_secret, confirmation_tag) =
+            self.state.next(&self.group_id, tree, private_keys, &commit_secret, &message);
+        message.confirmation_tag = Some(confirmation_tag.clone());
+
+        let welcome = if added.is_empty() {
+            None
+        } else {
+            let mut group_info = GroupInfo {
+                group_id: self.group_id.clone(),
+                name: self.name.clone(),
+                epoch: next.epoch,
+                tree: next.tree.clone(),
+                confirmed_transcript_hash: next.confirmed_transcript_hash.clone(),
+                confirmation_tag,
+                signer: own_member,
+                signature: Vec::new(),
+            };
+            group_info.signature = identity.sign(&group_info.signed_bytes());
+            let group_info = serde_json::to_vec(&group_info).expect("Failed to encode group info");
+            let welcome_key = Crypto::create_symmetric_key(&derive_secret(&joiner_secret, "welcome", &[]), &[], WELCOME_KEY_LABEL)?;
+            let encrypted_group_info = Crypto::encrypt_with_aad(&welcome_key, &group_info, &[])?;
+
+            let mut secrets = Vec::with_capacity(added.len());
+            for (leaf_index, key_package) in added.iter().zip(&key_packages) {
+                let path_secret = direct_path
+                    .iter()
+                    .position(|&node| in_subtree(node, 2 * leaf_index))
+                    .map(|position| path_secrets[position].to_vec());
+                let group_secrets = GroupSecrets { joiner_secret: joiner_secret.to_vec(), path_secret };
+                let group_secrets = serde_json::to_vec(&group_secrets).expect("Failed to encode group secrets");
+                let key_package_ref = key_package.reference();
+                let encrypted_group_secrets = HpkeCiphertext::seal(&key_package.init_key, &key_package_ref, &group_secrets)?;
+                secrets.push(EncryptedGroupSecrets { key_package_ref, encrypted_group_secrets });
+            }
+            Some(Welcome { secrets, encrypted_group_info })
+        };
+
+        self.pending_commit = Some(next);
+        Ok((message, welcome))
+    }
+
+    /// Move to the epoch of our pending commit once the relay has accepted it
+    pub fn merge_pending_commit(&mut self) -> bool {
+        let Some(next) = self.pending_commit.take() else {
+            return false;
+        };
+        self.state = next;
+        self.pending_proposals.clear();
+        true
+    }
+
+    /// Forget a commit the relay rejected; its proposals stay pending
+    pub fn clear_pending_commit(&mut self) {
+        self.pending_commit = None;
+    }
+
+    /// Encrypt an application message with the next key of our ratchet for this epoch
+    pub fn encrypt(&mut self, plaintext: &[u8], identity: &IdentityKeyPair) -> Result<MlsMessage, MlsError> {
+        let own_member = self.own_member();
+        let encryption_secret = &self.state.secrets.encryption_secret;
+        let own_leaf = self.own_leaf;
+        let ratchet = self
+            .state
+            .senders
+            .entry(own_leaf)
+            .or_insert_with(|| SenderRatchet::new(encryption_secret, own_leaf));
+
+        let (chain_key, message_key) = kdf_chain(&ratchet.chain_key);
+        let generation = ratchet.generation;
+        let aad = application_aad(&self.group_id, self.state.epoch, &own_member, generation);
+        let key = Crypto::create_symmetric_key(&message_key, &[], APPLICATION_KEY_LABEL)?;
+        let ciphertext = Crypto::encrypt_with_aad(&key, plaintext, &aad)?;
+        ratchet.chain_key = chain_key;
+        ratchet.generation += 1;
+
+        let content = MlsContent::Application { generation, ciphertext };
+        Ok(MlsMessage::new(&self.group_id, self.state.epoch, &own_member, content, identity))
+    }
+
+    /// Verify and apply a message another member sent to the group
+    pub fn process(&mut self, message: &MlsMessage) -> Result<Processed, MlsError> {
+        if message.group_id != self.group_id {
+            return Err(MlsError::WrongGroup);
+        }
+        if message.epoch != self.state.epoch {
+            return Err(MlsError::WrongEpoch);
+        }
+        let sender_leaf = self.state.tree.find_member(&message.sender).ok_or(MlsError::UnknownMember)?;
+        if sender_leaf == self.own_leaf {
+            return Err(MlsError::OwnMessage);
+        }
+        let signature_key = &self.state.tree.leaf(sender_leaf).ok_or(MlsError::UnknownMember)?.signature_key;
+        if !message.verify(signature_key) {
+            return Err(MlsError::InvalidSignature);
+        }
+        let sender = message.sender.clone();
+
+        match &message.content {
+            MlsContent::Application { generation, ciphertext } => {
+                let plaintext = self.decrypt(sender_leaf, &sender, *generation, ciphertext)?;
+                Ok(Processed::Application { sender, plaintext })
+            }
+            MlsContent::Proposal { proposal } => {
+                match proposal {
+                    Proposal::Remove { member } if self.state.tree.find_member(member).is_none() => {
+                        return Err(MlsError::UnknownMember);
+                    }
+                    Proposal::Add { key_package } if !key_package.verify() => return Err(MlsError::InvalidKeyPackage),
+                    _ => {}
+                }
+                if !self.pending_proposals.contains(proposal) {
+                    self.pending_proposals.push(proposal.clone());
+                }
+                Ok(Processed::Proposal { sender, proposal: proposal.clone() })
+            }
+            MlsContent::Commit { commit } => self.process_commit(sender_leaf, message, commit),
+        }
+    }
+
+    fn process_commit(&mut self, sender_leaf: u32, message: &MlsMessage, commit: &Commit) -> Result<Processed, MlsError> {
+        let sender = message.sender.clone();
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        for proposal in &commit.proposals {
+            match proposal {
+                Proposal::Add { key_package } => added.push(key_package.member().to_string()),
+                Proposal::Remove { member } => removed.push(member.clone()),
+            }
+        }
+        if removed.contains(&self.own_member()) {
+            self.pending_commit = None;
+            return Ok(Processed::Removed { sender });
+        }
+
+        // The committer keeps its identity; only its encryption key changes
+        let path = &commit.path;
+        let old_leaf = self.state.tree.leaf(sender_leaf).ok_or(MlsError::UnknownMember)?;
+        if removed.contains(&sender)
+            || path.leaf.member != sender
+            || path.leaf.signature_key != old_leaf.signature_key
+            || !path.leaf.verify()
+        {
+            return Err(MlsError::InvalidCommit);
+        }
+
+        let mut tree = self.state.tree.clone();
+        let added_leaves = tree.apply_proposals(&commit.proposals)?;
+        tree.apply_path(sender_leaf, path)?;
+
+        // Our keys for nodes that were blanked or are replaced by the commit are no use any more
+        let direct_path = tree.direct_path(sender_leaf);
+        let mut private_keys: HashMap<u32, SecretKey> = self
+            .state
+            .private_keys
+            .iter()
+            .filter(|(node, _)| !direct_path.contains(node) && tree.public_key(**node).is_some())
+            .map(|(node, secret_key)| (*node, secret_key.clone()))
+            .collect();
+
+        // The lowest node above both of us had its path secret encrypted to the subtree we're in
+        let copath = tree.copath(sender_leaf);
+        let position = copath
+            .iter()
+            .position(|&node| in_subtree(node, 2 * self.own_leaf))
+            .ok_or(MlsError::InvalidCommit)?;
+        let excluded: Vec<u32> = added_leaves.iter().map(|leaf_index| 2 * leaf_index).collect();
+        let resolution: Vec<u32> = tree
+            .resolution(copath[position])
+            .into_iter()
+            .filter(|node| !excluded.contains(node))
+            .collect();
+        let ciphertexts = &path.nodes[position].encrypted_path_secret;
+        if ciphertexts.len() != resolution.len() {
+            return Err(MlsError::InvalidCommit);
+        }
+        let (ciphertext, secret_key) = resolution
+            .iter()
+            .zip(ciphertexts)
+            .find_map(|(node, ciphertext)| private_keys.get(node).map(|secret_key| (ciphertext, secret_key)))
+            .ok_or(MlsError::InvalidCommit)?;
+        let context = hpke_context(&self.group_id, self.state.epoch + 1, &tree.hash());
+        let path_secret = ciphertext.open(secret_key, &context)?;
+        let mut path_secret: [u8; 32] = path_secret.as_slice().try_into().map_err(|_| MlsError::InvalidCommit)?;
+
+        // Everything from there to the root follows, and must match the keys in the commit
+        for (node, update) in direct_path.iter().zip(&path.nodes).skip(position) {
+            let secret_key = derive_key_pair(&path_secret);
+            if public_bytes(&secret_key) != update.public_key {
+                return Err(MlsError::InvalidCommit);
+            }
+            private_keys.insert(*node, secret_key);
+            path_secret = derive_secret(&path_secret, "path", &[]);
+        }
+
+        let (next, _, confirmation_tag) = self.state.next(&self.group_id, tree, private_keys, &path_secret, message);
+        let confirmed = message
+            .confirmation_tag
+            .as_ref()
+            .is_some_and(|tag| verify_slices_are_equal(tag, &confirmation_tag).is_ok());
+        if !confirmed {
+            return Err(MlsError::InvalidCommit);
+        }
+
+        // A commit of ours for the same epoch lost the race at the relay
+        self.state = next;
+        self.pending_proposals.clear();
+        self.pending_commit = None;
+        Ok(Processed::Commit { sender, added, removed })
+    }
+
+    /// Decrypt an application message, moving the sender's ratchet only once it decrypts
+    fn decrypt(&mut self, sender_leaf: u32, sender: &str, generation: u32, ciphertext: &[u8]) -> Result<Vec<u8>, MlsError> {
+        let encryption_secret = &self.state.secrets.encryption_secret;
+        let ratchet = self
+            .state
+            .senders
+            .entry(sender_leaf)
+            .or_insert_with(|| SenderRatchet::new(encryption_secret, sender_leaf));
+        let aad = application_aad(&self.group_id, self.state.epoch, sender, generation);
+
+        // An earlier message arriving late uses a key we stored when skipping past it
+        if generation < ratchet.generation {
+            let message_key = ratchet.skipped.get(&generation).ok_or(CryptoError::DecryptionFailed)?;
+            let key = Crypto::create_symmetric_key(message_key, &[], APPLICATION_KEY_LABEL)?;
+            let plaintext = Crypto::decrypt_with_aad(&key, ciphertext, &aad)?;
+            ratchet.skipped.remove(&generation);
+            return Ok(plaintext);
+        }
+        if generation - ratchet.generation > MAX_SKIP {
+            return Err(CryptoError::TooManySkippedMessages.into());
+        }
+
+        let mut chain_key = ratchet.chain_key;
+        let mut skipped = Vec::new();
+        for skipped_generation in ratchet.generation..generation {
+            let (next_chain, message_key) = kdf_chain(&chain_key);
+            skipped.push((skipped_generation, message_key));
+            chain_key = next_chain;
+        }
+        let (next_chain, message_key) = kdf_chain(&chain_key);
+        let key = Crypto::create_symmetric_key(&message_key, &[], APPLICATION_KEY_LABEL)?;
+        let plaintext = Crypto::decrypt_with_aad(&key, ciphertext, &aad)?;
+
+        ratchet.chain_key = next_chain;
+        ratchet.generation = generation + 1;
+        ratchet.skipped.extend(skipped);
+        if ratchet.skipped.len() > MAX_SKIP as usize {
+            let oldest = ratchet.generation.saturating_sub(MAX_SKIP);
+            ratchet.skipped.retain(|skipped_generation, _| *skipped_generation >= oldest);
+        }
+        Ok(plaintext)
+    }
+}
+
+/// Reasons the delivery service refuses a group message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// No group exists with the ID
+    UnknownGroup,
+    /// The sender isn't a member of the group, or isn't who the message claims
+    NotMember,
+    /// The message is for an epoch the group has already left, or hasn't reached
+    StaleEpoch,
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeliveryError::UnknownGroup => "unknown group",
+            DeliveryError::NotMember => "not a member of the group",
+            DeliveryError::StaleEpoch => "the group has moved to another epoch",
+        })
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// Who a group message goes to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routing {
+    /// Members at the message's epoch other than the sender, including any the commit removes
+    pub recipients: Vec<String>,
+    /// Members a commit adds, who get its Welcome instead
+    pub welcomed: Vec<String>,
+}
+
+struct DeliveryGroup {
+    epoch: u64,
+    members: Vec<String>,
+}
+
+/// The relay's side of MLS: orders commits and fans messages out to members
+///
+/// The relay can't read group secrets, but proposals and commits are in the clear,
+/// so it follows each group's epoch and members from them. Only the first commit
+/// for an epoch is accepted; a member whose commit loses the race gets
+/// `StaleEpoch` and processes the winning one instead. A group is created by the
+/// first commit of epoch 0.
+#[derive(Default)]
+pub struct DeliveryService {
+    groups: HashMap<String, DeliveryGroup>,
+}
+
+impl DeliveryService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check a message from `sender` fits the group's current epoch and work out who gets it
+    pub fn route(&mut self, sender: &str, message: &MlsMessage) -> Result<Routing, DeliveryError> {
+        if message.sender != sender {
+            return Err(DeliveryError::NotMember);
+        }
+        if message.is_commit() && message.epoch == 0 && !self.groups.contains_key(&message.group_id) {
+            let group = DeliveryGroup { epoch: 0, members: vec![sender.to_string()] };
+            self.groups.insert(message.group_id.clone(), group);
+        }
+        let group = self.groups.get_mut(&message.group_id).ok_or(DeliveryError::UnknownGroup)?;
+        if !group.members.iter().any(|member| member == sender) {
+            return Err(DeliveryError::NotMember);
+        }
+        if message.epoch != group.epoch {
+            return Err(DeliveryError::StaleEpoch);
+        }
+
+        let recipients = group.members.iter().filter(|member| *member != sender).cloned().collect();
+        let MlsContent::Commit { commit } = &message.content else {
+            return Ok(Routing { recipients, welcomed: Vec::new() });
+        };
+        let mut welcomed = Vec::new();
+        for proposal in &commit.proposals {
+            match proposal {
+                Proposal::Remove { member } => group.members.retain(|existing| existing != member),
+                Proposal::Add { key_package } => {
+                    let member = key_package.member().to_string();
+                    if !group.members.contains(&member) {
+                        group.members.push(member.clone());
+                        welcomed.push(member);
+                    }
+                }
+            }
+        }
+        group.epoch += 1;
+        if group.members.is_empty() {
+            self.groups.remove(&message.group_id);
+        }
+        Ok(Routing { recipients, welcomed })
+    }
+
+    /// Current members of a group
+    pub fn members(&self, group_id: &str) -> Option<&[String]> {
+        self.groups.get(group_id).map(|group| group.members.as_slice())
+    }
+}
+
+/// Level of a node in the array tree; leaves are level 0
+fn level(node: u32) -> u32 {
+    node.trailing_ones()
+}
+
+fn left(node: u32) -> u32 {
+    node ^ (1 << (level(node) - 1))
+}
+
+fn right(node: u32) -> u32 {
+    node ^ (3 << (level(node) - 1))
+}
+
+fn parent(node: u32) -> u32 {
+    let level = level(node);
+    let side = (node >> (level + 1)) & 1;
+    (node | (1 << level)) ^ (side << (level + 1))
+}
+
+fn sibling(node: u32) -> u32 {
+    let parent = parent(node);
+    if node < parent {
+        right(parent)
+    } else {
+        left(parent)
+    }
+}
+
+/// Whether `node` is `ancestor` or below it
+fn in_subtree(ancestor: u32, node: u32) -> bool {
+    let span = (1 << level(ancestor)) - 1;
+    node + span >= ancestor && node <= ancestor + span
+}
+
+/// Encode fields unambiguously, with each one length-prefixed
+fn encode_fields(fields: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for field in fields {
+        bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(field);
+    }
+    bytes
+}
+
+/// The group's state at an epoch, mixed into the key schedule
+fn group_context(group_id: &str, epoch: u64, tree_hash: &[u8], confirmed_transcript_hash: &[u8]) -> Vec<u8> {
+    encode_fields(&[group_id.as_bytes(), &epoch.to_be_bytes(), tree_hash, confirmed_transcript_hash])
+}
+
+/// What path secrets are bound to: the group, the epoch they start and the tree they lead to
+fn hpke_context(group_id: &str, epoch: u64, tree_hash: &[u8]) -> Vec<u8> {
+    encode_fields(&[b"path secret", group_id.as_bytes(), &epoch.to_be_bytes(), tree_hash])
+}
+
+fn application_aad(group_id: &str, epoch: u64, sender: &str, generation: u32) -> Vec<u8> {
+    encode_fields(&[b"application", group_id.as_bytes(), &epoch.to_be_bytes(), sender.as_bytes(), &generation.to_be_bytes()])
+}
+
+/// Expand a secret into a new one for `label`, bound to `context`
+fn derive_secret(secret: &[u8; 32], label: &str, context: &[u8]) -> [u8; 32] {
+    let label = [LABEL_PREFIX, label.as_bytes()].concat();
+    let mut output = [0u8; 32];
+    Prk::new_less_safe(HKDF_SHA256, secret)
+        .expand(&[&label, context], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut output))
+        .expect("HKDF output is one hash long");
+    output
+}
+
+/// Combine a salt and a secret into a new secret
+fn extract_secret(salt: &[u8; 32], secret: &[u8; 32]) -> [u8; 32] {
+    let mut output = [0u8; 32];
+    Salt::new(HKDF_SHA256, salt)
+        .extract(secret)
+        .expand(&[LABEL_PREFIX, b"extract"], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut output))
+        .expect("HKDF output is one hash long");
+    output
+}
+
+/// Derive a node's key pair from its path secret
+fn derive_key_pair(path_secret: &[u8; 32]) -> SecretKey {
+    let mut seed = derive_secret(path_secret, "node", &[]);
+    // Almost every 32-byte string is a valid scalar; try again with a new one if not
+    loop {
+        if let Ok(secret_key) = SecretKey::from_be_bytes(&seed) {
+            return secret_key;
+        }
+        seed = derive_secret(&seed, "node", &[]);
+    }
+}
+
+fn public_bytes(secret_key: &SecretKey) -> Vec<u8> {
+    secret_key.public_key().to_encoded_point(false).as_bytes().to_vec()
+}
+
+fn random_secret() -> [u8; 32] {
+    let mut secret = [0u8; 32];
+    SystemRandom::new().fill(&mut secret).expect("Random number generator failed");
+    secret
+}
+
+fn sha256(parts: &[&[u8]]) -> Vec<u8> {
+    digest(&SHA256, &parts.concat()).as_ref().to_vec()
+}
+
+fn mac(key: &[u8; 32], message: &[u8]) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), message).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    const GROUP_ID: &str = "5d1f3c2e-8a4b-4f6d-9e0a-2b7c1d3e4f5a";
+
+    struct Client {
+        identity: Rc<IdentityKeyPair>,
+        group: Option<MlsGroup>,
+    }
+
+    /// A relay and the clients around it, each holding its own copy of one group
+    struct Harness {
+        delivery: DeliveryService,
+        clients: HashMap<String, Client>,
+    }
+
+    impl Harness {
+        fn new(names: &[&str]) -> Self {
+            let clients = names
+                .iter()
+                .map(|name| (name.to_string(), Client { identity: Rc::new(IdentityKeyPair::generate()), group: None }))
+                .collect();
+            Harness { delivery: DeliveryService::new(), clients }
+        }
+
+        fn group(&mut self, name: &str) -> &mut MlsGroup {
+            self.clients.get_mut(name).and_then(|client| client.group.as_mut()).expect("not in the group")
+        }
+
+        /// Start the group with `creator` and add `members` in its first commit
+        fn create(&mut self, creator: &str, members: &[&str]) {
+            let client = self.clients.get_mut(creator).unwrap();
+            client.group = Some(MlsGroup::create(GROUP_ID, "test", creator, &client.identity));
+            self.commit(creator, members, &[]).unwrap();
+        }
+
+        /// Commit adds and removes through the relay, then hand the result to everyone it's for
+        fn commit(&mut self, committer: &str, add: &[&str], remove: &[&str]) -> Result<(), DeliveryError> {
+            let bundles: HashMap<&str, KeyPackageBundle> = add
+                .iter()
+                .map(|name| (*name, KeyPackageBundle::generate(name, &self.clients[*name].identity)))
+                .collect();
+            let key_packages = add.iter().map(|name| bundles[name].key_package.clone()).collect();
+            let remove = remove.iter().map(|name| name.to_string()).collect();
+            let identity = self.clients[committer].identity.clone();
+            let (message, welcome) = self.group(committer).commit(key_packages, remove, &identity).unwrap();
+
+            let routing = match self.delivery.route(committer, &message) {
+                Ok(routing) => routing,
+                Err(e) => {
+                    self.group(committer).clear_pending_commit();
+                    return Err(e);
+                }
+            };
+            assert!(self.group(committer).merge_pending_commit());
+            for recipient in &routing.recipients {
+                let client = self.clients.get_mut(recipient).unwrap();
+                match client.group.as_mut().unwrap().process(&message).unwrap() {
+                    Processed::Removed { .. } => client.group = None,
+                    Processed::Commit { sender, .. } => assert_eq!(sender, committer),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            for member in &routing.welcomed {
+                let welcome = welcome.as_ref().expect("commit adding members has a welcome");
+                let group = MlsGroup::join(welcome, &bundles[member.as_str()]).unwrap();
+                self.clients.get_mut(member).unwrap().group = Some(group);
+            }
+            Ok(())
+        }
+
+        /// Propose our own removal for another member to commit
+        fn propose_leave(&mut self, member: &str) {
+            let identity = self.clients[member].identity.clone();
+            let proposal = self.group(member).propose_remove(member, &identity).unwrap();
+            for recipient in self.delivery.route(member, &proposal).unwrap().recipients {
+                let processed = self.group(&recipient).process(&proposal).unwrap();
+                assert!(matches!(processed, Processed::Proposal { .. }));
+            }
+        }
+
+        /// Send an application message and check every other member reads it
+        fn send(&mut self, sender: &str, text: &str) {
+            let identity = self.clients[sender].identity.clone();
+            let message = self.group(sender).encrypt(text.as_bytes(), &identity).unwrap();
+            let recipients = self.delivery.route(sender, &message).unwrap().recipients;
+            assert!(!recipients.is_empty());
+            for recipient in recipients {
+                let processed = self.group(&recipient).process(&message).unwrap();
+                let expected = Processed::Application { sender: sender.to_string(), plaintext: text.as_bytes().to_vec() };
+                assert_eq!(processed, expected);
+            }
+        }
+
+        /// Every member agrees with the relay on the epoch and who's in the group
+        fn assert_in_step(&self, epoch: u64, members: &[&str]) {
+            let mut expected: Vec<String> = members.iter().map(|name| name.to_string()).collect();
+            expected.sort();
+            let mut relay = self.delivery.members(GROUP_ID).unwrap().to_vec();
+            relay.sort();
+            assert_eq!(relay, expected);
+
+            for (name, client) in &self.clients {
+                match &client.group {
+                    Some(group) => {
+                        assert!(expected.contains(name), "{} should have left", name);
+                        assert_eq!(group.epoch(), epoch, "{} is at the wrong epoch", name);
+                        let mut members = group.members();
+                        members.sort();
+                        assert_eq!(members, expected);
+                    }
+                    None => assert!(!expected.contains(name), "{} should be in the group", name),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn create_add_remove_and_leave_advance_every_member() {
+        let mut harness = Harness::new(&["alice", "bob", "carol", "dave", "erin"]);
+        harness.create("alice", &["bob", "carol"]);
+        harness.assert_in_step(1, &["alice", "bob", "carol"]);
+
+        harness.commit("bob", &["dave", "erin"], &[]).unwrap();
+        harness.assert_in_step(2, &["alice", "bob", "carol", "dave", "erin"]);
+
+        harness.commit("carol", &[], &["bob"]).unwrap();
+        harness.assert_in_step(3, &["alice", "carol", "dave", "erin"]);
+
+        harness.propose_leave("dave");
+        harness.commit("erin", &[], &[]).unwrap();
+        harness.assert_in_step(4, &["alice", "carol", "erin"]);
+
+        harness.send("alice", "still here");
+        harness.send("erin", "me too");
+    }
+
+    #[test]
+    fn members_cannot_commit_their_own_removal() {
+        let mut harness = Harness::new(&["alice", "bob"]);
+        harness.create("alice", &["bob"]);
+        let identity = harness.clients["bob"].identity.clone();
+        let result = harness.group("bob").commit(Vec::new(), vec!["bob".to_string()], &identity);
+        assert_eq!(result.err(), Some(MlsError::CannotRemoveSelf));
+    }
+
+    #[test]
+    fn application_messages_work_across_epoch_changes() {
+        let mut harness = Harness::new(&["alice", "bob", "carol", "dave"]);
+        harness.create("alice", &["bob", "carol"]);
+        harness.send("alice", "epoch one");
+        harness.send("bob", "hello");
+
+        // A message sent just before a commit can't be read in the new epoch
+        let identity = harness.clients["carol"].identity.clone();
+        let late = harness.group("carol").encrypt(b"too late", &identity).unwrap();
+        harness.commit("bob", &["dave"], &[]).unwrap();
+        assert_eq!(harness.delivery.route("carol", &late).err(), Some(DeliveryError::StaleEpoch));
+        assert_eq!(harness.group("alice").process(&late).err(), Some(MlsError::WrongEpoch));
+
+        harness.send("carol", "epoch two");
+        harness.send("dave", "hi all");
+
+        // Removed members are left behind at the old epoch
+        harness.commit("alice", &[], &["carol"]).unwrap();
+        harness.assert_in_step(3, &["alice", "bob", "dave"]);
+        harness.send("bob", "carol can't read this");
+    }
+
+    #[test]
+    fn application_messages_out_of_order_and_tampered() {
+        let mut harness = Harness::new(&["alice", "bob"]);
+        harness.create("alice", &["bob"]);
+        let identity = harness.clients["alice"].identity.clone();
+        let messages: Vec<MlsMessage> = (0..3)
+            .map(|i| harness.group("alice").encrypt(format!("message {}", i).as_bytes(), &identity).unwrap())
+            .collect();
+
+        // Skipped keys are kept for late messages, and each can only be used once
+        for i in [2, 0, 1] {
+            let processed = harness.group("bob").process(&messages[i]).unwrap();
+            let plaintext = format!("message {}", i).into_bytes();
+            assert_eq!(processed, Processed::Application { sender: "alice".to_string(), plaintext });
+        }
+        assert!(harness.group("bob").process(&messages[1]).is_err());
+
+        let mut tampered = harness.group("alice").encrypt(b"original", &identity).unwrap();
+        if let MlsContent::Application { ciphertext, .. } = &mut tampered.content {
+            let last = ciphertext.len() - 1;
+            ciphertext[last] ^= 1;
+        }
+        assert_eq!(harness.group("bob").process(&tampered).err(), Some(MlsError::InvalidSignature));
+
+        let mut forged = harness.group("alice").encrypt(b"original", &identity).unwrap();
+        forged.sender = "bob".to_string();
+        assert_eq!(harness.group("bob").process(&forged).err(), Some(MlsError::OwnMessage));
+    }
+
+    #[test]
+    fn relay_rejects_a_second_commit_for_the_same_epoch() {
+        let mut harness = Harness::new(&["alice", "bob", "carol"]);
+        harness.create("alice", &["bob", "carol"]);
+
+        // Bob's commit is prepared first, but Alice's reaches the relay first
+        let identity = harness.clients["bob"].identity.clone();
+        let (stale, _) = harness.group("bob").commit(Vec::new(), vec!["carol".to_string()], &identity).unwrap();
+        harness.commit("alice", &[], &[]).unwrap();
+        assert!(!harness.group("bob").has_pending_commit());
+        assert_eq!(harness.delivery.route("bob", &stale).err(), Some(DeliveryError::StaleEpoch));
+        assert_eq!(harness.group("carol").process(&stale).err(), Some(MlsError::WrongEpoch));
+        harness.assert_in_step(2, &["alice", "bob", "carol"]);
+
+        // Bob retries at the new epoch
+        harness.commit("bob", &[], &["carol"]).unwrap();
+        harness.assert_in_step(3, &["alice", "bob"]);
+        harness.send("alice", "just us");
+    }
+
+    #[test]
+    fn relay_rejects_senders_outside_the_group() {
+        let mut harness = Harness::new(&["alice", "bob", "mallory"]);
+        harness.create("alice", &["bob"]);
+        let identity = harness.clients["alice"].identity.clone();
+        let message = harness.group("alice").encrypt(b"hi", &identity).unwrap();
+        assert_eq!(harness.delivery.route("mallory", &message).err(), Some(DeliveryError::NotMember));
+
+        let mut elsewhere = message.clone();
+        elsewhere.group_id = "another group".to_string();
+        assert_eq!(harness.delivery.route("alice", &elsewhere).err(), Some(DeliveryError::UnknownGroup));
+    }
+
+    #[test]
+    fn welcome_joins_only_its_own_key_package() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let mut group = MlsGroup::create(GROUP_ID, "test", "alice", &alice);
+        let bundle = KeyPackageBundle::generate("bob", &bob);
+        let other = KeyPackageBundle::generate("bob", &bob);
+        let (_, welcome) = group.commit(vec![bundle.key_package.clone()], Vec::new(), &alice).unwrap();
+        let welcome = Welcome::from_bytes(&welcome.unwrap().to_bytes()).unwrap();
+        assert!(group.merge_pending_commit());
+
+        assert!(welcome.is_for(&bundle.key_package));
+        assert!(!welcome.is_for(&other.key_package));
+        assert_eq!(MlsGroup::join(&welcome, &other).err(), Some(MlsError::NoMatchingKeyPackage));
+
+        let mut tampered = welcome.clone();
+        tampered.encrypted_group_info[20] ^= 1;
+        assert!(MlsGroup::join(&tampered, &bundle).is_err());
+
+        let mut joined = MlsGroup::join(&welcome, &bundle).unwrap();
+        assert_eq!(joined.id(), GROUP_ID);
+        assert_eq!(joined.name(), "test");
+        assert_eq!(joined.epoch(), group.epoch());
+        assert_eq!(joined.members(), group.members());
+
+        let message = joined.encrypt(b"thanks for the invite", &bob).unwrap();
+        let processed = group.process(&message).unwrap();
+        let plaintext = b"thanks for the invite".to_vec();
+        assert_eq!(processed, Processed::Application { sender: "bob".to_string(), plaintext });
+    }
+
+    #[test]
+    fn key_packages_must_be_signed_by_their_member() {
+        let alice = IdentityKeyPair::generate();
+        let mut group = MlsGroup::create(GROUP_ID, "test", "alice", &alice);
+        let mut key_package = KeyPackageBundle::generate("bob", &IdentityKeyPair::generate()).key_package;
+        key_package.leaf.member = "carol".to_string();
+        assert!(!key_package.verify());
+        assert_eq!(group.commit(vec![key_package], Vec::new(), &alice).err(), Some(MlsError::InvalidKeyPackage));
+    }
+
+    #[test]
+    fn trees_must_be_full_with_nodes_in_place() {
+        let identity = IdentityKeyPair::generate();
+        let leaf = LeafNode::new("alice", vec![4; 65], &identity);
+        let parent = ParentNode { public_key: vec![4; 65], unmerged_leaves: Vec::new() };
+
+        let mut tree = RatchetTree::new(leaf.clone());
+        assert!(tree.is_well_formed());
+        tree.add_leaf(leaf.clone());
+        tree.add_leaf(leaf.clone());
+        assert!(tree.is_well_formed());
+
+        let tree = |nodes: Vec<Option<Node>>| RatchetTree { nodes };
+        assert!(!tree(Vec::new()).is_well_formed());
+        assert!(!tree(vec![Some(Node::Leaf(leaf.clone())); 6]).is_well_formed());
+        assert!(!tree(vec![Some(Node::Leaf(leaf.clone())), None]).is_well_formed());
+        assert!(!tree(vec![Some(Node::Leaf(leaf.clone())); 3]).is_well_formed());
+        assert!(!tree(vec![Some(Node::Parent(parent.clone())), None, None]).is_well_formed());
+        let unmerged = ParentNode { public_key: vec![4; 65], unmerged_leaves: vec![2] };
+        assert!(!tree(vec![None, Some(Node::Parent(unmerged)), None]).is_well_formed());
+        assert!(tree(vec![Some(Node::Leaf(leaf)), Some(Node::Parent(parent)), None]).is_well_formed());
+    }
+
+    #[test]
+    fn welcome_with_a_malformed_tree_is_rejected() {
+        let alice = IdentityKeyPair::generate();
+        let bob = IdentityKeyPair::generate();
+        let bundle = KeyPackageBundle::generate("bob", &bob);
+        let alice_leaf = LeafNode::new("alice", vec![4; 65], &alice);
+
+        // Six nodes would put the root on a leaf and send the path walk off the end
+        let mut nodes = vec![None; 6];
+        nodes[0] = Some(Node::Leaf(alice_leaf));
+        nodes[2] = Some(Node::Leaf(bundle.key_package.leaf.clone()));
+        let mut group_info = GroupInfo {
+            group_id: GROUP_ID.to_string(),
+            name: "test".to_string(),
+            epoch: 1,
+            tree: RatchetTree { nodes },
+            confirmed_transcript_hash: Vec::new(),
+            confirmation_tag: Vec::new(),
+            signer: "alice".to_string(),
+            signature: Vec::new(),
+        };
+        group_info.signature = alice.sign(&group_info.signed_bytes());
+
+        let joiner_secret = random_secret();
+        let group_secrets = GroupSecrets { joiner_secret: joiner_secret.to_vec(), path_secret: Some(random_secret().to_vec()) };
+        let key_package_ref = bundle.key_package.reference();
+        let encrypted_group_secrets = HpkeCiphertext::seal(
+            &bundle.key_package.init_key,
+            &key_package_ref,
+            &serde_json::to_vec(&group_secrets).unwrap(),
+        )
+        .unwrap();
+        let welcome_key =
+            Crypto::create_symmetric_key(&derive_secret(&joiner_secret, "welcome", &[]), &[], WELCOME_KEY_LABEL).unwrap();
+        let encrypted_group_info =
+            Crypto::encrypt_with_aad(&welcome_key, &serde_json::to_vec(&group_info).unwrap(), &[]).unwrap();
+        let welcome = Welcome { secrets: vec![EncryptedGroupSecrets { key_package_ref, encrypted_group_secrets }], encrypted_group_info };
+
+        assert_eq!(MlsGroup::join(&welcome, &bundle).err(), Some(MlsError::Malformed));
+    }
+
+    #[test]
+    fn array_tree_arithmetic() {
+        assert_eq!(parent(0), 1);
+        assert_eq!(parent(2), 1);
+        assert_eq!(parent(1), 3);
+        assert_eq!(parent(5), 3);
+        assert_eq!(sibling(0), 2);
+        assert_eq!(sibling(5), 1);
+        assert_eq!((left(3), right(3)), (1, 5));
+        assert!(in_subtree(3, 6));
+        assert!(!in_subtree(1, 4));
+    }
+}
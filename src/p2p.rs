@@ -1,24 +1,351 @@
+use crate::framing::{read_frame, write_frame, Frame, FrameType};
+use crate::handshake::{self, SecureLink};
+use crate::identity::IdentityKeyPair;
+use crate::protocol::Envelope;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Mutex};
 
-pub async fn start_p2p_listener() {
-    let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
+/// Most addresses a peer may advertise, and the most tried when connecting to it
+pub const MAX_PEER_ADDRESSES: usize = 4;
 
-    println!("P2P listener running on 127.0.0.1:8080");
-    loop {
-        let (stream, _) = listener.accept().await.unwrap();
-        tokio::spawn(handle_connection(stream));
-        //maybe add a sleep() here so it doesn't kill my computer?
+/// How long to wait for a direct connection, across all of a peer's addresses, before falling back to the relay
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a peer gets to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a peer gets to acknowledge an envelope before the relay is used instead
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Listen for direct connections from peers, returning the bound address
+///
+/// Accepted connections join `links` once the peer completes the handshake, so
+/// replies to a peer reuse the connection it opened.
+pub async fn start_p2p_listener(addr: &str, links: DirectLinks) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+
+    println!("P2P listener running on {}", local_addr);
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    let links = links.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = links.accept(stream).await {
+                            println!("Refused direct link from {}: {}", peer_addr, e);
+                        }
+                    });
+                }
+                Err(e) => println!("Failed to accept P2P connection: {}", e),
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+/// Envelopes waiting for the peer's acknowledgement, keyed by peer and message ID
+type Unacknowledged = HashMap<(String, String), oneshot::Sender<()>>;
+
+/// Long-lived, authenticated direct connections to peers, keyed by user ID
+///
+/// Each connection carries envelopes both ways once the handshake succeeds. Only
+/// peers whose identity key has been registered with `trust` are accepted.
+/// Envelopes received on any link are passed to the `inbound` channel and
+/// acknowledged to the peer.
+#[derive(Clone)]
+pub struct DirectLinks {
+    identity: Arc<IdentityKeyPair>,
+    own_id: String,
+    links: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Frame>>>>,
+    trusted: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    unacknowledged: Arc<Mutex<Unacknowledged>>,
+    inbound: mpsc::UnboundedSender<Envelope>,
+}
+
+impl DirectLinks {
+    pub fn new(identity: Arc<IdentityKeyPair>, own_id: &str, inbound: mpsc::UnboundedSender<Envelope>) -> Self {
+        DirectLinks {
+            identity,
+            own_id: own_id.to_string(),
+            links: Arc::new(Mutex::new(HashMap::new())),
+            trusted: Arc::new(Mutex::new(HashMap::new())),
+            unacknowledged: Arc::new(Mutex::new(HashMap::new())),
+            inbound,
+        }
+    }
+
+    /// Allow direct links with a peer that proves it holds `identity_key`
+    pub async fn trust(&self, peer_id: &str, identity_key: &[u8]) {
+        self.trusted.lock().await.insert(peer_id.to_string(), identity_key.to_vec());
+    }
+
+    /// Send an envelope straight to a peer, connecting to one of its addresses if needed
+    ///
+    /// Only returns once the peer acknowledges the envelope. Returns an error if no
+    /// address is reachable, the peer fails the handshake or the acknowledgement
+    /// doesn't arrive in time, so the caller can use the relay instead.
+    pub async fn send(&self, peer_id: &str, addresses: &[String], envelope: &Envelope) -> io::Result<()> {
+        let payload = serde_json::to_vec(envelope).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let key = (peer_id.to_string(), envelope.message_id.clone());
+        let (acknowledged_tx, acknowledged) = oneshot::channel();
+        self.unacknowledged.lock().await.insert(key.clone(), acknowledged_tx);
+
+        let result = match self.deliver(peer_id, addresses, Frame::new(FrameType::Envelope, payload)).await {
+            Ok(link) => match tokio::time::timeout(ACK_TIMEOUT, acknowledged).await {
+                Ok(Ok(())) => Ok(()),
+                _ => {
+                    // The link may be dead without the connection having noticed yet
+                    self.forget(peer_id, &link).await;
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "peer didn't acknowledge the envelope"))
+                }
+            },
+            Err(e) => Err(e),
+        };
+        self.unacknowledged.lock().await.remove(&key);
+        result
+    }
+
+    /// Queue a frame on an open link to the peer, or a new one, returning the link it went out on
+    async fn deliver(&self, peer_id: &str, addresses: &[String], frame: Frame) -> io::Result<mpsc::UnboundedSender<Frame>> {
+        // Reuse an open link, in either direction, before dialing the peer
+        let link = self.links.lock().await.get(peer_id).cloned();
+        let frame = match link {
+            Some(link) => match link.send(frame) {
+                Ok(()) => return Ok(link),
+                Err(mpsc::error::SendError(frame)) => frame,
+            },
+            None => frame,
+        };
+
+        let Some(peer_identity_key) = self.trusted.lock().await.get(peer_id).cloned() else {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "no trusted identity key for peer"));
+        };
+        let mut stream = connect(addresses).await?;
+        let handshake = handshake::initiate(&mut stream, &self.identity, &self.own_id, peer_id, &peer_identity_key);
+        let secure_link = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+
+        let link = self.attach(stream, secure_link).await;
+        link.send(frame).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "direct link closed"))?;
+        Ok(link)
+    }
+
+    /// Forget the link to a peer unless it has already been replaced by a newer one
+    async fn forget(&self, peer_id: &str, link: &mpsc::UnboundedSender<Frame>) {
+        let mut links = self.links.lock().await;
+        if links.get(peer_id).is_some_and(|current| current.same_channel(link)) {
+            links.remove(peer_id);
+        }
+    }
+
+    /// Run the responder side of the handshake on an inbound connection
+    async fn accept(&self, mut stream: TcpStream) -> io::Result<()> {
+        let trusted = self.trusted.lock().await.clone();
+        let authorize = |peer_id: &str, identity_key: &[u8]| trusted.get(peer_id).is_some_and(|key| key == identity_key);
+        let secure_link = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake::respond(&mut stream, &self.identity, authorize))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+
+        println!("Direct link established with {}", secure_link.peer_id);
+        self.attach(stream, secure_link).await;
+        Ok(())
+    }
+
+    /// Start encrypting and decrypting frames on an authenticated connection
+    async fn attach(&self, stream: TcpStream, secure_link: SecureLink) -> mpsc::UnboundedSender<Frame> {
+        let SecureLink { peer_id, mut send_cipher, mut receive_cipher, .. } = secure_link;
+        let (mut reader, mut writer) = stream.into_split();
+        let (link, mut outgoing) = mpsc::unbounded_channel::<Frame>();
+        self.links.lock().await.insert(peer_id.clone(), link.clone());
+
+        let writer_peer_id = peer_id.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = outgoing.recv().await {
+                let Ok(ciphertext) = send_cipher.seal(&frame.payload) else {
+                    break;
+                };
+                if let Err(e) = write_frame(&mut writer, &Frame::new(frame.frame_type, ciphertext)).await {
+                    println!("Failed to write to peer {}: {}", writer_peer_id, e);
+                    break;
+                }
+            }
+        });
+
+        let links = self.clone();
+        let this_link = link.clone();
+        tokio::spawn(async move {
+            loop {
+                let frame = match read_frame(&mut reader).await {
+                    Ok(Some(frame)) if frame.frame_type != FrameType::Handshake => frame,
+                    Ok(Some(_)) => {
+                        println!("Unexpected frame from peer {}", peer_id);
+                        break;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("Dropping direct link to {}: {}", peer_id, e);
+                        break;
+                    }
+                };
+
+                // A frame that fails to decrypt means the stream was tampered with
+                let Ok(payload) = receive_cipher.open(&frame.payload) else {
+                    println!("Dropping direct link to {}: frame failed to decrypt", peer_id);
+                    break;
+                };
+                if frame.frame_type == FrameType::Ack {
+                    let message_id = String::from_utf8_lossy(&payload).into_owned();
+                    if let Some(acknowledged) = links.unacknowledged.lock().await.remove(&(peer_id.clone(), message_id)) {
+                        let _ = acknowledged.send(());
+                    }
+                    continue;
+                }
+                let Ok(envelope) = serde_json::from_slice::<Envelope>(&payload) else {
+                    println!("Malformed envelope from peer {}", peer_id);
+                    continue;
+                };
+                if envelope.from != peer_id {
+                    println!("Dropped envelope from peer {} claiming to be from {}", peer_id, envelope.from);
+                    continue;
+                }
+                let message_id = envelope.message_id.clone().into_bytes();
+                if links.inbound.send(envelope).is_err() {
+                    break;
+                }
+                let _ = this_link.send(Frame::new(FrameType::Ack, message_id));
+            }
+            links.forget(&peer_id, &this_link).await;
+        });
+
+        link
+    }
+}
+
+/// Connect to the first reachable address, giving up on all of them after `CONNECT_TIMEOUT`
+///
+/// Only socket addresses are tried, so a peer can't make us resolve host names.
+async fn connect(addresses: &[String]) -> io::Result<TcpStream> {
+    let attempts = async {
+        for address in addresses.iter().take(MAX_PEER_ADDRESSES) {
+            let Ok(address) = address.parse::<SocketAddr>() else {
+                continue;
+            };
+            if let Ok(stream) = TcpStream::connect(address).await {
+                return Some(stream);
+            }
+        }
+        None
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, attempts).await {
+        Ok(Some(stream)) => Ok(stream),
+        _ => Err(io::Error::new(io::ErrorKind::NotConnected, "no reachable peer address")),
     }
 }
 
-async fn handle_connection(mut stream: TcpStream) {
-    let mut buffer = vec![0; 1024];
-    stream.read(&mut buffer).await.unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::PROTOCOL_VERSION;
+
+    /// A peer's direct links and what they pass on
+    struct Peer {
+        id: &'static str,
+        identity: Arc<IdentityKeyPair>,
+        links: DirectLinks,
+        inbound: mpsc::UnboundedReceiver<Envelope>,
+    }
+
+    impl Peer {
+        fn new(id: &'static str) -> Self {
+            let identity = Arc::new(IdentityKeyPair::generate());
+            let (inbound_tx, inbound) = mpsc::unbounded_channel();
+            Peer { id, links: DirectLinks::new(identity.clone(), id, inbound_tx), identity, inbound }
+        }
+
+        async fn listen(&self) -> Vec<String> {
+            vec![start_p2p_listener("127.0.0.1:0", self.links.clone()).await.unwrap().to_string()]
+        }
+
+        async fn trust(&self, peer: &Peer) {
+            self.links.trust(peer.id, peer.identity.public_key()).await;
+        }
 
-    // For demo purposes, print the received message
-    println!("Received: {:?}", String::from_utf8_lossy(&buffer));
+        fn envelope(&self, to: &Peer, message_id: &str) -> Envelope {
+            Envelope {
+                from: self.id.to_string(),
+                to: to.id.to_string(),
+                message: b"ciphertext".to_vec(),
+                message_id: message_id.to_string(),
+                version: PROTOCOL_VERSION,
+                initial: None,
+                group: None,
+                mls: None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn trusted_peers_exchange_acknowledged_envelopes() {
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        alice.trust(&bob).await;
+        bob.trust(&alice).await;
+        let addresses = bob.listen().await;
+
+        alice.links.send("bob", &addresses, &alice.envelope(&bob, "1")).await.unwrap();
+        let received = bob.inbound.recv().await.unwrap();
+        assert_eq!((received.from.as_str(), received.message_id.as_str()), ("alice", "1"));
+        assert_eq!(received.message, b"ciphertext");
+
+        // Bob answers over the link Alice opened, without knowing her addresses
+        bob.links.send("alice", &[], &bob.envelope(&alice, "2")).await.unwrap();
+        assert_eq!(alice.inbound.recv().await.unwrap().message_id, "2");
+    }
+
+    #[tokio::test]
+    async fn untrusted_identity_keys_are_refused() {
+        let alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        let addresses = bob.listen().await;
 
-    // Send acknowledgment
-    stream.write_all(b"Message received").await.unwrap();
+        // Alice won't dial a peer whose key she hasn't pinned
+        let error = alice.links.send("bob", &addresses, &alice.envelope(&bob, "1")).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+
+        // Bob refuses Alice, both unknown and under a key that isn't hers
+        alice.trust(&bob).await;
+        assert!(alice.links.send("bob", &addresses, &alice.envelope(&bob, "2")).await.is_err());
+        bob.links.trust("alice", IdentityKeyPair::generate().public_key()).await;
+        assert!(alice.links.send("bob", &addresses, &alice.envelope(&bob, "3")).await.is_err());
+
+        // An impostor listening at Bob's address fails Alice's handshake
+        let impostor = Peer::new("bob");
+        impostor.trust(&alice).await;
+        let impostor_addresses = impostor.listen().await;
+        let error = alice.links.send("bob", &impostor_addresses, &alice.envelope(&bob, "4")).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(bob.inbound.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_fail_quickly_so_the_relay_is_used() {
+        let alice = Peer::new("alice");
+        let bob = Peer::new("bob");
+        alice.trust(&bob).await;
+
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+        let addresses = ["bob.example:4000".to_string(), closed];
+        let started = tokio::time::Instant::now();
+        let error = alice.links.send("bob", &addresses, &alice.envelope(&bob, "1")).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+        assert!(started.elapsed() <= CONNECT_TIMEOUT);
+        assert!(alice.links.send("bob", &[], &alice.envelope(&bob, "2")).await.is_err());
+    }
 }
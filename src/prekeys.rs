@@ -0,0 +1,191 @@
+use crate::crypto::CryptoError;
+use crate::identity::IdentityKeyPair;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::SecretKey;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How long a signed prekey is published before it's replaced, in seconds
+const SIGNED_PREKEY_LIFETIME: u64 = 7 * 24 * 60 * 60;
+
+/// Public half of a one-time prekey, as stored on the server
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OneTimePreKey {
+    pub id: u32,
+    #[serde(with = "serde_bytes")]
+    pub public_key: Vec<u8>,
+}
+
+/// Keys a client publishes so others can start a session with it while it is offline
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PreKeyBundle {
+    #[serde(with = "serde_bytes")]
+    pub identity_key: Vec<u8>,
+    pub signed_prekey_id: u32,
+    #[serde(with = "serde_bytes")]
+    pub signed_prekey: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub signed_prekey_signature: Vec<u8>,
+    pub one_time_prekey: Option<OneTimePreKey>,
+}
+
+impl PreKeyBundle {
+    /// Check that the signed prekey was signed by the bundle's identity key
+    pub fn verify(&self) -> bool {
+        IdentityKeyPair::verify(&self.identity_key, &self.signed_prekey, &self.signed_prekey_signature)
+    }
+}
+
+/// X3DH header sent along with the first message of a new session
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InitialMessage {
+    #[serde(with = "serde_bytes")]
+    pub identity_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ephemeral_key: Vec<u8>,
+    /// Signed prekey of the bundle the session was started from
+    #[serde(default)]
+    pub signed_prekey_id: u32,
+    pub one_time_prekey_id: Option<u32>,
+    #[serde(with = "serde_bytes")]
+    pub salt: Vec<u8>, // Random HKDF salt chosen by the initiator
+}
+
+/// Private halves of the prekeys this client has published
+#[derive(Serialize, Deserialize)]
+pub struct PreKeyStore {
+    signed_prekey: Vec<u8>,
+    signed_prekey_signature: Vec<u8>,
+    #[serde(default)]
+    signed_prekey_id: u32,
+    #[serde(default)]
+    signed_prekey_created: u64, // Seconds since the Unix epoch
+    /// Signed prekey replaced by the current one, kept for initial messages still on their way
+    #[serde(default)]
+    previous_signed_prekey: Option<(u32, Vec<u8>)>,
+    one_time_prekeys: HashMap<u32, Vec<u8>>,
+    /// One-time prekeys already uploaded to the server
+    #[serde(default)]
+    published: HashSet<u32>,
+    next_id: u32,
+}
+
+impl PreKeyStore {
+    /// Generate a signed prekey and `count` one-time prekeys
+    pub fn generate(identity: &IdentityKeyPair, count: usize) -> Self {
+        let signed_prekey = SecretKey::random(&mut rand_core::OsRng);
+        let signed_prekey_signature = identity.sign(&public_bytes(&signed_prekey));
+
+        let mut store = PreKeyStore {
+            signed_prekey: signed_prekey.to_be_bytes().to_vec(),
+            signed_prekey_signature,
+            signed_prekey_id: 0,
+            signed_prekey_created: unix_time(),
+            previous_signed_prekey: None,
+            one_time_prekeys: HashMap::new(),
+            published: HashSet::new(),
+            next_id: 0,
+        };
+        store.replenish(count);
+        store
+    }
+
+    /// Load the plaintext prekey file clients kept before the keystore, to move it in
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        serde_json::from_slice(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Get the ID of the current signed prekey
+    pub fn signed_prekey_id(&self) -> u32 {
+        self.signed_prekey_id
+    }
+
+    /// Get the current public signed prekey
+    pub fn signed_prekey(&self) -> Result<Vec<u8>, CryptoError> {
+        Ok(public_bytes(&self.signed_prekey_secret(self.signed_prekey_id)?))
+    }
+
+    /// Get the identity signature over the signed prekey
+    pub fn signed_prekey_signature(&self) -> &[u8] {
+        &self.signed_prekey_signature
+    }
+
+    /// Replace the signed prekey once it has been published for a week, returning whether it was
+    ///
+    /// The replaced prekey is kept until the next rotation, for initial messages sent before it.
+    pub fn rotate_signed_prekey_if_stale(&mut self, identity: &IdentityKeyPair) -> bool {
+        if unix_time().saturating_sub(self.signed_prekey_created) < SIGNED_PREKEY_LIFETIME {
+            return false;
+        }
+
+        let signed_prekey = SecretKey::random(&mut rand_core::OsRng);
+        let replaced = std::mem::replace(&mut self.signed_prekey, signed_prekey.to_be_bytes().to_vec());
+        self.previous_signed_prekey = Some((self.signed_prekey_id, replaced));
+        self.signed_prekey_signature = identity.sign(&public_bytes(&signed_prekey));
+        self.signed_prekey_id = self.signed_prekey_id.wrapping_add(1);
+        self.signed_prekey_created = unix_time();
+        true
+    }
+
+    /// Get the public halves of the one-time prekeys not uploaded yet, marking them as uploaded
+    pub fn publish_one_time_prekeys(&mut self) -> Vec<OneTimePreKey> {
+        let mut prekeys: Vec<OneTimePreKey> = self
+            .one_time_prekeys
+            .iter()
+            .filter(|(id, _)| !self.published.contains(id))
+            .filter_map(|(id, secret)| {
+                let secret = SecretKey::from_be_bytes(secret).ok()?;
+                Some(OneTimePreKey { id: *id, public_key: public_bytes(&secret) })
+            })
+            .collect();
+        prekeys.sort_by_key(|prekey| prekey.id);
+        self.published.extend(prekeys.iter().map(|prekey| prekey.id));
+        prekeys
+    }
+
+    /// Generate `count` more one-time prekeys, to be uploaded with `publish_one_time_prekeys`
+    pub fn replenish(&mut self, count: usize) {
+        for _ in 0..count {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            let secret = SecretKey::random(&mut rand_core::OsRng);
+            self.one_time_prekeys.insert(id, secret.to_be_bytes().to_vec());
+        }
+    }
+
+    /// Get the private half of the signed prekey with `id`, the current one or the one it replaced
+    pub(crate) fn signed_prekey_secret(&self, id: u32) -> Result<SecretKey, CryptoError> {
+        let secret = match &self.previous_signed_prekey {
+            _ if id == self.signed_prekey_id => &self.signed_prekey,
+            Some((previous_id, previous)) if *previous_id == id => previous,
+            _ => return Err(CryptoError::UnknownSignedPreKey),
+        };
+        SecretKey::from_be_bytes(secret).map_err(|_| CryptoError::CorruptKey)
+    }
+
+    /// Get the private half of a one-time prekey, which stays usable until removed
+    pub(crate) fn one_time_prekey_secret(&self, id: u32) -> Option<SecretKey> {
+        let secret = self.one_time_prekeys.get(&id)?;
+        SecretKey::from_be_bytes(secret).ok()
+    }
+
+    /// Remove a one-time prekey so it can never be used twice
+    pub(crate) fn remove_one_time_prekey(&mut self, id: u32) {
+        self.published.remove(&id);
+        self.one_time_prekeys.remove(&id);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Encode the public key of a secret as an uncompressed SEC1 point
+fn public_bytes(secret: &SecretKey) -> Vec<u8> {
+    secret.public_key().to_encoded_point(false).as_bytes().to_vec()
+}
@@ -0,0 +1,537 @@
+use crate::groups::GroupRoster;
+use crate::mls::{DeliveryError, KeyPackage, MlsMessage, Welcome};
+use crate::prekeys::{InitialMessage, OneTimePreKey, PreKeyBundle};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Version of the client/server wire protocol, sent with every frame
+pub const WIRE_VERSION: u8 = 1;
+
+/// How frames are encoded on the WebSocket, chosen by the client when it connects
+///
+/// JSON frames are sent as text and CBOR frames as binary. JSON is kept for
+/// debugging; CBOR is smaller and is what the client uses by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    /// Parse an encoding name as used in the `encoding` query parameter
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Encoding::Json),
+            "cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+        }
+    }
+}
+
+/// Encrypted message as relayed to its recipient
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub from: String,
+    pub to: String,
+    #[serde(with = "ciphertext")]
+    pub message: Vec<u8>,
+    pub message_id: String,
+    pub version: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial: Option<InitialMessage>,
+    /// Group the message was sent to; `message` is then a Sender Key ciphertext
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// Set for MLS group traffic; `message` is then an encoded `MlsMessage` or `Welcome`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mls: Option<MlsPayload>,
+}
+
+/// What an MLS envelope carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MlsPayload {
+    Message,
+    Welcome,
+}
+
+/// Frames a client sends to the relay
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    Login {
+        username: String,
+    },
+    Register {
+        name: String,
+        #[serde(with = "serde_bytes")]
+        identity_key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        challenge_response: Vec<u8>,
+    },
+    Send {
+        to: String,
+        #[serde(with = "ciphertext")]
+        message: Vec<u8>,
+        message_id: String,
+        version: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        initial: Option<InitialMessage>,
+    },
+    /// Publish the current signed prekey and one-time prekeys the server doesn't have yet
+    UploadPreKeys {
+        signed_prekey_id: u32,
+        #[serde(with = "serde_bytes")]
+        signed_prekey: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        signed_prekey_signature: Vec<u8>,
+        one_time_prekeys: Vec<OneTimePreKey>,
+    },
+    RequestPreKeyBundle {
+        for_client: String,
+        request_id: String,
+    },
+    AdvertiseAddresses {
+        addresses: Vec<String>,
+    },
+    RequestAddresses {
+        for_client: String,
+    },
+    /// Start a group with the first roster, signed by the sender as its owner
+    CreateGroup {
+        roster: GroupRoster,
+        request_id: String,
+    },
+    /// Change a group's members: the owner signs any change, a member only its own departure
+    UpdateGroup {
+        roster: GroupRoster,
+        request_id: String,
+    },
+    /// Group message for the relay to fan out to every other member
+    SendGroup {
+        group_id: String,
+        #[serde(with = "ciphertext")]
+        message: Vec<u8>,
+        message_id: String,
+        version: u8,
+    },
+    /// Key packages others can add us to MLS groups with, replacing any published before
+    PublishKeyPackages {
+        key_packages: Vec<KeyPackage>,
+    },
+    RequestKeyPackage {
+        for_client: String,
+        request_id: String,
+    },
+    /// MLS proposal, commit or application message for the relay to order and fan out
+    SendMls {
+        message: MlsMessage,
+        message_id: String,
+        /// Welcome for the members a commit adds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        welcome: Option<Welcome>,
+    },
+}
+
+/// Frames the relay sends to a client
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    /// Login challenge to sign with the account's identity key
+    Challenge {
+        username: String,
+        #[serde(with = "serde_bytes")]
+        challenge: Vec<u8>,
+    },
+    LoginFailed {
+        username: String,
+        reason: String,
+    },
+    /// Login succeeded; `client_id` is the user ID messages are routed by
+    Registered {
+        client_id: String,
+    },
+    /// Online users as (user ID, display name) pairs
+    ClientList {
+        clients: Vec<(String, String)>,
+    },
+    PreKeyBundle {
+        client_id: String,
+        request_id: String,
+        bundle: PreKeyBundle,
+    },
+    /// How many of the client's one-time prekeys the server has left, sent after an upload and when they run low
+    PreKeyCount {
+        remaining: usize,
+    },
+    KeyPackage {
+        client_id: String,
+        request_id: String,
+        key_package: KeyPackage,
+    },
+    /// Direct P2P addresses of a user, empty if they're offline
+    Addresses {
+        client_id: String,
+        addresses: Vec<String>,
+    },
+    Deliver {
+        envelope: Envelope,
+    },
+    /// Latest signed roster of a group; a user missing from its members has been removed
+    GroupUpdate {
+        roster: GroupRoster,
+    },
+    /// Progress of a `Send` through the relay
+    Ack {
+        message_id: String,
+        status: RelayStatus,
+    },
+    /// A frame was rejected; `request_id` names the request or message it answers, if known
+    Error {
+        code: ErrorCode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+        reason: String,
+    },
+}
+
+/// How far the relay has got with a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayStatus {
+    /// The recipient exists and the relay has taken the message
+    Accepted,
+    /// The recipient is offline, so the message waits in their queue
+    Queued,
+    /// The message was handed to the recipient's connection
+    Delivered,
+}
+
+/// Machine-readable reasons the relay rejects a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame couldn't be decoded
+    Malformed,
+    /// The frame was sent with a wire version the relay doesn't speak
+    UnsupportedVersion,
+    /// Only the login handshake is allowed before logging in
+    NotLoggedIn,
+    /// No account exists for the user
+    UnknownUser,
+    /// The user has not published the requested keys, or is offline
+    KeyNotFound,
+    /// A signature didn't match the account's identity key
+    InvalidSignature,
+    /// The recipient's offline queue is full
+    QueueFull,
+    /// The recipient went offline while the message was being relayed
+    RecipientUnavailable,
+    /// No group exists with the ID
+    UnknownGroup,
+    /// The user isn't allowed to do this, e.g. change a group they don't own
+    NotAllowed,
+    /// An MLS message is for an epoch the group has moved on from
+    StaleEpoch,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorCode::Malformed => "malformed",
+            ErrorCode::UnsupportedVersion => "unsupported_version",
+            ErrorCode::NotLoggedIn => "not_logged_in",
+            ErrorCode::UnknownUser => "unknown_user",
+            ErrorCode::KeyNotFound => "key_not_found",
+            ErrorCode::InvalidSignature => "invalid_signature",
+            ErrorCode::QueueFull => "queue_full",
+            ErrorCode::RecipientUnavailable => "recipient_unavailable",
+            ErrorCode::UnknownGroup => "unknown_group",
+            ErrorCode::NotAllowed => "not_allowed",
+            ErrorCode::StaleEpoch => "stale_epoch",
+        })
+    }
+}
+
+/// Reasons a frame can't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The frame isn't a valid message
+    Malformed,
+    /// The frame was sent with a wire version we don't speak
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed => f.write_str("malformed frame"),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "unsupported wire version {}", version),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for ErrorCode {
+    fn from(error: ProtocolError) -> Self {
+        match error {
+            ProtocolError::Malformed => ErrorCode::Malformed,
+            ProtocolError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+        }
+    }
+}
+
+impl From<DeliveryError> for ErrorCode {
+    fn from(error: DeliveryError) -> Self {
+        match error {
+            DeliveryError::UnknownGroup => ErrorCode::UnknownGroup,
+            DeliveryError::NotMember => ErrorCode::NotAllowed,
+            DeliveryError::StaleEpoch => ErrorCode::StaleEpoch,
+        }
+    }
+}
+
+impl ClientMessage {
+    /// ID the relay's `Ack` or `Error` for this frame refers to, if it expects a reply
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            ClientMessage::Send { message_id, .. }
+            | ClientMessage::SendGroup { message_id, .. }
+            | ClientMessage::SendMls { message_id, .. } => Some(message_id),
+            ClientMessage::RequestPreKeyBundle { request_id, .. }
+            | ClientMessage::RequestKeyPackage { request_id, .. }
+            | ClientMessage::CreateGroup { request_id, .. }
+            | ClientMessage::UpdateGroup { request_id, .. } => Some(request_id),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        String::from_utf8(encode(self, Encoding::Json)).expect("JSON frames are UTF-8")
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, ProtocolError> {
+        decode(text.as_bytes(), Encoding::Json)
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        encode(self, encoding)
+    }
+
+    pub fn decode(bytes: &[u8], encoding: Encoding) -> Result<Self, ProtocolError> {
+        decode(bytes, encoding)
+    }
+}
+
+impl ServerMessage {
+    pub fn to_json(&self) -> String {
+        String::from_utf8(encode(self, Encoding::Json)).expect("JSON frames are UTF-8")
+    }
+
+    pub fn from_json(text: &str) -> Result<Self, ProtocolError> {
+        decode(text.as_bytes(), Encoding::Json)
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Vec<u8> {
+        encode(self, encoding)
+    }
+
+    pub fn decode(bytes: &[u8], encoding: Encoding) -> Result<Self, ProtocolError> {
+        decode(bytes, encoding)
+    }
+}
+
+/// Ciphertext as base64 text in JSON and as a byte string in CBOR
+///
+/// Either form is accepted when decoding: frames are buffered by serde to find
+/// their `type` tag, which hides whether they came from a human-readable format.
+pub(crate) mod ciphertext {
+    use base64::engine::general_purpose::STANDARD as BASE64;
+    use base64::Engine;
+    use serde::de::{self, Unexpected, Visitor};
+    use serde::{Deserializer, Serializer};
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&BASE64.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(CiphertextVisitor)
+    }
+
+    struct CiphertextVisitor;
+
+    impl<'de> Visitor<'de> for CiphertextVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("base64 text or a byte string")
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Vec<u8>, E> {
+            BASE64.decode(text).map_err(|_| E::invalid_value(Unexpected::Str(text), &self))
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+    }
+}
+
+/// A message tagged with the wire version it was encoded with
+#[derive(Serialize, Deserialize)]
+struct Versioned<T> {
+    protocol_version: u8,
+    #[serde(flatten)]
+    message: T,
+}
+
+/// Only the version of a frame, checked before the rest is parsed
+#[derive(Deserialize)]
+struct VersionOnly {
+    protocol_version: u8,
+}
+
+fn encode<T: Serialize>(message: &T, encoding: Encoding) -> Vec<u8> {
+    let versioned = Versioned { protocol_version: WIRE_VERSION, message };
+    match encoding {
+        Encoding::Json => serde_json::to_vec(&versioned).expect("Failed to encode frame"),
+        Encoding::Cbor => {
+            let mut bytes = Vec::new();
+            ciborium::into_writer(&versioned, &mut bytes).expect("Failed to encode frame");
+            bytes
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], encoding: Encoding) -> Result<T, ProtocolError> {
+    let VersionOnly { protocol_version } = parse(bytes, encoding)?;
+    if protocol_version != WIRE_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(protocol_version));
+    }
+    let versioned: Versioned<T> = parse(bytes, encoding)?;
+    Ok(versioned.message)
+}
+
+fn parse<T: DeserializeOwned>(bytes: &[u8], encoding: Encoding) -> Result<T, ProtocolError> {
+    match encoding {
+        Encoding::Json => serde_json::from_slice(bytes).map_err(|_| ProtocolError::Malformed),
+        Encoding::Cbor => ciborium::from_reader(bytes).map_err(|_| ProtocolError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope(message: Vec<u8>) -> Envelope {
+        Envelope {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            message,
+            message_id: "0f8e2c4a-6b1d-4e3f-9a7c-5d2b8e1f4a6c".to_string(),
+            version: 1,
+            initial: Some(InitialMessage {
+                identity_key: vec![4; 65],
+                ephemeral_key: vec![4; 65],
+                signed_prekey_id: 3,
+                one_time_prekey_id: Some(7),
+                salt: vec![9; 32],
+            }),
+            group: None,
+            mls: None,
+        }
+    }
+
+    fn deliver(message: Vec<u8>) -> ServerMessage {
+        ServerMessage::Deliver { envelope: envelope(message) }
+    }
+
+    fn delivered(message: ServerMessage) -> Envelope {
+        match message {
+            ServerMessage::Deliver { envelope } => envelope,
+            _ => panic!("expected a delivery"),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip_in_both_encodings() {
+        let ciphertext: Vec<u8> = (0..=255).collect();
+        for encoding in [Encoding::Json, Encoding::Cbor] {
+            let bytes = deliver(ciphertext.clone()).encode(encoding);
+            let envelope = delivered(ServerMessage::decode(&bytes, encoding).unwrap());
+            assert_eq!(envelope.message, ciphertext);
+            let initial = envelope.initial.unwrap();
+            assert_eq!(initial.identity_key, vec![4; 65]);
+            assert_eq!(initial.salt, vec![9; 32]);
+
+            let send = ClientMessage::SendGroup {
+                group_id: "group".to_string(),
+                message: ciphertext.clone(),
+                message_id: "id".to_string(),
+                version: 1,
+            };
+            match ClientMessage::decode(&send.encode(encoding), encoding).unwrap() {
+                ClientMessage::SendGroup { message, .. } => assert_eq!(message, ciphertext),
+                _ => panic!("expected a group message"),
+            }
+        }
+    }
+
+    #[test]
+    fn json_carries_base64_and_cbor_carries_byte_strings() {
+        let json = deliver(b"ciphertext".to_vec()).to_json();
+        assert!(json.contains("\"message\":\"Y2lwaGVydGV4dA==\""));
+
+        let cbor = deliver(b"ciphertext".to_vec()).encode(Encoding::Cbor);
+        let value: ciborium::Value = ciborium::from_reader(cbor.as_slice()).unwrap();
+        let envelope = value.as_map().unwrap().iter().find(|(key, _)| key.as_text() == Some("envelope")).unwrap();
+        let fields = envelope.1.as_map().unwrap();
+        let field = |name: &str| &fields.iter().find(|(key, _)| key.as_text() == Some(name)).unwrap().1;
+        assert_eq!(field("message").as_bytes(), Some(&b"ciphertext".to_vec()));
+        let initial = field("initial").as_map().unwrap();
+        assert!(initial.iter().all(|(key, value)| key.as_text().is_some_and(|key| key.ends_with("_id")) || value.is_bytes()));
+    }
+
+    #[test]
+    fn cbor_frames_are_smaller_than_json() {
+        let ciphertext = vec![0xa5; 4096];
+        let json = deliver(ciphertext.clone()).encode(Encoding::Json);
+        let cbor = deliver(ciphertext.clone()).encode(Encoding::Cbor);
+
+        // Base64 alone costs a third more; the CBOR frame is the ciphertext plus a little framing
+        assert!(json.len() > ciphertext.len() * 4 / 3);
+        assert!(cbor.len() < ciphertext.len() + 400, "CBOR frame is {} bytes", cbor.len());
+        assert!(cbor.len() * 4 < json.len() * 3);
+    }
+
+    #[test]
+    fn bad_frames_are_rejected() {
+        assert_eq!(ServerMessage::from_json("{}").err(), Some(ProtocolError::Malformed));
+        let newer = deliver(Vec::new()).to_json().replace("\"protocol_version\":1", "\"protocol_version\":2");
+        assert_eq!(ServerMessage::from_json(&newer).err(), Some(ProtocolError::UnsupportedVersion(2)));
+        let not_base64 = deliver(Vec::new()).to_json().replace("\"message\":\"\"", "\"message\":\"not base64!\"");
+        assert_eq!(ServerMessage::from_json(&not_base64).err(), Some(ProtocolError::Malformed));
+        let cbor = deliver(b"ciphertext".to_vec()).encode(Encoding::Cbor);
+        assert!(ServerMessage::decode(&cbor[..cbor.len() - 3], Encoding::Cbor).is_err());
+    }
+}
@@ -0,0 +1,688 @@
+use crate::crypto::{AssociatedData, Crypto, CryptoError};
+use crate::identity::IdentityKeyPair;
+use crate::prekeys::{InitialMessage, PreKeyBundle, PreKeyStore};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::SecretKey;
+use ring::hkdf::{KeyType, Salt, HKDF_SHA256};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum number of message keys kept for messages that haven't arrived yet
+const MAX_SKIP: u32 = 1000;
+
+/// Length of an uncompressed SEC1 P-256 point
+const PUBLIC_KEY_LEN: usize = 65;
+
+/// Length of an encoded `Header`
+const HEADER_LEN: usize = PUBLIC_KEY_LEN + 8;
+
+/// HKDF label turning a chain's message key into an AES-256-GCM key
+const MESSAGE_KEY_LABEL: &str = "ratchet message";
+
+/// How long a replaced session is kept, in seconds, which is as long as the relay holds messages
+const PREVIOUS_SESSION_TTL: u64 = 7 * 24 * 60 * 60;
+
+/// Answered handshakes remembered for each peer, so a replayed initial message can't restart a session
+const MAX_HANDSHAKES: usize = 32;
+
+/// Double Ratchet header sent in front of every ciphertext
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub dh: Vec<u8>,
+    pub previous_count: u32,
+    pub count: u32,
+}
+
+impl Header {
+    /// Encode as `dh || previous_count || count`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.dh.as_slice(),
+            &self.previous_count.to_be_bytes(),
+            &self.count.to_be_bytes(),
+        ]
+        .concat()
+    }
+
+    /// Split an encoded header off the front of a ratchet message
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, &[u8]), CryptoError> {
+        if bytes.len() < HEADER_LEN {
+            return Err(CryptoError::InvalidHeader);
+        }
+        let (header, rest) = bytes.split_at(HEADER_LEN);
+        let (dh, counts) = header.split_at(PUBLIC_KEY_LEN);
+
+        let header = Header {
+            dh: dh.to_vec(),
+            previous_count: u32::from_be_bytes(counts[..4].try_into().map_err(|_| CryptoError::InvalidHeader)?),
+            count: u32::from_be_bytes(counts[4..].try_into().map_err(|_| CryptoError::InvalidHeader)?),
+        };
+        Ok((header, rest))
+    }
+}
+
+/// Message key stored for a message that was skipped over
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    dh: Vec<u8>,
+    count: u32,
+    message_key: [u8; 32],
+}
+
+/// Double Ratchet state for a conversation with a single peer
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    dh_self: Vec<u8>,
+    dh_remote: Option<Vec<u8>>,
+    root_key: [u8; 32],
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    send_count: u32,
+    recv_count: u32,
+    previous_count: u32,
+    skipped: Vec<SkippedKey>,
+}
+
+impl Session {
+    /// Start a session as the party that sent the X3DH initial message
+    ///
+    /// `remote_public_key` is the peer's signed prekey from its bundle.
+    pub fn initiate(shared_secret: [u8; 32], remote_public_key: &[u8]) -> Result<Self, CryptoError> {
+        let dh_self = SecretKey::random(&mut rand_core::OsRng);
+        let dh_out = Crypto::diffie_hellman(&dh_self, remote_public_key)?;
+        let (root_key, send_chain) = kdf_root(&shared_secret, &dh_out);
+
+        Ok(Session {
+            dh_self: dh_self.to_be_bytes().to_vec(),
+            dh_remote: Some(remote_public_key.to_vec()),
+            root_key,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            send_count: 0,
+            recv_count: 0,
+            previous_count: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Start a session as the party that answered an X3DH initial message
+    ///
+    /// The signed prekey the initiator used doubles as the first ratchet key pair.
+    pub fn respond(shared_secret: [u8; 32], signed_prekey: &SecretKey) -> Self {
+        Session {
+            dh_self: signed_prekey.to_be_bytes().to_vec(),
+            dh_remote: None,
+            root_key: shared_secret,
+            send_chain: None,
+            recv_chain: None,
+            send_count: 0,
+            recv_count: 0,
+            previous_count: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Encrypt the next message, returning `header || nonce || ciphertext`
+    ///
+    /// Both `associated_data` and the header are authenticated with the message.
+    /// Fails with `NoSendingChain` if a responder session hasn't received a message yet.
+    pub fn encrypt(&mut self, plaintext: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (send_chain, message_key) = kdf_chain(&self.send_chain.ok_or(CryptoError::NoSendingChain)?);
+        self.send_chain = Some(send_chain);
+
+        let header = Header {
+            dh: self.dh_public()?,
+            previous_count: self.previous_count,
+            count: self.send_count,
+        };
+        self.send_count += 1;
+
+        let header = header.to_bytes();
+        let key = Crypto::create_symmetric_key(&message_key, &[], MESSAGE_KEY_LABEL)?;
+        let aad = [associated_data, &header].concat();
+        let ciphertext = Crypto::encrypt_with_aad(&key, plaintext, &aad)?;
+        Ok([header, ciphertext].concat())
+    }
+
+    /// Decrypt a message produced by the peer's `encrypt`
+    ///
+    /// The session is only updated if the message authenticates.
+    pub fn decrypt(&mut self, message: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (header, ciphertext) = Header::from_bytes(message)?;
+        let aad = [associated_data, &message[..HEADER_LEN]].concat();
+
+        if let Some(index) = self
+            .skipped
+            .iter()
+            .position(|skipped| skipped.dh == header.dh && skipped.count == header.count)
+        {
+            let key = Crypto::create_symmetric_key(&self.skipped[index].message_key, &[], MESSAGE_KEY_LABEL)?;
+            let plaintext = Crypto::decrypt_with_aad(&key, ciphertext, &aad)?;
+            self.skipped.remove(index);
+            return Ok(plaintext);
+        }
+
+        // Work on a copy so a forged message can't corrupt the ratchet
+        let mut next = self.clone();
+        if next.dh_remote.as_deref() != Some(header.dh.as_slice()) {
+            next.skip_message_keys(header.previous_count)?;
+            next.dh_ratchet(&header)?;
+        }
+        next.skip_message_keys(header.count)?;
+
+        let (recv_chain, message_key) = kdf_chain(&next.recv_chain.ok_or(CryptoError::DecryptionFailed)?);
+        next.recv_chain = Some(recv_chain);
+        next.recv_count += 1;
+
+        let key = Crypto::create_symmetric_key(&message_key, &[], MESSAGE_KEY_LABEL)?;
+        let plaintext = Crypto::decrypt_with_aad(&key, ciphertext, &aad)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    /// Store the message keys of messages up to `until` that haven't arrived yet
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), CryptoError> {
+        let Some(mut recv_chain) = self.recv_chain else {
+            return Ok(());
+        };
+        // A count behind ours is a replay of a message we've already used the key for
+        let skip = until.checked_sub(self.recv_count).ok_or(CryptoError::DecryptionFailed)?;
+        if skip > MAX_SKIP {
+            return Err(CryptoError::TooManySkippedMessages);
+        }
+
+        let dh = self.dh_remote.clone().unwrap_or_default();
+        while self.recv_count < until {
+            let (next_chain, message_key) = kdf_chain(&recv_chain);
+            recv_chain = next_chain;
+            self.skipped.push(SkippedKey { dh: dh.clone(), count: self.recv_count, message_key });
+            self.recv_count += 1;
+        }
+        self.recv_chain = Some(recv_chain);
+
+        // Drop the oldest keys once the cache is full
+        let excess = self.skipped.len().saturating_sub(MAX_SKIP as usize);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+
+    /// Step the DH ratchet forward with the peer's new ratchet key
+    fn dh_ratchet(&mut self, header: &Header) -> Result<(), CryptoError> {
+        self.previous_count = self.send_count;
+        self.send_count = 0;
+        self.recv_count = 0;
+        self.dh_remote = Some(header.dh.clone());
+
+        let dh_out = Crypto::diffie_hellman(&self.dh_secret()?, &header.dh)?;
+        let (root_key, recv_chain) = kdf_root(&self.root_key, &dh_out);
+        self.root_key = root_key;
+        self.recv_chain = Some(recv_chain);
+
+        let dh_self = SecretKey::random(&mut rand_core::OsRng);
+        let dh_out = Crypto::diffie_hellman(&dh_self, &header.dh)?;
+        let (root_key, send_chain) = kdf_root(&self.root_key, &dh_out);
+        self.root_key = root_key;
+        self.send_chain = Some(send_chain);
+        self.dh_self = dh_self.to_be_bytes().to_vec();
+        Ok(())
+    }
+
+    fn dh_secret(&self) -> Result<SecretKey, CryptoError> {
+        SecretKey::from_be_bytes(&self.dh_self).map_err(|_| CryptoError::CorruptKey)
+    }
+
+    fn dh_public(&self) -> Result<Vec<u8>, CryptoError> {
+        Ok(self.dh_secret()?.public_key().to_encoded_point(false).as_bytes().to_vec())
+    }
+}
+
+/// Ratchet sessions keyed by peer ID
+///
+/// Every handshake derives its secret and stores the resulting session in a single
+/// call, so concurrent handshakes with different peers can't mix up their secrets.
+///
+/// When both sides start a handshake at once each ends up answering the other's,
+/// so the session a handshake replaces is kept to decrypt what the peer sends with it.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SessionManager {
+    sessions: HashMap<String, Session>,
+    previous: HashMap<String, Session>, // Sessions replaced by a newer handshake with each peer
+    #[serde(default)]
+    replaced_at: HashMap<String, u64>, // When each previous session was replaced, in seconds since the Unix epoch
+    pending_initial: HashMap<String, InitialMessage>, // X3DH headers still to be sent to each peer
+    #[serde(default)]
+    handshakes: HashMap<String, VecDeque<Vec<u8>>>, // Ephemeral keys of the handshakes answered for each peer
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check whether a session with `peer_id` exists
+    pub fn has_session(&self, peer_id: &str) -> bool {
+        self.sessions.contains_key(peer_id)
+    }
+
+    /// Run X3DH against `peer_id`'s prekey bundle and store the new session
+    pub fn initiate(
+        &mut self,
+        peer_id: &str,
+        identity: &IdentityKeyPair,
+        bundle: &PreKeyBundle,
+    ) -> Result<(), CryptoError> {
+        let (secret, initial) = Crypto::x3dh_initiate(identity, bundle)?;
+        let session = Session::initiate(secret, &bundle.signed_prekey)?;
+
+        self.replace(peer_id, session);
+        self.pending_initial.insert(peer_id.to_string(), initial);
+        Ok(())
+    }
+
+    /// Answer an X3DH initial message from `peer_id`, without storing anything yet
+    ///
+    /// Anyone can send an initial message carrying a peer's identity key, so the
+    /// session only replaces the current one once `accept` has decrypted the
+    /// first message with it.
+    pub fn respond(
+        &self,
+        peer_id: &str,
+        identity: &IdentityKeyPair,
+        prekeys: &PreKeyStore,
+        initial: &InitialMessage,
+    ) -> Result<Session, CryptoError> {
+        if self.handshakes.get(peer_id).is_some_and(|answered| answered.contains(&initial.ephemeral_key)) {
+            return Err(CryptoError::ReplayedHandshake);
+        }
+        let secret = Crypto::x3dh_respond(identity, prekeys, initial)?;
+        Ok(Session::respond(secret, &prekeys.signed_prekey_secret(initial.signed_prekey_id)?))
+    }
+
+    /// Decrypt the first message of a session from `respond`, then store the session
+    ///
+    /// The one-time prekey the handshake used is removed once the message decrypts.
+    pub fn accept(
+        &mut self,
+        peer_id: &str,
+        mut session: Session,
+        prekeys: &mut PreKeyStore,
+        initial: &InitialMessage,
+        message: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let plaintext = session.decrypt(message, &associated_data.to_bytes())?;
+        if let Some(id) = initial.one_time_prekey_id {
+            prekeys.remove_one_time_prekey(id);
+        }
+
+        let answered = self.handshakes.entry(peer_id.to_string()).or_default();
+        if answered.len() == MAX_HANDSHAKES {
+            answered.pop_front();
+        }
+        answered.push_back(initial.ephemeral_key.clone());
+        self.replace(peer_id, session);
+        self.pending_initial.remove(peer_id);
+        Ok(plaintext)
+    }
+
+    fn replace(&mut self, peer_id: &str, session: Session) {
+        self.prune_previous();
+        if let Some(replaced) = self.sessions.insert(peer_id.to_string(), session) {
+            self.previous.insert(peer_id.to_string(), replaced);
+            self.replaced_at.insert(peer_id.to_string(), unix_time());
+        }
+    }
+
+    /// Forget replaced sessions once nothing sent with them can still arrive
+    fn prune_previous(&mut self) {
+        let now = unix_time();
+        let replaced_at = &self.replaced_at;
+        self.previous.retain(|peer_id, _| {
+            replaced_at.get(peer_id).is_some_and(|at| now.saturating_sub(*at) < PREVIOUS_SESSION_TTL)
+        });
+        let previous = &self.previous;
+        self.replaced_at.retain(|peer_id, _| previous.contains_key(peer_id));
+    }
+
+    /// Encrypt a message for `peer_id`, authenticating its envelope metadata
+    ///
+    /// The first message of a session we started also returns the X3DH header to send with it.
+    pub fn encrypt(
+        &mut self,
+        peer_id: &str,
+        plaintext: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<(Vec<u8>, Option<InitialMessage>), CryptoError> {
+        let session = self.sessions.get_mut(peer_id).ok_or(CryptoError::NoSession)?;
+        let ciphertext = session.encrypt(plaintext, &associated_data.to_bytes())?;
+        Ok((ciphertext, self.pending_initial.remove(peer_id)))
+    }
+
+    /// Decrypt a message from `peer_id`, checking its envelope metadata
+    pub fn decrypt(
+        &mut self,
+        peer_id: &str,
+        message: &[u8],
+        associated_data: &AssociatedData,
+    ) -> Result<Vec<u8>, CryptoError> {
+        let session = self.sessions.get_mut(peer_id).ok_or(CryptoError::NoSession)?;
+        let associated_data = associated_data.to_bytes();
+        let error = match session.decrypt(message, &associated_data) {
+            Ok(plaintext) => return Ok(plaintext),
+            Err(error) => error,
+        };
+
+        // The peer may still be sending with the session our latest handshake replaced
+        self.prune_previous();
+        match self.previous.get_mut(peer_id) {
+            Some(previous) => previous.decrypt(message, &associated_data).map_err(|_| error),
+            None => Err(error),
+        }
+    }
+}
+
+/// HKDF output length for `kdf_root`
+struct RootOutput;
+
+impl KeyType for RootOutput {
+    fn len(&self) -> usize {
+        64
+    }
+}
+
+/// Derive a new root key and chain key from a DH output
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Salt::new(HKDF_SHA256, root_key)
+        .extract(dh_out)
+        .expand(&[b"p2p_sparse_messaging ratchet"], RootOutput)
+        .unwrap()
+        .fill(&mut okm)
+        .unwrap();
+
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (root, chain)
+}
+
+/// Advance a chain key, returning the next chain key and a message key
+pub(crate) fn kdf_chain(chain_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, chain_key);
+
+    let mut message_key = [0u8; 32];
+    let mut next_chain = [0u8; 32];
+    message_key.copy_from_slice(hmac::sign(&key, &[0x01]).as_ref());
+    next_chain.copy_from_slice(hmac::sign(&key, &[0x02]).as_ref());
+    (next_chain, message_key)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A user with the keys they publish and their sessions
+    struct Peer {
+        id: &'static str,
+        identity: IdentityKeyPair,
+        prekeys: PreKeyStore,
+        sessions: SessionManager,
+    }
+
+    impl Peer {
+        fn new(id: &'static str) -> Self {
+            let identity = IdentityKeyPair::generate();
+            let prekeys = PreKeyStore::generate(&identity, 5);
+            Peer { id, identity, prekeys, sessions: SessionManager::new() }
+        }
+
+        /// The bundle the relay would hand out for this peer
+        fn bundle(&mut self) -> PreKeyBundle {
+            PreKeyBundle {
+                identity_key: self.identity.public_key().to_vec(),
+                signed_prekey_id: self.prekeys.signed_prekey_id(),
+                signed_prekey: self.prekeys.signed_prekey().unwrap(),
+                signed_prekey_signature: self.prekeys.signed_prekey_signature().to_vec(),
+                one_time_prekey: self.prekeys.publish_one_time_prekeys().pop(),
+            }
+        }
+
+        fn send(&mut self, to: &Peer, message_id: &str, text: &str) -> (Vec<u8>, Option<InitialMessage>) {
+            let associated_data = AssociatedData { version: 1, sender: self.id, recipient: to.id, message_id };
+            self.sessions.encrypt(to.id, text.as_bytes(), &associated_data).unwrap()
+        }
+
+        fn receive(&mut self, from: &Peer, message_id: &str, message: &[u8]) -> Result<String, CryptoError> {
+            let associated_data = AssociatedData { version: 1, sender: from.id, recipient: self.id, message_id };
+            let plaintext = self.sessions.decrypt(from.id, message, &associated_data)?;
+            Ok(String::from_utf8(plaintext).unwrap())
+        }
+
+        /// Receive the first message of a session `from` started
+        fn answer(&mut self, from: &Peer, initial: &InitialMessage, message_id: &str, message: &[u8]) -> Result<String, CryptoError> {
+            let associated_data = AssociatedData { version: 1, sender: from.id, recipient: self.id, message_id };
+            let session = self.sessions.respond(from.id, &self.identity, &self.prekeys, initial)?;
+            let plaintext = self.sessions.accept(from.id, session, &mut self.prekeys, initial, message, &associated_data)?;
+            Ok(String::from_utf8(plaintext).unwrap())
+        }
+    }
+
+    /// Alice starts a session with Bob, who answers her first message
+    fn connected() -> (Peer, Peer) {
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        alice.sessions.initiate("bob", &alice.identity, &bob.bundle()).unwrap();
+
+        let (message, initial) = alice.send(&bob, "0", "hello");
+        assert_eq!(bob.answer(&alice, &initial.unwrap(), "0", &message).unwrap(), "hello");
+        (alice, bob)
+    }
+
+    #[test]
+    fn messages_round_trip_in_both_directions() {
+        let (mut alice, mut bob) = connected();
+
+        // Each reply steps the DH ratchet
+        for round in 0..3 {
+            let id = format!("b{}", round);
+            let (message, initial) = bob.send(&alice, &id, "from bob");
+            assert!(initial.is_none());
+            assert_eq!(alice.receive(&bob, &id, &message).unwrap(), "from bob");
+
+            let id = format!("a{}", round);
+            let (message, initial) = alice.send(&bob, &id, "from alice");
+            assert!(initial.is_none());
+            assert_eq!(bob.receive(&alice, &id, &message).unwrap(), "from alice");
+        }
+    }
+
+    #[test]
+    fn only_the_first_message_carries_the_initial_header() {
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        alice.sessions.initiate("bob", &alice.identity, &bob.bundle()).unwrap();
+
+        assert!(alice.send(&bob, "0", "first").1.is_some());
+        assert!(alice.send(&bob, "1", "second").1.is_none());
+    }
+
+    #[test]
+    fn the_responder_cannot_send_first() {
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        alice.sessions.initiate("bob", &alice.identity, &bob.bundle()).unwrap();
+        let (_, initial) = alice.send(&bob, "0", "hello");
+        let mut session = bob.sessions.respond("alice", &bob.identity, &bob.prekeys, &initial.unwrap()).unwrap();
+
+        assert_eq!(session.encrypt(b"too soon", b"").err(), Some(CryptoError::NoSendingChain));
+    }
+
+    #[test]
+    fn out_of_order_messages_use_skipped_keys() {
+        let (mut alice, mut bob) = connected();
+        let messages: Vec<_> = (0..4).map(|i| alice.send(&bob, &i.to_string(), &format!("message {}", i)).0).collect();
+
+        for i in [3, 1, 0, 2] {
+            assert_eq!(bob.receive(&alice, &i.to_string(), &messages[i]).unwrap(), format!("message {}", i));
+        }
+    }
+
+    #[test]
+    fn skipped_keys_survive_a_ratchet_step() {
+        let (mut alice, mut bob) = connected();
+        let (late, _) = alice.send(&bob, "late", "sent before the reply");
+        let (reply, _) = bob.send(&alice, "reply", "reply");
+        assert_eq!(alice.receive(&bob, "reply", &reply).unwrap(), "reply");
+        let (next, _) = alice.send(&bob, "next", "new chain");
+
+        assert_eq!(bob.receive(&alice, "next", &next).unwrap(), "new chain");
+        assert_eq!(bob.receive(&alice, "late", &late).unwrap(), "sent before the reply");
+    }
+
+    #[test]
+    fn skipping_too_many_messages_is_refused() {
+        let (mut alice, mut bob) = connected();
+        for i in 0..=MAX_SKIP {
+            alice.send(&bob, &i.to_string(), "never delivered");
+        }
+        let (message, _) = alice.send(&bob, "last", "too far ahead");
+
+        assert_eq!(bob.receive(&alice, "last", &message), Err(CryptoError::TooManySkippedMessages));
+    }
+
+    #[test]
+    fn replayed_messages_are_rejected() {
+        let (mut alice, mut bob) = connected();
+        let (message, _) = alice.send(&bob, "1", "once");
+        assert_eq!(bob.receive(&alice, "1", &message).unwrap(), "once");
+
+        assert_eq!(bob.receive(&alice, "1", &message), Err(CryptoError::DecryptionFailed));
+    }
+
+    #[test]
+    fn tampered_messages_are_rejected_without_breaking_the_session() {
+        let (mut alice, mut bob) = connected();
+        let (message, _) = alice.send(&bob, "1", "intact");
+
+        // Flip a bit in the header, then in the ciphertext
+        for index in [PUBLIC_KEY_LEN + 7, message.len() - 1] {
+            let mut tampered = message.clone();
+            tampered[index] ^= 1;
+            assert!(bob.receive(&alice, "1", &tampered).is_err());
+        }
+        assert_eq!(bob.receive(&alice, "1", &message[..HEADER_LEN - 1]), Err(CryptoError::InvalidHeader));
+
+        assert_eq!(bob.receive(&alice, "1", &message).unwrap(), "intact");
+    }
+
+    #[test]
+    fn messages_are_bound_to_their_envelope() {
+        let (mut alice, mut bob) = connected();
+        let (message, _) = alice.send(&bob, "1", "for message 1");
+
+        assert_eq!(bob.receive(&alice, "2", &message), Err(CryptoError::DecryptionFailed));
+        let carol = Peer::new("carol");
+        let associated_data = AssociatedData { version: 1, sender: "alice", recipient: carol.id, message_id: "1" };
+        assert!(bob.sessions.decrypt("alice", &message, &associated_data).is_err());
+        let associated_data = AssociatedData { version: 2, sender: "alice", recipient: "bob", message_id: "1" };
+        assert!(bob.sessions.decrypt("alice", &message, &associated_data).is_err());
+
+        assert_eq!(bob.receive(&alice, "1", &message).unwrap(), "for message 1");
+    }
+
+    #[test]
+    fn crossed_handshakes_keep_the_replaced_session() {
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        alice.sessions.initiate("bob", &alice.identity, &bob.bundle()).unwrap();
+        bob.sessions.initiate("alice", &bob.identity, &alice.bundle()).unwrap();
+        let (from_alice, alice_initial) = alice.send(&bob, "a", "from alice");
+        let (from_bob, bob_initial) = bob.send(&alice, "b", "from bob");
+
+        // Each answers the other's handshake, replacing the session it started
+        assert_eq!(alice.answer(&bob, &bob_initial.unwrap(), "b", &from_bob).unwrap(), "from bob");
+        assert_eq!(bob.answer(&alice, &alice_initial.unwrap(), "a", &from_alice).unwrap(), "from alice");
+
+        // Alice now sends on the session Bob started, which Bob replaced but kept
+        let (message, _) = alice.send(&bob, "c", "reply");
+        assert_eq!(bob.receive(&alice, "c", &message).unwrap(), "reply");
+    }
+
+    #[test]
+    fn replaced_sessions_expire() {
+        let (mut alice, mut bob) = connected();
+        let (first, _) = alice.send(&bob, "1", "first");
+        let (second, _) = alice.send(&bob, "2", "second");
+
+        // Alice starts over, and Bob keeps the old session for what's still on its way
+        let mut restarted = SessionManager::new();
+        restarted.initiate("bob", &alice.identity, &bob.bundle()).unwrap();
+        let associated_data = AssociatedData { version: 1, sender: "alice", recipient: "bob", message_id: "new" };
+        let (message, initial) = restarted.encrypt("bob", b"new session", &associated_data).unwrap();
+        assert_eq!(bob.answer(&alice, &initial.unwrap(), "new", &message).unwrap(), "new session");
+        assert_eq!(bob.receive(&alice, "1", &first).unwrap(), "first");
+
+        *bob.sessions.replaced_at.get_mut("alice").unwrap() -= PREVIOUS_SESSION_TTL;
+        assert!(bob.receive(&alice, "2", &second).is_err());
+        assert!(bob.sessions.previous.is_empty());
+        assert!(bob.sessions.replaced_at.is_empty());
+    }
+
+    #[test]
+    fn unknown_one_time_prekeys_are_refused() {
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        let bundle = bob.bundle();
+        alice.sessions.initiate("bob", &alice.identity, &bundle).unwrap();
+        let (message, initial) = alice.send(&bob, "0", "hello");
+        bob.answer(&alice, &initial.unwrap(), "0", &message).unwrap();
+
+        // Each one-time prekey only answers one handshake
+        alice.sessions.initiate("bob", &alice.identity, &bundle).unwrap();
+        let (message, initial) = alice.send(&bob, "1", "again");
+        assert_eq!(bob.answer(&alice, &initial.unwrap(), "1", &message), Err(CryptoError::UnknownOneTimePreKey));
+    }
+
+    #[test]
+    fn forged_handshakes_leave_the_session_working() {
+        let (mut alice, mut bob) = connected();
+        let (reply, _) = bob.send(&alice, "1", "reply");
+        assert_eq!(alice.receive(&bob, "1", &reply).unwrap(), "reply");
+
+        // A handshake in Alice's name that doesn't decrypt its first message changes nothing
+        let mut restarted = SessionManager::new();
+        restarted.initiate("bob", &alice.identity, &bob.bundle()).unwrap();
+        let associated_data = AssociatedData { version: 1, sender: "alice", recipient: "bob", message_id: "2" };
+        let (first, initial) = restarted.encrypt("bob", b"restarted", &associated_data).unwrap();
+        let initial = initial.unwrap();
+        let mut tampered = initial.clone();
+        tampered.salt[0] ^= 1;
+        assert_eq!(bob.answer(&alice, &tampered, "2", &first), Err(CryptoError::DecryptionFailed));
+        let mut tampered = first.clone();
+        tampered[HEADER_LEN] ^= 1;
+        assert_eq!(bob.answer(&alice, &initial, "2", &tampered), Err(CryptoError::DecryptionFailed));
+
+        let (message, _) = bob.send(&alice, "3", "still here");
+        assert_eq!(alice.receive(&bob, "3", &message).unwrap(), "still here");
+
+        // The one-time prekey wasn't used up, so the genuine handshake still works, but only once
+        assert_eq!(bob.answer(&alice, &initial, "2", &first).unwrap(), "restarted");
+        assert_eq!(bob.answer(&alice, &initial, "2", &first), Err(CryptoError::ReplayedHandshake));
+    }
+
+    #[test]
+    fn corrupt_ratchet_keys_are_an_error() {
+        let (mut alice, bob) = connected();
+        alice.sessions.sessions.get_mut("bob").unwrap().dh_self = vec![0; 32];
+
+        let associated_data = AssociatedData { version: 1, sender: "alice", recipient: bob.id, message_id: "1" };
+        assert_eq!(alice.sessions.encrypt("bob", b"hello", &associated_data).err(), Some(CryptoError::CorruptKey));
+    }
+}